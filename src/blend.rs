@@ -0,0 +1,105 @@
+/// Emulates the slow response of the DMG and CGB screens, which games use for transparency
+/// by showing objects every other frame
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FrameBlending {
+    /// Every frame is shown as it was drawn
+    Off,
+    /// Shows the average of the current and the previous frame
+    Simple,
+    /// Each pixel moves towards its new colour, leaving a fading trail like on the real screen
+    Exponential,
+}
+
+// The part of the difference to the new colour that remains after one frame
+const LCD_RETENTION: f32 = 0.45;
+
+/// Blends the RGB888 frames of the GPU according to a `FrameBlending` mode
+#[derive(Clone, PartialEq)]
+pub(crate) struct FrameBlender {
+    mode: FrameBlending,
+    // The previous frame as it was drawn (Simple), or the colours of the screen (Exponential)
+    previous: Vec<u8>,
+    screen: Vec<f32>,
+}
+
+impl FrameBlender {
+    pub(crate) fn new(mode: FrameBlending) -> FrameBlender {
+        FrameBlender {
+            mode,
+            previous: Vec::new(),
+            screen: Vec::new(),
+        }
+    }
+
+    pub(crate) fn mode(&self) -> FrameBlending {
+        self.mode
+    }
+
+    /// Replaces the finished frame by what the screen shows
+    pub(crate) fn apply(&mut self, frame: &mut [u8]) {
+        match self.mode {
+            FrameBlending::Off => {}
+            FrameBlending::Simple => {
+                if self.previous.len() != frame.len() {
+                    self.previous = frame.to_vec();
+                }
+                for (value, previous) in frame.iter_mut().zip(self.previous.iter_mut()) {
+                    let drawn = *value;
+                    *value = (drawn as u16 + *previous as u16).div_ceil(2) as u8;
+                    *previous = drawn;
+                }
+            }
+            FrameBlending::Exponential => {
+                if self.screen.len() != frame.len() {
+                    self.screen = frame.iter().map(|&value| value as f32).collect();
+                }
+                for (value, screen) in frame.iter_mut().zip(self.screen.iter_mut()) {
+                    *screen = *value as f32 + (*screen - *value as f32) * LCD_RETENTION;
+                    *value = screen.round() as u8;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{FrameBlender, FrameBlending};
+
+    #[test]
+    fn simple_blending() {
+        let mut blender = FrameBlender::new(FrameBlending::Simple);
+        let mut frame = [0, 0xFF, 0x80];
+        blender.apply(&mut frame);
+        assert_eq!(frame, [0, 0xFF, 0x80]);
+
+        // Something that is only drawn every other frame looks half transparent
+        for _ in 0..4 {
+            let mut frame = [0xFF, 0, 0x80];
+            blender.apply(&mut frame);
+            assert_eq!(frame, [0x80, 0x80, 0x80]);
+            let mut frame = [0, 0xFF, 0x80];
+            blender.apply(&mut frame);
+            assert_eq!(frame, [0x80, 0x80, 0x80]);
+        }
+    }
+
+    #[test]
+    fn exponential_blending() {
+        let mut blender = FrameBlender::new(FrameBlending::Exponential);
+        let mut frame = [0];
+        blender.apply(&mut frame);
+        assert_eq!(frame, [0]);
+
+        // The screen takes a few frames to settle on a new colour
+        let mut shown = Vec::new();
+        for _ in 0..8 {
+            let mut frame = [0xFF];
+            blender.apply(&mut frame);
+            shown.push(frame[0]);
+        }
+        assert!(shown.windows(2).all(|w| w[0] < w[1] || w[1] == 0xFF));
+        assert!(shown[0] > 0x80 && shown[0] < 0xFF);
+        assert_eq!(shown[7], 0xFF);
+    }
+}
@@ -0,0 +1,128 @@
+/// How the 15 bit colours of the CGB are converted to the RGB colours of the host screen
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ColorCorrection {
+    /// Scales each 5 bit component to 8 bits, the colours as the game specifies them
+    Raw,
+    /// The colour matrix of Gambatte, mixes the components like the CGB screen does
+    Gambatte,
+    /// Like SameBoy's "modern balanced" mode: the response curve of the CGB screen with
+    /// slightly mixed green and blue, keeping the brightness of the colour
+    ModernBalanced,
+    /// The darker and less saturated screen of the GBA, for games that were made for it
+    GbaScreen,
+}
+
+const COLOR_COUNT: usize = 0x8000;
+
+// Brightness of a 5 bit component on the CGB screen, from SameBoy
+#[rustfmt::skip]
+const CGB_CURVE: [u8; 32] = [
+    0, 6, 12, 20, 28, 36, 45, 56, 66, 76, 88, 100, 113, 125, 137, 149,
+    161, 172, 182, 192, 202, 210, 218, 225, 232, 238, 243, 247, 250, 252, 254, 255,
+];
+
+/// The RGB colour of every BGR555 colour for one `ColorCorrection`
+#[derive(Clone, PartialEq)]
+pub(crate) struct ColorTable {
+    correction: ColorCorrection,
+    colors: Box<[[u8; 3]]>,
+}
+
+impl ColorTable {
+    pub(crate) fn new(correction: ColorCorrection) -> ColorTable {
+        let colors = (0..COLOR_COUNT)
+            .map(|color| {
+                let component = |shift: usize| ((color >> shift) & 0x1F) as u8;
+                correct(correction, component(0), component(5), component(10))
+            })
+            .collect();
+        ColorTable { correction, colors }
+    }
+
+    pub(crate) fn correction(&self) -> ColorCorrection {
+        self.correction
+    }
+
+    /// Converts a colour in the BGR555 format of the CGB palette registers
+    pub(crate) fn get(&self, color: u16) -> [u8; 3] {
+        self.colors[color as usize & (COLOR_COUNT - 1)]
+    }
+}
+
+/// Converts a BGR555 colour without correction, like `ColorCorrection::Raw`
+pub(crate) fn raw_color(color: u16) -> [u8; 3] {
+    let component = |shift: u16| ((color >> shift) & 0x1F) as u8;
+    [component(0), component(5), component(10)].map(|c| (c << 3) | (c >> 2))
+}
+
+// r, g and b are between 0 and 1F
+fn correct(correction: ColorCorrection, r: u8, g: u8, b: u8) -> [u8; 3] {
+    match correction {
+        ColorCorrection::Raw => raw_color(r as u16 | (g as u16) << 5 | (b as u16) << 10),
+        ColorCorrection::Gambatte => {
+            // Taken from the Gambatte emulator
+            let r = r as u32;
+            let g = g as u32;
+            let b = b as u32;
+            [
+                ((r * 13 + g * 2 + b) >> 1) as u8,
+                ((g * 3 + b) << 1) as u8,
+                ((r * 3 + g * 2 + b * 11) >> 1) as u8,
+            ]
+        }
+        ColorCorrection::ModernBalanced => {
+            let [r, g, b] = [r, g, b].map(|c| CGB_CURVE[c as usize] as u32);
+            let new = [r, (g * 3 + b) / 4, b];
+            let old_max = r.max(g).max(b);
+            let new_max = new[0].max(new[1]).max(new[2]);
+            if new_max == 0 {
+                return [0; 3];
+            }
+            new.map(|c| (c * old_max / new_max) as u8)
+        }
+        ColorCorrection::GbaScreen => {
+            // The gba-color shader of libretro: the screen is darkened by a higher gamma,
+            // then the components bleed into each other
+            let [r, g, b] = [r, g, b].map(|c| (c as f32 / 31.0).powf(3.2));
+            let mix = |fr: f32, fg: f32, fb: f32| {
+                let linear = ((r * fr + g * fg + b * fb) * 0.94).clamp(0.0, 1.0);
+                (linear.powf(1.0 / 2.2) * 255.0).round() as u8
+            };
+            [
+                mix(0.82, 0.24, -0.06),
+                mix(0.125, 0.665, 0.21),
+                mix(0.195, 0.075, 0.73),
+            ]
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ColorCorrection, ColorTable};
+
+    #[test]
+    fn color_tables() {
+        let raw = ColorTable::new(ColorCorrection::Raw);
+        assert_eq!(raw.get(0x0000), [0x00, 0x00, 0x00]);
+        assert_eq!(raw.get(0x7FFF), [0xFF, 0xFF, 0xFF]);
+        assert_eq!(raw.get(0x001F), [0xFF, 0x00, 0x00]);
+        assert_eq!(raw.get(0x0210), [0x84, 0x84, 0x00]);
+
+        let gambatte = ColorTable::new(ColorCorrection::Gambatte);
+        assert_eq!(gambatte.get(0x7FFF), [0xF8, 0xF8, 0xF8]);
+        assert_eq!(gambatte.get(0x001F), [0xC9, 0x00, 0x2E]);
+
+        let modern = ColorTable::new(ColorCorrection::ModernBalanced);
+        assert_eq!(modern.get(0x0000), [0x00, 0x00, 0x00]);
+        assert_eq!(modern.get(0x7FFF), [0xFF, 0xFF, 0xFF]);
+        // Pure blue takes on some green, but stays as bright
+        assert_eq!(modern.get(0x7C00), [0x00, 0x3F, 0xFF]);
+
+        let gba = ColorTable::new(ColorCorrection::GbaScreen);
+        assert_eq!(gba.get(0x0000), [0x00, 0x00, 0x00]);
+        let [r, g, b] = gba.get(0x7FFF);
+        assert!(r < 0xFF && g < 0xFF && b < 0xFF);
+        assert!(gba.get(0x4210) < raw.get(0x4210));
+    }
+}
@@ -1,9 +1,30 @@
 use crate::instructions;
 use crate::mbc;
-use crate::mmu::MMU;
+use crate::mmu::{MemoryInit, MMU};
 use crate::register::CpuFlag::{C, H, N, Z};
 use crate::register::Registers;
-use crate::StrResult;
+use crate::Result;
+
+// Number of M-cycles the CPU is paused for after a CGB speed switch
+const SPEED_SWITCH_DELAY: u32 = 2050;
+
+/// Describes an illegal opcode which locked up the CPU
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Lockup {
+    /// The illegal opcode that was executed
+    pub opcode: u8,
+    /// Address of the illegal opcode
+    pub address: u16,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CpuStatus {
+    Running,
+    Halted,
+    Stopped,
+    /// The CPU executed an illegal opcode and will not execute any further instructions
+    LockedUp(Lockup),
+}
 
 pub struct CPU {
     pub(crate) reg: Registers,
@@ -13,10 +34,14 @@ pub struct CPU {
     pub(crate) ime: bool,
     pub(crate) setdi: u32,
     pub(crate) setei: u32,
+    pub(crate) stopped: bool,
+    speed_switch_delay: u32,
+    lockup: Option<Lockup>,
+    lockup_reported: bool,
 }
 
 impl CPU {
-    pub fn new(cart: Box<dyn mbc::MBC + 'static>) -> StrResult<CPU> {
+    pub fn new(cart: Box<dyn mbc::MBC + 'static>) -> Result<CPU> {
         let cpu_mmu = MMU::new(cart)?;
         let registers = Registers::new(cpu_mmu.gbmode);
         Ok(CPU {
@@ -26,11 +51,15 @@ impl CPU {
             ime: true,
             setdi: 0,
             setei: 0,
+            stopped: false,
+            speed_switch_delay: 0,
+            lockup: None,
+            lockup_reported: false,
             mmu: cpu_mmu,
         })
     }
 
-    pub fn new_cgb(cart: Box<dyn mbc::MBC + 'static>) -> StrResult<CPU> {
+    pub fn new_cgb(cart: Box<dyn mbc::MBC + 'static>) -> Result<CPU> {
         let cpu_mmu = MMU::new_cgb(cart)?;
         let registers = Registers::new(cpu_mmu.gbmode);
         Ok(CPU {
@@ -40,15 +69,109 @@ impl CPU {
             ime: true,
             setdi: 0,
             setei: 0,
+            stopped: false,
+            speed_switch_delay: 0,
+            lockup: None,
+            lockup_reported: false,
+            mmu: cpu_mmu,
+        })
+    }
+
+    pub fn new_sgb(cart: Box<dyn mbc::MBC + 'static>) -> Result<CPU> {
+        let cpu_mmu = MMU::new_sgb(cart)?;
+        let registers = Registers::new(cpu_mmu.gbmode);
+        Ok(CPU {
+            reg: registers,
+            halted: false,
+            halt_bug: false,
+            ime: true,
+            setdi: 0,
+            setei: 0,
+            stopped: false,
+            speed_switch_delay: 0,
+            lockup: None,
+            lockup_reported: false,
             mmu: cpu_mmu,
         })
     }
 
+    pub fn reset(&mut self) {
+        self.mmu.reset();
+        self.reset_state();
+    }
+
+    pub fn power_cycle(&mut self, init: MemoryInit) {
+        self.mmu.power_cycle(init);
+        self.reset_state();
+    }
+
+    fn reset_state(&mut self) {
+        self.reg = Registers::new(self.mmu.gbmode);
+        self.halted = false;
+        self.halt_bug = false;
+        self.ime = true;
+        self.setdi = 0;
+        self.setei = 0;
+        self.stopped = false;
+        self.speed_switch_delay = 0;
+        self.lockup = None;
+        self.lockup_reported = false;
+    }
+
     pub fn do_cycle(&mut self) -> u32 {
+        if self.lockup.is_some() {
+            // The CPU hangs, but the rest of the hardware keeps running
+            return self.mmu.do_cycle(4);
+        }
+        if self.stopped {
+            return self.stopcycle();
+        }
+        if self.speed_switch_delay > 0 {
+            // The CPU and DIV are frozen during the speed switch, the rest keeps running
+            let ticks = self.speed_switch_delay * 4;
+            self.speed_switch_delay = 0;
+            return self.mmu.do_cycle_speed_switch(ticks);
+        }
         let ticks = self.docycle() * 4;
         return self.mmu.do_cycle(ticks);
     }
 
+    pub fn status(&self) -> CpuStatus {
+        if let Some(lockup) = self.lockup {
+            CpuStatus::LockedUp(lockup)
+        } else if self.stopped {
+            CpuStatus::Stopped
+        } else if self.halted {
+            CpuStatus::Halted
+        } else {
+            CpuStatus::Running
+        }
+    }
+
+    pub fn check_and_reset_lockup(&mut self) -> Option<Lockup> {
+        if self.lockup_reported {
+            return None;
+        }
+        self.lockup_reported = self.lockup.is_some();
+        self.lockup
+    }
+
+    pub(crate) fn lock_up(&mut self, opcode: u8) {
+        self.lockup = Some(Lockup {
+            opcode,
+            address: self.reg.pc.wrapping_sub(1),
+        });
+    }
+
+    fn stopcycle(&mut self) -> u32 {
+        // In STOP mode the oscillator is halted, so nothing but the joypad is clocked.
+        // Any selected joypad line going low wakes the CPU again.
+        if self.mmu.keypad.selected_pressed() {
+            self.stopped = false;
+        }
+        4
+    }
+
     fn docycle(&mut self) -> u32 {
         self.updateime();
         match self.handleinterrupt() {
@@ -77,10 +200,28 @@ impl CPU {
 
     pub(crate) fn fetchword(&mut self) -> u16 {
         let w = self.mmu.rw(self.reg.pc);
-        self.reg.pc += 2;
+        self.reg.pc = self.reg.pc.wrapping_add(2);
         w
     }
 
+    pub(crate) fn stop(&mut self) {
+        // STOP is followed by a byte which is skipped
+        self.fetchbyte();
+
+        if self.mmu.switch_speed() {
+            self.mmu.wb(0xFF04, 0);
+            self.speed_switch_delay = SPEED_SWITCH_DELAY;
+            return;
+        }
+
+        // With a button already held down STOP does not enter low-power mode
+        if self.mmu.keypad.selected_pressed() {
+            return;
+        }
+        self.mmu.wb(0xFF04, 0);
+        self.stopped = true;
+    }
+
     fn updateime(&mut self) {
         self.setdi = match self.setdi {
             2 => 1,
@@ -110,22 +251,32 @@ impl CPU {
             return 0;
         }
 
+        // Leaving HALT costs one additional M-cycle
+        let wakeup = if self.halted { 1 } else { 0 };
         self.halted = false;
         if self.ime == false {
-            return 0;
+            return wakeup;
         }
         self.ime = false;
 
-        let n = triggered.trailing_zeros();
-        if n >= 5 {
-            panic!("Invalid interrupt triggered");
-        }
-        self.mmu.intf &= !(1 << n);
+        // The high byte of PC is pushed first. When this write hits IE and disables the
+        // pending interrupt, the dispatch is cancelled and execution continues at 0x0000.
         let pc = self.reg.pc;
-        self.pushstack(pc);
-        self.reg.pc = 0x0040 | ((n as u16) << 3);
+        self.reg.sp = self.reg.sp.wrapping_sub(1);
+        self.mmu.wb(self.reg.sp, (pc >> 8) as u8);
+        let triggered = self.mmu.inte & self.mmu.intf & 0x1F;
+        self.reg.sp = self.reg.sp.wrapping_sub(1);
+        self.mmu.wb(self.reg.sp, (pc & 0xFF) as u8);
 
-        4
+        if triggered == 0 {
+            self.reg.pc = 0x0000;
+        } else {
+            let n = triggered.trailing_zeros();
+            self.mmu.intf &= !(1 << n);
+            self.reg.pc = 0x0040 | ((n as u16) << 3);
+        }
+
+        wakeup + 5
     }
 
     pub(crate) fn pushstack(&mut self, value: u16) {
@@ -135,7 +286,7 @@ impl CPU {
 
     pub(crate) fn popstack(&mut self) -> u16 {
         let res = self.mmu.rw(self.reg.sp);
-        self.reg.sp += 2;
+        self.reg.sp = self.reg.sp.wrapping_add(2);
         res
     }
 
@@ -339,3 +490,145 @@ impl CPU {
         instructions::call_cb(self)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{CpuStatus, Lockup, CPU};
+    use crate::keypad::KeypadKey;
+    use crate::mbc;
+    use crate::mmu::{MemoryInit, RamPattern};
+
+    fn cpu_with_program(program: &[u8]) -> CPU {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x100 + program.len()].copy_from_slice(program);
+        let cart = mbc::get_mbc(rom, true).unwrap();
+        CPU::new(cart).unwrap()
+    }
+
+    #[test]
+    fn interrupt_dispatch() {
+        let mut cpu = cpu_with_program(&[0x00]);
+        cpu.mmu.inte = 0x04;
+        cpu.mmu.intf = 0x04;
+
+        assert_eq!(cpu.docycle(), 5);
+        assert_eq!(cpu.reg.pc, 0x0050);
+        assert_eq!(cpu.reg.sp, 0xFFFC);
+        assert_eq!(cpu.mmu.rw(0xFFFC), 0x0100);
+        assert_eq!(cpu.mmu.intf & 0x04, 0);
+        assert!(!cpu.ime);
+    }
+
+    #[test]
+    fn halt_wakeup() {
+        let mut cpu = cpu_with_program(&[0x76, 0x00]);
+        cpu.mmu.inte = 0x01;
+        assert_eq!(cpu.docycle(), 1);
+        assert!(cpu.halted);
+
+        cpu.mmu.intf = 0x01;
+        assert_eq!(cpu.docycle(), 6);
+        assert!(!cpu.halted);
+        assert_eq!(cpu.reg.pc, 0x0040);
+    }
+
+    #[test]
+    fn ie_push_cancels_dispatch() {
+        let mut cpu = cpu_with_program(&[0x00]);
+        // The high byte of PC (0x01) ends up in IE, which disables the STAT interrupt
+        cpu.reg.sp = 0x0000;
+        cpu.mmu.inte = 0x02;
+        cpu.mmu.intf = 0x02;
+
+        assert_eq!(cpu.docycle(), 5);
+        assert_eq!(cpu.reg.pc, 0x0000);
+        assert_eq!(cpu.mmu.inte, 0x01);
+        assert_eq!(cpu.mmu.intf & 0x02, 0x02);
+    }
+
+    #[test]
+    fn stop_waits_for_joypad() {
+        let mut cpu = cpu_with_program(&[0x10, 0x00, 0x00]);
+        cpu.mmu.wb(0xFF00, 0x20);
+        cpu.do_cycle();
+        assert!(cpu.stopped);
+        assert_eq!(cpu.reg.pc, 0x0102);
+
+        cpu.do_cycle();
+        assert!(cpu.stopped);
+        assert_eq!(cpu.mmu.rb(0xFF04), 0);
+
+        cpu.mmu.keypad.keydown(KeypadKey::Right);
+        cpu.do_cycle();
+        assert!(!cpu.stopped);
+    }
+
+    #[test]
+    fn illegal_opcode_locks_up() {
+        let mut cpu = cpu_with_program(&[0x00, 0xDD, 0x3C]);
+        cpu.do_cycle();
+        assert_eq!(cpu.status(), CpuStatus::Running);
+        assert_eq!(cpu.check_and_reset_lockup(), None);
+
+        cpu.do_cycle();
+        let lockup = Lockup {
+            opcode: 0xDD,
+            address: 0x0101,
+        };
+        assert_eq!(cpu.status(), CpuStatus::LockedUp(lockup));
+        assert_eq!(cpu.check_and_reset_lockup(), Some(lockup));
+        assert_eq!(cpu.check_and_reset_lockup(), None);
+
+        // Neither instructions nor interrupts are executed anymore
+        let a = cpu.reg.a;
+        cpu.ime = true;
+        cpu.mmu.inte = 0x01;
+        cpu.mmu.intf = 0x01;
+        cpu.do_cycle();
+        assert_eq!(cpu.reg.a, a);
+        assert_eq!(cpu.reg.pc, 0x0102);
+    }
+
+    #[test]
+    fn reset_keeps_memory() {
+        let mut cpu = cpu_with_program(&[0x3C, 0xDD]);
+        cpu.mmu.wb(0xC000, 0x12);
+        cpu.mmu.wb(0xFF06, 0x34);
+        cpu.do_cycle();
+        cpu.do_cycle();
+        assert!(cpu.check_and_reset_lockup().is_some());
+
+        cpu.reset();
+        assert_eq!(cpu.status(), CpuStatus::Running);
+        assert_eq!(cpu.reg.pc, 0x0100);
+        assert_eq!(cpu.reg.a, 0x01);
+        assert_eq!(cpu.mmu.rb(0xFF06), 0x00);
+        assert_eq!(cpu.mmu.rb(0xC000), 0x12);
+    }
+
+    #[test]
+    fn power_cycle_initializes_memory() {
+        let mut cpu = cpu_with_program(&[0x00]);
+        cpu.mmu.wb(0xC000, 0x12);
+        cpu.mmu.wb(0x8000, 0x34);
+
+        cpu.power_cycle(MemoryInit {
+            wram: RamPattern::Fill(0xAA),
+            vram: RamPattern::Fill(0x55),
+        });
+        assert_eq!(cpu.mmu.rb(0xC000), 0xAA);
+        assert_eq!(cpu.mmu.rb(0xDFFF), 0xAA);
+        assert_eq!(cpu.mmu.rb(0x8000), 0x55);
+
+        let init = MemoryInit {
+            wram: RamPattern::Random { seed: 7 },
+            vram: RamPattern::Zero,
+        };
+        cpu.power_cycle(init);
+        let first: Vec<u8> = (0xC000..0xC100).map(|a| cpu.mmu.rb(a)).collect();
+        cpu.power_cycle(init);
+        let second: Vec<u8> = (0xC000..0xC100).map(|a| cpu.mmu.rb(a)).collect();
+        assert_eq!(first, second);
+        assert_eq!(cpu.mmu.rb(0x8000), 0x00);
+    }
+}
@@ -1,12 +1,24 @@
-use crate::cpu::CPU;
+use crate::blend::FrameBlending;
+use crate::color::ColorCorrection;
+use crate::cpu::{CpuStatus, Lockup, CPU};
 use crate::gbmode::GbMode;
+use crate::gpu::{Layer, OamEntry, TileMap};
+use crate::image::Image;
 use crate::keypad::KeypadKey;
 use crate::mbc;
-use crate::sound;
-use crate::StrResult;
+use crate::mmu::MemoryInit;
+use crate::palette::DmgPalette;
+use crate::pixel::PixelFormat;
+use crate::sound::{self, HighPassFilter, SoundChannel};
+use crate::timeline::FrameTimeline;
+use crate::video::{VideoRecorder, Y4mWriter};
+use crate::{ApuLog, Error, Result};
+use std::path::Path;
 
 pub struct Device {
     cpu: CPU,
+    video: Option<VideoRecorder>,
+    memory_init: MemoryInit,
 }
 
 impl Device {
@@ -14,37 +26,191 @@ impl Device {
         romname: &str,
         _skip_checksum: bool,
         _save_state: Option<String>,
-    ) -> StrResult<Device> {
+    ) -> Result<Device> {
         let cart = mbc::FileBackedMBC::new(romname.into(), false)?;
-        CPU::new(Box::new(cart)).map(|cpu| Device { cpu })
+        CPU::new(Box::new(cart)).map(Device::with_cpu)
     }
 
     pub fn new_cgb(
         romname: &str,
         _skip_checksum: bool,
         _save_state: Option<String>,
-    ) -> StrResult<Device> {
+    ) -> Result<Device> {
         let cart = mbc::FileBackedMBC::new(romname.into(), false)?;
-        CPU::new_cgb(Box::new(cart)).map(|cpu| Device { cpu })
+        CPU::new_cgb(Box::new(cart)).map(Device::with_cpu)
+    }
+
+    /// Runs the game in a Super Game Boy, with its palettes, attributes and border
+    pub fn new_sgb(
+        romname: &str,
+        _skip_checksum: bool,
+        _save_state: Option<String>,
+    ) -> Result<Device> {
+        let cart = mbc::FileBackedMBC::new(romname.into(), false)?;
+        CPU::new_sgb(Box::new(cart)).map(Device::with_cpu)
+    }
+
+    fn with_cpu(cpu: CPU) -> Device {
+        Device {
+            cpu,
+            video: None,
+            memory_init: MemoryInit::default(),
+        }
+    }
+
+    /// Starts with WRAM and VRAM filled according to `init` instead of
+    /// `MemoryInit::default()`. `memory_init` returns it for later power cycles.
+    pub fn with_memory_init(mut self, init: MemoryInit) -> Device {
+        self.memory_init = init;
+        self.cpu.power_cycle(init);
+        self
+    }
+
+    /// The contents of WRAM and VRAM this device was started with
+    pub fn memory_init(&self) -> MemoryInit {
+        self.memory_init
+    }
+
+    /// Selects the format of the frames returned by `get_gpu_data` and `get_sgb_data`,
+    /// `PixelFormat::Rgb888` by default
+    pub fn with_pixel_format(mut self, format: PixelFormat) -> Device {
+        self.cpu.mmu.gpu.set_pixel_format(format);
+        self
     }
 
     pub fn do_cycle(&mut self) -> u32 {
-        self.cpu.do_cycle()
+        let ticks = self.cpu.do_cycle();
+        if let Some(video) = self.video.as_mut() {
+            video.add_cycles(ticks);
+        }
+        ticks
+    }
+
+    /// Resets the CPU and all I/O registers to their post-boot state.
+    /// The contents of WRAM, VRAM and the cartridge RAM are kept.
+    pub fn reset(&mut self) {
+        self.cpu.reset();
+    }
+
+    /// Emulates switching the console off and on again. WRAM and VRAM are filled according to
+    /// `init`, the battery backed cartridge RAM is kept.
+    pub fn power_cycle(&mut self, init: MemoryInit) {
+        self.cpu.power_cycle(init);
+    }
+
+    pub fn status(&self) -> CpuStatus {
+        self.cpu.status()
+    }
+
+    /// Returns the lockup once after the CPU executed an illegal opcode.
+    /// The host decides what to do next, the device itself just keeps running with a hung CPU.
+    pub fn check_and_reset_lockup(&mut self) -> Option<Lockup> {
+        self.cpu.check_and_reset_lockup()
     }
 
     pub fn check_and_reset_gpu_updated(&mut self) -> bool {
         let result = self.cpu.mmu.gpu.updated;
         self.cpu.mmu.gpu.updated = false;
+        if let (true, Some(video)) = (result, self.video.as_mut()) {
+            video.frame(&self.cpu.mmu.gpu.screenshot(false).data);
+        }
         result
     }
 
+    /// Selects the colours of classic games. Classic mode uses `DmgPalette::Grey` by default,
+    /// classic games on a CGB get the palette the CGB boot ROM selects.
+    pub fn set_dmg_palette(&mut self, palette: DmgPalette) {
+        self.cpu.mmu.set_dmg_palette(palette);
+    }
+
+    /// Selects how CGB colours are shown, `ColorCorrection::Gambatte` by default. Also applies
+    /// to the colours of classic games on a CGB and to the SGB palettes and border.
+    pub fn set_color_correction(&mut self, correction: ColorCorrection) {
+        self.cpu.mmu.gpu.set_color_correction(correction);
+    }
+
+    /// Blends consecutive frames like the slow screens of the DMG and CGB, off by default.
+    /// Not applied to `PixelFormat::Indexed` frames.
+    pub fn set_frame_blending(&mut self, mode: FrameBlending) {
+        self.cpu.mmu.gpu.set_frame_blending(mode);
+    }
+
+    pub fn frame_blending(&self) -> FrameBlending {
+        self.cpu.mmu.gpu.frame_blending()
+    }
+
+    /// Hides or shows the background, the window or the sprites, independent of LCDC. All
+    /// layers are shown by default.
+    pub fn set_layer_enabled(&mut self, layer: Layer, enabled: bool) {
+        self.cpu.mmu.gpu.set_layer_enabled(layer, enabled);
+    }
+
+    pub fn layer_enabled(&self, layer: Layer) -> bool {
+        self.cpu.mmu.gpu.layer_enabled(layer)
+    }
+
+    /// The current screen, or the SGB picture with its border, enlarged by an integer `scale`.
+    /// `raw_colors` shows the colours of CGB games as they are set, without colour correction
+    /// and frame blending. Save it with `Image::save_png`.
+    pub fn screenshot(&self, scale: usize, raw_colors: bool) -> Image {
+        self.cpu.mmu.gpu.screenshot(raw_colors).scaled(scale)
+    }
+
+    /// The tiles of both VRAM banks. Like the other debug views, this shows the current state,
+    /// which may be in the middle of being changed by the game.
+    pub fn render_tiles(&self) -> Image {
+        self.cpu.mmu.gpu.render_tiles()
+    }
+
+    /// A tile map with the visible part of the background outlined
+    pub fn render_tile_map(&self, map: TileMap) -> Image {
+        self.cpu.mmu.gpu.render_tile_map(map)
+    }
+
+    pub fn oam_entries(&self) -> [OamEntry; 40] {
+        self.cpu.mmu.gpu.oam_entries()
+    }
+
+    /// The 40 sprites in OAM order, 8 per row
+    pub fn render_oam(&self) -> Image {
+        self.cpu.mmu.gpu.render_oam()
+    }
+
+    /// The BG palettes on the left and the OBJ palettes on the right
+    pub fn render_palettes(&self) -> Image {
+        self.cpu.mmu.gpu.render_palettes()
+    }
+
+    /// Records the PPU register writes and interrupts of each frame, for debugging raster
+    /// effects
+    pub fn set_timeline_enabled(&mut self, enabled: bool) {
+        self.cpu.mmu.gpu.set_timeline_enabled(enabled);
+    }
+
+    /// The register writes and interrupts of the last finished frame, if recording is enabled
+    pub fn timeline(&self) -> Option<&FrameTimeline> {
+        self.cpu.mmu.gpu.timeline()
+    }
+
+    /// The timeline of the last finished frame over a picture of the line and dot timing
+    pub fn render_timeline(&self) -> Option<Image> {
+        self.cpu.mmu.gpu.render_timeline()
+    }
+
+    /// The screen of `SCREEN_W` x `SCREEN_H` pixels in the selected pixel format
     pub fn get_gpu_data(&self) -> &[u8] {
-        &self.cpu.mmu.gpu.data
+        self.cpu.mmu.gpu.frame()
+    }
+
+    /// The complete SGB picture of `SGB_SCREEN_W` x `SGB_SCREEN_H` pixels including the border.
+    /// Returns `None` when not running in SGB mode.
+    pub fn get_sgb_data(&self) -> Option<&[u8]> {
+        self.cpu.mmu.gpu.sgb_frame()
     }
 
     pub fn enable_audio(&mut self, player: Box<dyn sound::AudioPlayer>, is_on: bool) {
         match self.cpu.mmu.gbmode {
-            GbMode::Classic => {
+            GbMode::Classic | GbMode::Sgb => {
                 self.cpu.mmu.sound = Some(sound::Sound::new_dmg(player));
             }
             GbMode::Color | GbMode::ColorAsClassic => {
@@ -64,6 +230,195 @@ impl Device {
         }
     }
 
+    /// Outputs the audio generated so far, hosts pacing emulation by the audio buffer call this
+    /// once per frame
+    pub fn flush_audio(&mut self) {
+        if let Some(ref mut sound) = self.cpu.mmu.sound {
+            sound.flush();
+        }
+    }
+
+    /// Generates audio slightly faster or slower than the sample rate of the player, to keep
+    /// its buffer at the desired level. `ratio` is limited to 0.995-1.005, and ignored while
+    /// audio or video is recorded.
+    pub fn set_audio_rate_ratio(&mut self, ratio: f64) {
+        if let Some(ref mut sound) = self.cpu.mmu.sound {
+            sound.set_rate_ratio(ratio);
+        }
+    }
+
+    /// Current digital output (0-15) of the four sound channels, for visualisers.
+    /// All zero when audio is not enabled.
+    pub fn channel_amplitudes(&mut self) -> [u8; 4] {
+        self.cpu
+            .mmu
+            .sound
+            .as_mut()
+            .map_or([0; 4], |sound| sound.channel_amplitudes())
+    }
+
+    /// Silences a channel in the mixer. Like solo and gain, this only affects the audio output,
+    /// the emulated sound registers are left alone.
+    pub fn set_channel_muted(&mut self, channel: SoundChannel, muted: bool) {
+        if let Some(sound) = self.cpu.mmu.sound.as_mut() {
+            sound.set_muted(channel, muted);
+        }
+    }
+
+    pub fn channel_muted(&self, channel: SoundChannel) -> bool {
+        self.cpu.mmu.sound.as_ref().is_some_and(|sound| sound.muted(channel))
+    }
+
+    /// While any channel is soloed, only the soloed channels are heard
+    pub fn set_channel_solo(&mut self, channel: SoundChannel, solo: bool) {
+        if let Some(sound) = self.cpu.mmu.sound.as_mut() {
+            sound.set_solo(channel, solo);
+        }
+    }
+
+    pub fn channel_solo(&self, channel: SoundChannel) -> bool {
+        self.cpu.mmu.sound.as_ref().is_some_and(|sound| sound.solo(channel))
+    }
+
+    /// Scales the output of a channel, 1.0 is the emulated volume
+    pub fn set_channel_gain(&mut self, channel: SoundChannel, gain: f32) {
+        if let Some(sound) = self.cpu.mmu.sound.as_mut() {
+            sound.set_gain(channel, gain);
+        }
+    }
+
+    pub fn channel_gain(&self, channel: SoundChannel) -> f32 {
+        self.cpu.mmu.sound.as_ref().map_or(1.0, |sound| sound.gain(channel))
+    }
+
+    /// Selects how the capacitors at the audio output are modelled. The default is
+    /// `HighPassFilter::Accurate`.
+    pub fn set_high_pass_filter(&mut self, filter: HighPassFilter) {
+        if let Some(sound) = self.cpu.mmu.sound.as_mut() {
+            sound.set_high_pass_filter(filter);
+        }
+    }
+
+    pub fn high_pass_filter(&self) -> HighPassFilter {
+        let sound = self.cpu.mmu.sound.as_ref();
+        sound.map_or(HighPassFilter::Accurate, |sound| sound.high_pass_filter())
+    }
+
+    /// Starts recording the mixed audio output to a 32-bit float WAV file. The recording does
+    /// not depend on the audio player keeping up, and it keeps the sample rate of the player
+    /// while the rate ratio is ignored, so it is identical on every run of a ROM. Fails with
+    /// `Error::AlreadyRecordingVideo` during a video recording.
+    pub fn start_audio_recording(&mut self, path: &Path) -> Result<()> {
+        if self.video.is_some() {
+            return Err(Error::AlreadyRecordingVideo);
+        }
+        match self.cpu.mmu.sound.as_mut() {
+            Some(sound) => sound.start_recording(path),
+            None => Err(Error::AudioNotEnabled),
+        }
+    }
+
+    /// Completes the current recording, if any. The audio of a video recording is only stopped
+    /// by `stop_video_recording`, this fails with `Error::AlreadyRecordingVideo`.
+    pub fn stop_audio_recording(&mut self) -> Result<()> {
+        if self.video.is_some() {
+            return Err(Error::AlreadyRecordingVideo);
+        }
+        match self.cpu.mmu.sound.as_mut() {
+            Some(sound) => sound.stop_recording(),
+            None => Ok(()),
+        }
+    }
+
+    /// True while the audio is recorded on its own, not as part of a video
+    pub fn is_recording_audio(&self) -> bool {
+        let sound = self.cpu.mmu.sound.as_ref();
+        self.video.is_none() && sound.is_some_and(|sound| sound.is_recording())
+    }
+
+    /// Starts recording each sound channel to its own stereo WAV file in `dir`, e.g. to mix
+    /// them as separate tracks. The channels are recorded before mute, solo and gain.
+    pub fn start_channel_recording(&mut self, dir: &Path) -> Result<()> {
+        match self.cpu.mmu.sound.as_mut() {
+            Some(sound) => sound.start_channel_recording(dir),
+            None => Err(Error::AudioNotEnabled),
+        }
+    }
+
+    pub fn stop_channel_recording(&mut self) -> Result<()> {
+        match self.cpu.mmu.sound.as_mut() {
+            Some(sound) => sound.stop_channel_recording(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn is_recording_channels(&self) -> bool {
+        self.cpu.mmu.sound.as_ref().is_some_and(|sound| sound.is_recording_channels())
+    }
+
+    /// Starts recording the screen to a Y4M video and the audio to a WAV file, see
+    /// `start_audio_recording`. Frames are captured by `check_and_reset_gpu_updated` and placed
+    /// by the emulated time, so the video and audio stay in sync. The SGB picture is recorded
+    /// with its border. Fails with `Error::AlreadyRecordingAudio` while the audio is recorded
+    /// on its own.
+    pub fn start_video_recording(&mut self, video: &Path, audio: &Path) -> Result<()> {
+        self.stop_video_recording()?;
+        if self.is_recording_audio() {
+            return Err(Error::AlreadyRecordingAudio);
+        }
+        let sound = self.cpu.mmu.sound.as_mut().ok_or(Error::AudioNotEnabled)?;
+        // The audio recording starts with the samples generated from now on
+        sound.start_recording(audio)?;
+        let screen = self.cpu.mmu.gpu.screenshot(false);
+        let writer = match Y4mWriter::create(video, screen.width, screen.height) {
+            Ok(writer) => writer,
+            Err(e) => {
+                // Leaves no audio without its video behind
+                let _ = sound.stop_recording();
+                let _ = std::fs::remove_file(audio);
+                return Err(e);
+            }
+        };
+        self.video = Some(VideoRecorder::new(writer, screen.data));
+        Ok(())
+    }
+
+    pub fn stop_video_recording(&mut self) -> Result<()> {
+        let video = match self.video.take() {
+            Some(video) => video,
+            None => return Ok(()),
+        };
+        let audio = match self.cpu.mmu.sound.as_mut() {
+            Some(sound) => {
+                sound.flush();
+                sound.stop_recording()
+            }
+            None => Ok(()),
+        };
+        video.finish().and(audio)
+    }
+
+    pub fn is_recording_video(&self) -> bool {
+        self.video.is_some()
+    }
+
+    /// Starts logging every write to the sound registers with its timestamp. The log can be
+    /// exported as VGM file, or compared to another log to test changes to the sound unit.
+    pub fn start_apu_log(&mut self) -> Result<()> {
+        match self.cpu.mmu.sound.as_mut() {
+            Some(sound) => {
+                sound.start_apu_log();
+                Ok(())
+            }
+            None => Err(Error::AudioNotEnabled),
+        }
+    }
+
+    /// Stops logging, returns `None` if no log was started
+    pub fn stop_apu_log(&mut self) -> Option<ApuLog> {
+        self.cpu.mmu.sound.as_mut().and_then(|sound| sound.stop_apu_log())
+    }
+
     pub fn keyup(&mut self, key: KeypadKey) {
         self.cpu.mmu.keypad.keyup(key);
     }
@@ -72,7 +427,119 @@ impl Device {
         self.cpu.mmu.keypad.keydown(key);
     }
 
+    /// Key events for the additional controllers of the SGB multiplayer mode, `player` is 0-3
+    pub fn keyup_player(&mut self, player: usize, key: KeypadKey) {
+        self.cpu.mmu.keypad.keyup_player(player, key);
+    }
+
+    pub fn keydown_player(&mut self, player: usize, key: KeypadKey) {
+        self.cpu.mmu.keypad.keydown_player(player, key);
+    }
+
     pub fn romname(&self) -> String {
         self.cpu.mmu.mbc.romname()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::Device;
+    use crate::cpu::CPU;
+    use crate::sound::AudioPlayer;
+    use crate::{mbc, Error};
+
+    struct NullPlayer;
+
+    impl AudioPlayer for NullPlayer {
+        fn play(&mut self, _left_channel: &[f32], _right_channel: &[f32]) {}
+
+        fn samples_rate(&self) -> u32 {
+            44100
+        }
+
+        fn underflowed(&self) -> bool {
+            false
+        }
+    }
+
+    // A ROM that loops at the entry point, with the LCD left on by the boot ROM
+    fn test_device() -> Device {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x102].copy_from_slice(&[0x18, 0xFE]);
+        let cart = mbc::get_mbc(rom, true).unwrap();
+        let mut device = Device::with_cpu(CPU::new(cart).unwrap());
+        device.enable_audio(Box::new(NullPlayer), false);
+        device
+    }
+
+    #[test]
+    fn video_recording_in_sync() {
+        let dir = std::env::temp_dir();
+        let video = dir.join("gb_emulator_sync.y4m");
+        let audio = dir.join("gb_emulator_sync.wav");
+        let mut device = test_device();
+        device.start_video_recording(&video, &audio).unwrap();
+        let mut ticks = 0u64;
+        for _ in 0..300 {
+            // The windowed mode adjusts the rate every frame
+            device.set_audio_rate_ratio(1.005);
+            while !device.check_and_reset_gpu_updated() {
+                ticks += device.do_cycle() as u64;
+            }
+        }
+        device.stop_video_recording().unwrap();
+
+        let video_data = std::fs::read(&video).unwrap();
+        let audio_size = std::fs::metadata(&audio).unwrap().len();
+        let _ = std::fs::remove_file(&video);
+        let _ = std::fs::remove_file(&audio);
+        let header = video_data.iter().position(|&b| b == b'\n').unwrap() + 1;
+        let frames = (video_data.len() - header) as u64 / (6 + 160 * 144 * 3);
+        // Stereo 32-bit samples after a 58 bytes header, at the nominal rate
+        let samples = (audio_size - 58) / 8;
+        assert!(samples.abs_diff(ticks * 44100 / 4194304) <= 2, "{} samples", samples);
+        // The audio ends where the last frame of the video starts, within half a frame
+        assert!(samples.abs_diff((frames - 1) * 70224 * 44100 / 4194304) <= 369);
+    }
+
+    #[test]
+    fn video_recording_refused_while_recording_audio() {
+        let dir = std::env::temp_dir();
+        let video = dir.join("gb_emulator_refused.y4m");
+        let audio = dir.join("gb_emulator_refused.wav");
+        let mut device = test_device();
+        device.start_audio_recording(&audio).unwrap();
+        let result = device.start_video_recording(&video, &dir.join("other.wav"));
+        assert!(matches!(result, Err(Error::AlreadyRecordingAudio)));
+        assert!(device.is_recording_audio());
+        assert!(!video.exists());
+        device.stop_audio_recording().unwrap();
+        let _ = std::fs::remove_file(&audio);
+    }
+
+    #[test]
+    fn audio_recording_refused_while_recording_video() {
+        let dir = std::env::temp_dir();
+        let video = dir.join("gb_emulator_refused_audio.y4m");
+        let audio = dir.join("gb_emulator_refused_audio.wav");
+        let other = dir.join("gb_emulator_refused_other.wav");
+        let mut device = test_device();
+        device.start_video_recording(&video, &audio).unwrap();
+        assert!(!device.is_recording_audio());
+        let result = device.start_audio_recording(&other);
+        assert!(matches!(result, Err(Error::AlreadyRecordingVideo)));
+        assert!(!other.exists());
+        let result = device.stop_audio_recording();
+        assert!(matches!(result, Err(Error::AlreadyRecordingVideo)));
+        assert!(device.is_recording_video());
+        // The audio of the video is still recorded
+        for _ in 0..10000 {
+            device.do_cycle();
+        }
+        device.stop_video_recording().unwrap();
+        let audio_size = std::fs::metadata(&audio).unwrap().len();
+        let _ = std::fs::remove_file(&video);
+        let _ = std::fs::remove_file(&audio);
+        assert!(audio_size > 58);
+    }
+}
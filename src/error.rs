@@ -0,0 +1,79 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Reading or writing a file failed
+    Io { path: PathBuf, source: io::Error },
+    /// The ROM is too small to contain a cartridge header
+    RomTooSmall { size: usize },
+    /// The header checksum at 0x014D does not match the header
+    InvalidChecksum { expected: u8, found: u8 },
+    /// The cartridge type at 0x0147 is not supported
+    UnsupportedCartridge { cartridge_type: u8 },
+    /// The ROM size at 0x0148 is not valid
+    InvalidRomSize { value: u8 },
+    /// The save data does not match the RAM size of the cartridge
+    InvalidSaveSize { expected: usize, found: usize },
+    /// A CGB only cartridge was started in classic mode
+    CgbOnly,
+    /// The operation needs audio, which was not enabled with `Device::enable_audio`
+    AudioNotEnabled,
+    /// A video recording was started while the audio is recorded on its own
+    AlreadyRecordingAudio,
+    /// The audio recording was started or stopped while it belongs to a video recording
+    AlreadyRecordingVideo,
+    /// The file is not a valid GBS file, or the song does not exist
+    InvalidGbs { reason: &'static str },
+    /// An error that occurred while loading the ROM at the given path
+    Rom { path: PathBuf, source: Box<Error> },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io { path, source } => write!(f, "{}: {}", path.display(), source),
+            Error::RomTooSmall { size } => {
+                write!(f, "ROM is too small ({} bytes) to contain a header", size)
+            }
+            Error::InvalidChecksum { expected, found } => write!(
+                f,
+                "cartridge checksum is invalid (expected {:02X}, found {:02X} at 014D)",
+                expected, found
+            ),
+            Error::UnsupportedCartridge { cartridge_type } => write!(
+                f,
+                "unsupported cartridge type {:02X} at 0147",
+                cartridge_type
+            ),
+            Error::InvalidRomSize { value } => {
+                write!(f, "invalid ROM size {:02X} at 0148", value)
+            }
+            Error::InvalidSaveSize { expected, found } => write!(
+                f,
+                "save data has incorrect length (expected {} bytes, found {})",
+                expected, found
+            ),
+            Error::CgbOnly => write!(f, "this game does not work in Classic mode"),
+            Error::AudioNotEnabled => write!(f, "audio is not enabled"),
+            Error::AlreadyRecordingAudio => write!(f, "the audio is already being recorded"),
+            Error::AlreadyRecordingVideo => write!(f, "the audio is recorded with the video"),
+            Error::InvalidGbs { reason } => write!(f, "invalid GBS file: {}", reason),
+            Error::Rom { path, source } => write!(f, "{}: {}", path.display(), source),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            Error::Rom { source, .. } => Some(&**source),
+            _ => None,
+        }
+    }
+}
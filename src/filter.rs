@@ -0,0 +1,210 @@
+use crate::image::Image;
+use crate::xbrz;
+
+/// Upscaling filters for the screen, applied on the CPU so screenshots look like the window
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Filter {
+    /// The picture as it is
+    None,
+    /// Scale2x (EPX), sharpens diagonal edges without adding colours
+    Scale2x,
+    /// Scale3x, like Scale2x with three times the size
+    Scale3x,
+    /// xBRZ, follows diagonal and shallow edges with smooth transitions
+    Xbrz2x,
+    /// xBRZ with three times the size
+    Xbrz3x,
+    /// xBRZ with four times the size
+    Xbrz4x,
+    /// A dot matrix LCD, every pixel is a dot of 3x3 pixels with a darker gap to the next
+    LcdGrid,
+}
+
+// Brightness of the gaps between the dots of `LcdGrid`, in 1/256
+const LCD_GAP_BRIGHTNESS: u32 = 160;
+// The largest factor of a filter that works pixel by pixel
+const MAX_FACTOR: usize = 3;
+
+// The pixels that replace a pixel, row by row. Only the first n x n are used.
+type Pixels = [[u8; 3]; MAX_FACTOR * MAX_FACTOR];
+
+impl Filter {
+    /// The factor by which the filter enlarges the picture
+    pub fn factor(self) -> usize {
+        match self {
+            Filter::None => 1,
+            Filter::Scale2x | Filter::Xbrz2x => 2,
+            Filter::Scale3x | Filter::Xbrz3x | Filter::LcdGrid => 3,
+            Filter::Xbrz4x => 4,
+        }
+    }
+
+    pub fn apply(self, image: &Image) -> Image {
+        let n = self.factor();
+        if let Filter::Xbrz2x | Filter::Xbrz3x | Filter::Xbrz4x = self {
+            // xBRZ looks at the corners between the pixels first
+            return xbrz::scale(image, n);
+        }
+        let mut res = Image::new(image.width * n, image.height * n);
+        for y in 0..image.height {
+            for x in 0..image.width {
+                let block = Block { image, x, y };
+                let pixels = self.scale_pixel(&block);
+                for (i, &color) in pixels[..n * n].iter().enumerate() {
+                    res.set_pixel(x * n + i % n, y * n + i / n, color);
+                }
+            }
+        }
+        res
+    }
+
+    // The n x n pixels that replace a pixel
+    fn scale_pixel(self, p: &Block) -> Pixels {
+        let e = p.at(0, 0);
+        let mut res = [e; MAX_FACTOR * MAX_FACTOR];
+        match self {
+            Filter::None => {}
+            Filter::Scale2x => res[..4].copy_from_slice(&scale2x(p)),
+            Filter::Scale3x => res[..9].copy_from_slice(&scale3x(p)),
+            Filter::Xbrz2x | Filter::Xbrz3x | Filter::Xbrz4x => {
+                unreachable!("xBRZ scales the whole picture")
+            }
+            Filter::LcdGrid => {
+                let gap = e.map(|c| (c as u32 * LCD_GAP_BRIGHTNESS / 256) as u8);
+                // The right column and the bottom row
+                for (i, pixel) in res[..9].iter_mut().enumerate() {
+                    if i % 3 == 2 || i / 3 == 2 {
+                        *pixel = gap;
+                    }
+                }
+            }
+        }
+        res
+    }
+}
+
+// A pixel and its neighbourhood, pixels outside the picture repeat the border
+struct Block<'a> {
+    image: &'a Image,
+    x: usize,
+    y: usize,
+}
+
+impl Block<'_> {
+    fn at(&self, dx: isize, dy: isize) -> [u8; 3] {
+        let x = (self.x as isize + dx).clamp(0, self.image.width as isize - 1);
+        let y = (self.y as isize + dy).clamp(0, self.image.height as isize - 1);
+        self.image.pixel(x as usize, y as usize)
+    }
+}
+
+// A B C
+// D E F
+// G H I
+fn scale2x(p: &Block) -> [[u8; 3]; 4] {
+    let (b, d, e, f, h) = (p.at(0, -1), p.at(-1, 0), p.at(0, 0), p.at(1, 0), p.at(0, 1));
+    if b == h || d == f {
+        return [e; 4];
+    }
+    [
+        if d == b { d } else { e },
+        if b == f { f } else { e },
+        if d == h { d } else { e },
+        if h == f { f } else { e },
+    ]
+}
+
+fn scale3x(p: &Block) -> [[u8; 3]; 9] {
+    let (a, b, c) = (p.at(-1, -1), p.at(0, -1), p.at(1, -1));
+    let (d, e, f) = (p.at(-1, 0), p.at(0, 0), p.at(1, 0));
+    let (g, h, i) = (p.at(-1, 1), p.at(0, 1), p.at(1, 1));
+    if b == h || d == f {
+        return [e; 9];
+    }
+    [
+        if d == b { d } else { e },
+        if (d == b && e != c) || (b == f && e != a) { b } else { e },
+        if b == f { f } else { e },
+        if (d == b && e != g) || (d == h && e != a) { d } else { e },
+        e,
+        if (b == f && e != i) || (h == f && e != c) { f } else { e },
+        if d == h { d } else { e },
+        if (d == h && e != i) || (h == f && e != g) { h } else { e },
+        if h == f { f } else { e },
+    ]
+}
+
+#[cfg(test)]
+mod test {
+    use super::Filter;
+    use crate::image::Image;
+
+    const W: [u8; 3] = [0xFF, 0xFF, 0xFF];
+    const K: [u8; 3] = [0x00, 0x00, 0x00];
+
+    // A black diagonal line from the top left to the bottom right on white
+    fn diagonal() -> Image {
+        let mut image = Image::new(4, 4);
+        for y in 0..4 {
+            for x in 0..4 {
+                image.set_pixel(x, y, if x == y { K } else { W });
+            }
+        }
+        image
+    }
+
+    #[test]
+    fn scale2x() {
+        let res = Filter::Scale2x.apply(&diagonal());
+        assert_eq!((res.width, res.height), (8, 8));
+        // The line stays, and the steps next to it are filled in
+        assert_eq!(res.pixel(2, 2), K);
+        assert_eq!(res.pixel(3, 3), K);
+        assert_eq!(res.pixel(2, 1), K);
+        assert_eq!(res.pixel(4, 3), K);
+        assert_eq!(res.pixel(3, 1), W);
+        assert_eq!(res.pixel(5, 3), W);
+    }
+
+    #[test]
+    fn filters_keep_flat_areas() {
+        let mut image = Image::new(3, 3);
+        image.fill_rect(0, 0, 3, 3, [0x40, 0x80, 0xC0]);
+        for filter in [
+            Filter::None,
+            Filter::Scale2x,
+            Filter::Scale3x,
+            Filter::Xbrz2x,
+            Filter::Xbrz3x,
+            Filter::Xbrz4x,
+        ] {
+            let res = filter.apply(&image);
+            let n = filter.factor();
+            assert_eq!((res.width, res.height), (3 * n, 3 * n));
+            assert!(res.data.chunks(3).all(|c| c == [0x40, 0x80, 0xC0]));
+        }
+    }
+
+    #[test]
+    fn xbrz_blends_edges() {
+        for filter in [Filter::Xbrz2x, Filter::Xbrz3x, Filter::Xbrz4x] {
+            let res = filter.apply(&diagonal());
+            let n = filter.factor();
+            assert_eq!((res.width, res.height), (4 * n, 4 * n));
+            // The steps of the line are smoothed, far from it the picture stays white
+            assert!(res.data.chunks(3).any(|c| c != W && c != K), "{:?}", filter);
+            assert_eq!(res.pixel(4 * n - 1, 0), W);
+        }
+    }
+
+    #[test]
+    fn lcd_grid() {
+        let mut image = Image::new(1, 1);
+        image.set_pixel(0, 0, W);
+        let res = Filter::LcdGrid.apply(&image);
+        assert_eq!(res.pixel(0, 0), W);
+        assert_eq!(res.pixel(1, 1), W);
+        assert_eq!(res.pixel(2, 0), [0x9F; 3]);
+        assert_eq!(res.pixel(0, 2), [0x9F; 3]);
+    }
+}
@@ -3,6 +3,7 @@ pub enum GbMode {
     Classic,
     ColorAsClassic,
     Color,
+    Sgb,
 }
 
 #[derive(PartialEq, Copy, Clone)]
@@ -0,0 +1,418 @@
+use crate::cpu::CPU;
+use crate::mbc;
+use crate::mmu::{MemoryInit, RamPattern};
+use crate::sound::{AudioPlayer, Sound};
+use crate::{Error, Result};
+use std::path::Path;
+use std::time::Duration;
+
+const HEADER_SIZE: usize = 0x70;
+const ROM_BANK_SIZE: usize = 0x4000;
+// MBC5 supports up to 512 ROM banks
+const MAX_ROM_SIZE: usize = 512 * ROM_BANK_SIZE;
+const CLOCKS_PER_SECOND: u64 = 1 << 22;
+
+// Location of the driver which calls INIT and then waits for interrupts
+const DRIVER_ADDRESS: u16 = 0x0200;
+
+/// The header of a GBS file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GbsHeader {
+    pub version: u8,
+    pub song_count: u8,
+    /// The song to play by default, starting at 1
+    pub first_song: u8,
+    /// Address at which the music data is loaded, at least 0x0400
+    pub load_address: u16,
+    /// Called with the song number (starting at 0) in A
+    pub init_address: u16,
+    /// Called once per VBlank or timer interrupt
+    pub play_address: u16,
+    pub stack_pointer: u16,
+    pub timer_modulo: u8,
+    /// Bit 2 selects the timer interrupt instead of VBlank, bit 7 runs the CPU at double speed
+    pub timer_control: u8,
+    pub title: String,
+    pub author: String,
+    pub copyright: String,
+}
+
+impl GbsHeader {
+    pub fn parse(data: &[u8]) -> Result<GbsHeader> {
+        if data.len() < HEADER_SIZE {
+            return Err(Error::InvalidGbs { reason: "file is too small" });
+        }
+        if &data[0..3] != b"GBS" {
+            return Err(Error::InvalidGbs { reason: "missing GBS signature" });
+        }
+        let word = |a: usize| u16::from_le_bytes([data[a], data[a + 1]]);
+        let string = |a: usize| {
+            data[a..a + 32]
+                .iter()
+                .take_while(|&&c| c != 0)
+                .map(|&c| c as char)
+                .collect::<String>()
+        };
+
+        let header = GbsHeader {
+            version: data[0x03],
+            song_count: data[0x04],
+            first_song: data[0x05],
+            load_address: word(0x06),
+            init_address: word(0x08),
+            play_address: word(0x0A),
+            stack_pointer: word(0x0C),
+            timer_modulo: data[0x0E],
+            timer_control: data[0x0F],
+            title: string(0x10),
+            author: string(0x30),
+            copyright: string(0x50),
+        };
+        if header.version != 1 {
+            return Err(Error::InvalidGbs { reason: "unsupported version" });
+        }
+        if header.song_count == 0 {
+            return Err(Error::InvalidGbs { reason: "file contains no songs" });
+        }
+        if header.load_address < 0x0400 || header.load_address >= 0x8000 {
+            return Err(Error::InvalidGbs { reason: "load address is outside of the ROM" });
+        }
+        if header.load_address as usize + data.len() - HEADER_SIZE > MAX_ROM_SIZE {
+            return Err(Error::InvalidGbs { reason: "music data is too large" });
+        }
+        Ok(header)
+    }
+
+    fn uses_timer(&self) -> bool {
+        self.timer_control & 0x04 != 0
+    }
+
+    fn double_speed(&self) -> bool {
+        self.timer_control & 0x80 != 0
+    }
+}
+
+/// Plays the songs of a GBS file on the emulated sound hardware
+pub struct GbsPlayer {
+    cpu: CPU,
+    header: GbsHeader,
+    track: u8,
+    ticks: u64,
+    length: Option<Duration>,
+    fade: Duration,
+}
+
+impl GbsPlayer {
+    pub fn new(path: &Path) -> Result<GbsPlayer> {
+        let data = std::fs::read(path).map_err(|source| Error::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        GbsPlayer::from_bytes(&data).map_err(|e| Error::Rom {
+            path: path.to_path_buf(),
+            source: Box::new(e),
+        })
+    }
+
+    /// Loads a GBS file. Call `enable_audio` and `start_track` before running it.
+    pub fn from_bytes(data: &[u8]) -> Result<GbsPlayer> {
+        let header = GbsHeader::parse(data)?;
+        let cart = mbc::get_mbc(build_rom(&header, &data[HEADER_SIZE..]), true)?;
+        let cpu = if header.double_speed() {
+            CPU::new_cgb(cart)?
+        } else {
+            CPU::new(cart)?
+        };
+        Ok(GbsPlayer {
+            cpu,
+            track: header.first_song.saturating_sub(1).min(header.song_count - 1),
+            header,
+            ticks: 0,
+            length: None,
+            fade: Duration::ZERO,
+        })
+    }
+
+    pub fn header(&self) -> &GbsHeader {
+        &self.header
+    }
+
+    pub fn enable_audio(&mut self, player: Box<dyn AudioPlayer>) {
+        self.cpu.mmu.sound = Some(if self.header.double_speed() {
+            Sound::new_cgb(player)
+        } else {
+            Sound::new_dmg(player)
+        });
+    }
+
+    /// Starts playing a song from the beginning. Songs are numbered from 0.
+    pub fn start_track(&mut self, track: u8) -> Result<()> {
+        if track >= self.header.song_count {
+            return Err(Error::InvalidGbs { reason: "song number out of range" });
+        }
+        self.cpu.power_cycle(MemoryInit {
+            wram: RamPattern::Zero,
+            vram: RamPattern::Zero,
+        });
+        let mmu = &mut self.cpu.mmu;
+        // The cartridge RAM survives a power cycle, clear what the previous song left behind
+        mmu.wb(0x0000, 0x0A);
+        for a in 0xA000..0xC000 {
+            mmu.wb(a, 0);
+        }
+        mmu.wb(0xFF26, 0x80);
+        mmu.wb(0xFF25, 0xFF);
+        mmu.wb(0xFF24, 0x77);
+        mmu.wb(0xFF05, self.header.timer_modulo);
+        mmu.wb(0xFF06, self.header.timer_modulo);
+        mmu.wb(0xFF07, self.header.timer_control & 0x07);
+        mmu.wb(0xFF0F, 0);
+        mmu.wb(0xFFFF, if self.header.uses_timer() { 0x04 } else { 0x01 });
+
+        self.cpu.reg.pc = DRIVER_ADDRESS;
+        self.cpu.reg.sp = self.header.stack_pointer;
+        self.cpu.reg.a = track;
+        self.track = track;
+        self.ticks = 0;
+        self.update_volume();
+        Ok(())
+    }
+
+    pub fn track(&self) -> u8 {
+        self.track
+    }
+
+    /// Sets how long each song plays before it fades out. `None` plays forever.
+    pub fn set_length(&mut self, length: Option<Duration>) {
+        self.length = length;
+    }
+
+    /// Sets the duration of the fade out at the end of a song
+    pub fn set_fade(&mut self, fade: Duration) {
+        self.fade = fade;
+    }
+
+    /// Runs a single instruction and returns the number of clock ticks it took
+    pub fn do_cycle(&mut self) -> u32 {
+        let ticks = self.cpu.do_cycle();
+        self.ticks += ticks as u64;
+        self.update_volume();
+        ticks
+    }
+
+    /// Time played since the song was started
+    pub fn elapsed(&self) -> Duration {
+        // Split up, the nanoseconds of all ticks overflow after 73 minutes
+        let nanos = (self.ticks % CLOCKS_PER_SECOND) * 1_000_000_000 / CLOCKS_PER_SECOND;
+        Duration::from_secs(self.ticks / CLOCKS_PER_SECOND) + Duration::from_nanos(nanos)
+    }
+
+    /// True when the song has played for its length and has faded out completely
+    pub fn is_finished(&self) -> bool {
+        self.length
+            .is_some_and(|length| self.elapsed() >= length + self.fade)
+    }
+
+    /// Volume of the song while fading out, 1.0 before the fade starts
+    pub fn volume(&self) -> f32 {
+        self.cpu.mmu.sound.as_ref().map_or(1.0, |sound| sound.master_volume())
+    }
+
+    /// Outputs the audio generated so far
+    pub fn flush_audio(&mut self) {
+        if let Some(sound) = self.cpu.mmu.sound.as_mut() {
+            sound.flush();
+        }
+    }
+
+    /// Records the audio output to a WAV file, see `Device::start_audio_recording`
+    pub fn start_audio_recording(&mut self, path: &Path) -> Result<()> {
+        match self.cpu.mmu.sound.as_mut() {
+            Some(sound) => sound.start_recording(path),
+            None => Err(Error::AudioNotEnabled),
+        }
+    }
+
+    pub fn stop_audio_recording(&mut self) -> Result<()> {
+        match self.cpu.mmu.sound.as_mut() {
+            Some(sound) => sound.stop_recording(),
+            None => Ok(()),
+        }
+    }
+
+    fn update_volume(&mut self) {
+        let volume = match self.length {
+            Some(length) if self.elapsed() >= length => {
+                let faded = (self.elapsed() - length).as_secs_f32();
+                1.0 - (faded / self.fade.as_secs_f32()).min(1.0)
+            }
+            _ => 1.0,
+        };
+        if let Some(sound) = self.cpu.mmu.sound.as_mut() {
+            sound.set_master_volume(volume);
+        }
+    }
+}
+
+// Builds an MBC5 cartridge with the music data at the load address. The area below it holds
+// the interrupt handlers and a driver that calls INIT and then sleeps until PLAY is due.
+fn build_rom(header: &GbsHeader, data: &[u8]) -> Vec<u8> {
+    let load = header.load_address as usize;
+    let size = (load + data.len()).div_ceil(ROM_BANK_SIZE).next_power_of_two().max(2) * ROM_BANK_SIZE;
+    let mut rom = vec![0xFF; size];
+    rom[load..load + data.len()].copy_from_slice(data);
+
+    let [init_lo, init_hi] = header.init_address.to_le_bytes();
+    let [play_lo, play_hi] = header.play_address.to_le_bytes();
+
+    // RST vectors jump to the same offset relative to the load address
+    for rst in (0x00..0x40).step_by(8) {
+        let [lo, hi] = (header.load_address + rst as u16).to_le_bytes();
+        rom[rst..rst + 3].copy_from_slice(&[0xC3, lo, hi]);
+    }
+    // VBlank, LCD STAT, timer, serial and joypad interrupts
+    for vector in [0x40, 0x48, 0x50, 0x58, 0x60] {
+        rom[vector] = 0xD9;
+    }
+    let play_vector = if header.uses_timer() { 0x50 } else { 0x40 };
+    rom[play_vector..play_vector + 4].copy_from_slice(&[0xCD, play_lo, play_hi, 0xD9]);
+
+    let mut driver = vec![0xF3]; // DI
+    if header.double_speed() {
+        // PUSH AF; LD A,1; LDH (KEY1),A; STOP; POP AF
+        driver.extend_from_slice(&[0xF5, 0x3E, 0x01, 0xE0, 0x4D, 0x10, 0x00, 0xF1]);
+    }
+    // CALL init; EI; HALT; JR -3
+    driver.extend_from_slice(&[0xCD, init_lo, init_hi, 0xFB, 0x76, 0x18, 0xFD]);
+    let start = DRIVER_ADDRESS as usize;
+    rom[start..start + driver.len()].copy_from_slice(&driver);
+
+    rom[0x0100..0x0150].fill(0);
+    for (i, c) in header.title.bytes().take(11).enumerate() {
+        rom[0x0134 + i] = c;
+    }
+    rom[0x0143] = if header.double_speed() { 0x80 } else { 0x00 };
+    rom[0x0147] = 0x1A; // MBC5+RAM
+    rom[0x0148] = (size / (2 * ROM_BANK_SIZE)).trailing_zeros() as u8;
+    rom[0x0149] = 0x02; // 8 KB
+    rom
+}
+
+#[cfg(test)]
+mod test {
+    use super::{GbsHeader, GbsPlayer, CLOCKS_PER_SECOND};
+    use crate::AudioPlayer;
+    use std::time::Duration;
+
+    struct NullPlayer;
+
+    impl AudioPlayer for NullPlayer {
+        fn play(&mut self, _left: &[f32], _right: &[f32]) {}
+
+        fn samples_rate(&self) -> u32 {
+            44100
+        }
+
+        fn underflowed(&self) -> bool {
+            false
+        }
+    }
+
+    // Builds a GBS file whose INIT stores the song number at C000 and whose PLAY counts the
+    // calls at C001
+    fn test_gbs(timer_modulo: u8, timer_control: u8) -> Vec<u8> {
+        let mut data = vec![0; 0x70];
+        data[0..4].copy_from_slice(b"GBS\x01");
+        data[0x04] = 3;
+        data[0x05] = 2;
+        data[0x06..0x08].copy_from_slice(&0x0400u16.to_le_bytes());
+        data[0x08..0x0A].copy_from_slice(&0x0400u16.to_le_bytes());
+        data[0x0A..0x0C].copy_from_slice(&0x0404u16.to_le_bytes());
+        data[0x0C..0x0E].copy_from_slice(&0xDFFFu16.to_le_bytes());
+        data[0x0E] = timer_modulo;
+        data[0x0F] = timer_control;
+        data[0x10..0x14].copy_from_slice(b"Test");
+        // INIT: LD (C000),A; RET
+        data.extend_from_slice(&[0xEA, 0x00, 0xC0, 0xC9]);
+        // PLAY: LD HL,C001; INC (HL); RET
+        data.extend_from_slice(&[0x21, 0x01, 0xC0, 0x34, 0xC9]);
+        data
+    }
+
+    fn run_for(player: &mut GbsPlayer, duration: Duration) {
+        while player.elapsed() < duration {
+            player.do_cycle();
+        }
+    }
+
+    #[test]
+    fn header() {
+        let header = GbsHeader::parse(&test_gbs(0, 0)).unwrap();
+        assert_eq!(header.song_count, 3);
+        assert_eq!(header.first_song, 2);
+        assert_eq!(header.play_address, 0x0404);
+        assert_eq!(header.stack_pointer, 0xDFFF);
+        assert_eq!(header.title, "Test");
+        assert_eq!(header.author, "");
+
+        let mut data = test_gbs(0, 0);
+        data[0x07] = 0;
+        assert!(GbsHeader::parse(&data).is_err());
+        assert!(GbsHeader::parse(&data[..0x40]).is_err());
+    }
+
+    #[test]
+    fn vblank_play() {
+        let mut player = GbsPlayer::from_bytes(&test_gbs(0, 0)).unwrap();
+        assert_eq!(player.track(), 1);
+        player.start_track(2).unwrap();
+        run_for(&mut player, Duration::from_secs(1));
+        assert_eq!(player.cpu.mmu.rb(0xC000), 2);
+        // PLAY is called once per frame
+        let calls = player.cpu.mmu.rb(0xC001);
+        assert!((59..=60).contains(&calls), "{} calls", calls);
+        assert!(player.start_track(3).is_err());
+    }
+
+    #[test]
+    fn timer_play() {
+        // 4096 Hz / (256 - 0xC0) = 64 Hz
+        let mut player = GbsPlayer::from_bytes(&test_gbs(0xC0, 0x04)).unwrap();
+        player.start_track(0).unwrap();
+        run_for(&mut player, Duration::from_secs(1));
+        let calls = player.cpu.mmu.rb(0xC001);
+        assert!((63..=64).contains(&calls), "{} calls", calls);
+
+        // At double speed the timer runs twice as fast
+        let mut player = GbsPlayer::from_bytes(&test_gbs(0xC0, 0x84)).unwrap();
+        player.start_track(0).unwrap();
+        run_for(&mut player, Duration::from_secs(1));
+        let calls = player.cpu.mmu.rb(0xC001);
+        assert!((126..=128).contains(&calls), "{} calls", calls);
+    }
+
+    #[test]
+    fn length_and_fade() {
+        let mut player = GbsPlayer::from_bytes(&test_gbs(0, 0)).unwrap();
+        player.set_length(Some(Duration::from_millis(100)));
+        player.set_fade(Duration::from_millis(100));
+        player.enable_audio(Box::new(NullPlayer));
+        player.start_track(0).unwrap();
+        run_for(&mut player, Duration::from_millis(150));
+        assert!(!player.is_finished());
+        let volume = player.volume();
+        assert!((volume - 0.5).abs() < 0.01, "volume {}", volume);
+        run_for(&mut player, Duration::from_millis(200));
+        assert!(player.is_finished());
+    }
+
+    #[test]
+    fn long_elapsed() {
+        let mut player = GbsPlayer::from_bytes(&test_gbs(0, 0)).unwrap();
+        // Ten hours and a quarter second
+        player.ticks = 10 * 3600 * CLOCKS_PER_SECOND + CLOCKS_PER_SECOND / 4;
+        assert_eq!(player.elapsed(), Duration::from_millis(10 * 3600 * 1000 + 250));
+        player.set_length(Some(Duration::from_secs(10 * 3600)));
+        assert!(player.is_finished());
+    }
+}
@@ -1,8 +1,17 @@
+use crate::blend::{FrameBlender, FrameBlending};
+use crate::color::{self, ColorCorrection, ColorTable};
 use crate::gbmode::GbMode;
+use crate::image::Image;
+use crate::mmu::RamPattern;
+use crate::palette::{DmgColors, DmgPalette};
+use crate::pixel::PixelFormat;
+use crate::sgb::{Sgb, SGB_SCREEN_H, SGB_SCREEN_W};
+use crate::timeline::{self, FrameTimeline, TimelineRecorder};
 use std::cmp::Ordering;
 
 const VRAM_SIZE: usize = 0x4000;
 const VOAM_SIZE: usize = 0xA0;
+
 pub const SCREEN_W: usize = 160;
 pub const SCREEN_H: usize = 144;
 
@@ -14,6 +23,14 @@ enum PrioType { //handle sprite vs enum prioriy
     Normal,
 }
 
+/// A layer of the picture, for `GPU::set_layer_enabled`
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum Layer {
+    Background,
+    Window,
+    Sprites,
+}
+
 #[derive(Clone, PartialEq)]
 pub struct GPU {
     mode: u8,                        // Current LCD mode (0-3)
@@ -41,9 +58,12 @@ pub struct GPU {
     palbr: u8,                       // Background palette register (DMG)
     pal0r: u8,                       // Sprite palette 0 register (DMG)
     pal1r: u8,                       // Sprite palette 1 register (DMG)
-    palb: [u8; 4],                   // Background palette colors (DMG)
-    pal0: [u8; 4],                   // Sprite palette 0 colors (DMG)
-    pal1: [u8; 4],                   // Sprite palette 1 colors (DMG)
+    palb: [u8; 4],                   // Background palette shades (DMG)
+    pal0: [u8; 4],                   // Sprite palette 0 shades (DMG)
+    pal1: [u8; 4],                   // Sprite palette 1 shades (DMG)
+    dmg_palette: DmgPalette,         // Colours of the shades (DMG)
+    dmg_colors: DmgColors,           // RGB of the BG, OBJ0 and OBJ1 shades (DMG)
+    color_table: ColorTable,         // RGB of the CGB colours
     vram: [u8; VRAM_SIZE],           // Video RAM (VRAM)
     voam: [u8; VOAM_SIZE],           // Sprite attribute table (OAM)
     cbgpal_inc: bool,                // CGB BG palette auto-increment flag
@@ -54,16 +74,28 @@ pub struct GPU {
     csprit: [[[u8; 3]; 4]; 8],       // CGB sprite palettes (8 palettes, 4 colors, RGB)
     vrambank: usize,                 // Current VRAM bank (CGB)
     pub data: Vec<u8>,               // Framebuffer (RGB pixel data)
+    indices: Vec<u8>,                // Framebuffer (palette and shade of each pixel)
+    cgb_colors: Vec<u16>,            // Framebuffer (uncorrected BGR555 colours, CGB)
+    pixel_format: PixelFormat,       // Format of the frames for the host
+    blender: FrameBlender,           // Blends finished frames to emulate the slow screen
+    show_bg: bool,                   // Draw the background (host setting, not LCDC)
+    show_window: bool,               // Draw the window (host setting, not LCDC)
+    show_sprites: bool,              // Draw the sprites (host setting, not LCDC)
+    output: Vec<u8>,                 // Last frame converted to pixel_format
+    sgb_output: Vec<u8>,             // Last SGB picture converted to pixel_format
+    timeline: Option<Box<TimelineRecorder>>, // Register writes and interrupts per frame
     bgprio: [PrioType; SCREEN_W],    // Per-pixel background priority for current scanline
     pub updated: bool,               // Framebuffer updated flag
     pub interrupt: u8,               // Interrupt request flags
     pub gbmode: GbMode,              // Game Boy mode (DMG or CGB)
     hblanking: bool,                 // HBlank active flag
     first_frame: bool,               // True if first frame after LCD enabled
+    pub sgb: Option<Box<Sgb>>,       // Super Game Boy state (SGB)
 }
 
 impl GPU {
     pub fn new() -> GPU {
+        let color_table = ColorTable::new(ColorCorrection::Gambatte);
         GPU {
             mode: 0,
             modeclock: 0,
@@ -93,9 +125,22 @@ impl GPU {
             palb: [0; 4],
             pal0: [0; 4],
             pal1: [0; 4],
+            dmg_palette: DmgPalette::Grey,
+            dmg_colors: DmgPalette::Grey.colors(|color| color_table.get(color)),
+            color_table,
             vram: [0; VRAM_SIZE],
             voam: [0; VOAM_SIZE],
             data: vec![0; SCREEN_W * SCREEN_H * 3],
+            indices: vec![0; SCREEN_W * SCREEN_H],
+            cgb_colors: vec![0; SCREEN_W * SCREEN_H],
+            pixel_format: PixelFormat::Rgb888,
+            blender: FrameBlender::new(FrameBlending::Off),
+            show_bg: true,
+            show_window: true,
+            show_sprites: true,
+            output: Vec::new(),
+            sgb_output: Vec::new(),
+            timeline: None,
             bgprio: [PrioType::Normal; SCREEN_W],
             updated: false,
             interrupt: 0,
@@ -109,6 +154,7 @@ impl GPU {
             vrambank: 0,
             hblanking: false,
             first_frame: false,
+            sgb: None,
         }
     }
 
@@ -116,6 +162,179 @@ impl GPU {
         GPU::new()
     }
 
+    pub fn new_sgb() -> GPU {
+        let mut res = GPU::new();
+        res.gbmode = GbMode::Sgb;
+        res.sgb = Some(Box::new(Sgb::new()));
+        res
+    }
+
+    pub fn reset(&mut self) {
+        let mut gpu = GPU::new();
+        gpu.gbmode = self.gbmode;
+        gpu.vram = self.vram;
+        gpu.voam = self.voam;
+        gpu.dmg_palette = self.dmg_palette;
+        gpu.dmg_colors = self.dmg_colors;
+        std::mem::swap(&mut gpu.color_table, &mut self.color_table);
+        gpu.pixel_format = self.pixel_format;
+        gpu.blender = FrameBlender::new(self.blender.mode());
+        gpu.show_bg = self.show_bg;
+        gpu.show_window = self.show_window;
+        gpu.show_sprites = self.show_sprites;
+        gpu.timeline = self.timeline.take();
+        // The SNES side of the Super Game Boy is not affected by a reset
+        gpu.sgb = self.sgb.take();
+        *self = gpu;
+        self.convert_frame();
+    }
+
+    /// Reinitializes the memory. The registers are expected to be reset already, as
+    /// `MMU::reset` writes their initial values afterwards.
+    pub fn power_cycle(&mut self, vram: RamPattern) {
+        vram.apply(&mut self.vram);
+        self.voam = [0; VOAM_SIZE];
+        if let Some(sgb) = self.sgb.as_mut() {
+            **sgb = Sgb::new();
+        }
+    }
+
+    /// Selects the colours of classic games. `CgbAuto` must be resolved by the caller.
+    pub fn set_dmg_palette(&mut self, palette: DmgPalette) {
+        self.dmg_palette = palette;
+        self.dmg_colors = palette.colors(|color| self.color_table.get(color));
+    }
+
+    pub fn set_color_correction(&mut self, correction: ColorCorrection) {
+        if correction != self.color_table.correction() {
+            self.color_table = ColorTable::new(correction);
+            self.set_dmg_palette(self.dmg_palette);
+        }
+    }
+
+    pub fn set_pixel_format(&mut self, format: PixelFormat) {
+        self.pixel_format = format;
+        self.convert_frame();
+    }
+
+    pub fn frame_blending(&self) -> FrameBlending {
+        self.blender.mode()
+    }
+
+    pub fn set_frame_blending(&mut self, mode: FrameBlending) {
+        if mode != self.blender.mode() {
+            self.blender = FrameBlender::new(mode);
+        }
+    }
+
+    /// Hides or shows a layer. Hidden layers are not drawn, but they still affect the
+    /// emulation, e.g. the window line counter keeps running.
+    pub fn set_layer_enabled(&mut self, layer: Layer, enabled: bool) {
+        match layer {
+            Layer::Background => self.show_bg = enabled,
+            Layer::Window => self.show_window = enabled,
+            Layer::Sprites => self.show_sprites = enabled,
+        }
+    }
+
+    pub fn layer_enabled(&self, layer: Layer) -> bool {
+        match layer {
+            Layer::Background => self.show_bg,
+            Layer::Window => self.show_window,
+            Layer::Sprites => self.show_sprites,
+        }
+    }
+
+    /// Starts or stops recording the PPU register writes and interrupts of each frame
+    pub fn set_timeline_enabled(&mut self, enabled: bool) {
+        if enabled != self.timeline.is_some() {
+            self.timeline = enabled.then(|| Box::new(TimelineRecorder::new()));
+        }
+    }
+
+    /// The timeline of the last finished frame, if recording is enabled
+    pub fn timeline(&self) -> Option<&FrameTimeline> {
+        self.timeline.as_ref().map(|timeline| timeline.last())
+    }
+
+    /// The timeline of the last finished frame drawn over the PPU modes, with the frame in
+    /// the dots of mode 3
+    pub fn render_timeline(&self) -> Option<Image> {
+        let screen = Image {
+            width: SCREEN_W,
+            height: SCREEN_H,
+            data: self.data.clone(),
+        };
+        Some(self.timeline()?.render(&screen))
+    }
+
+    /// Adds the interrupts raised by any part of the system to the timeline, at the current
+    /// position of the LCD
+    pub(crate) fn record_interrupts(&mut self, flags: u8) {
+        if let Some(timeline) = self.timeline.as_mut() {
+            timeline.interrupts(self.line, self.modeclock, flags);
+        }
+    }
+
+    /// The current frame, or the SGB picture with its border. `raw_colors` shows the colours
+    /// of CGB games without colour correction and frame blending.
+    pub fn screenshot(&self, raw_colors: bool) -> Image {
+        if let Some(sgb) = self.sgb.as_ref() {
+            return Image {
+                width: SGB_SCREEN_W,
+                height: SGB_SCREEN_H,
+                data: sgb.output.clone(),
+            };
+        }
+        let mut image = Image {
+            width: SCREEN_W,
+            height: SCREEN_H,
+            data: self.data.clone(),
+        };
+        if raw_colors && self.gbmode == GbMode::Color {
+            for (i, &color) in self.cgb_colors.iter().enumerate() {
+                image.set_pixel(i % SCREEN_W, i / SCREEN_W, color::raw_color(color));
+            }
+        }
+        image
+    }
+
+    /// The current frame in the pixel format of the host
+    pub fn frame(&self) -> &[u8] {
+        match self.pixel_format {
+            PixelFormat::Rgb888 => &self.data,
+            PixelFormat::Indexed => &self.indices,
+            _ => &self.output,
+        }
+    }
+
+    /// The current SGB picture including the border in the pixel format of the host
+    pub fn sgb_frame(&self) -> Option<&[u8]> {
+        let sgb = self.sgb.as_ref()?;
+        Some(match self.pixel_format {
+            PixelFormat::Rgb888 | PixelFormat::Indexed => &sgb.output,
+            _ => &self.sgb_output,
+        })
+    }
+
+    // Converts the finished frame, the framebuffers are already in the formats that need no
+    // conversion
+    fn convert_frame(&mut self) {
+        if let PixelFormat::Rgb888 | PixelFormat::Indexed = self.pixel_format {
+            return;
+        }
+        self.pixel_format.convert(&self.data, &mut self.output);
+        if let Some(sgb) = self.sgb.as_ref() {
+            self.pixel_format.convert(&sgb.output, &mut self.sgb_output);
+        }
+    }
+
+    pub fn sgb_command(&mut self, data: &[u8]) {
+        if let Some(sgb) = self.sgb.as_mut() {
+            sgb.command(data);
+        }
+    }
+
     pub fn do_cycle(&mut self, ticks: u32) {
         if !self.lcd_on {
             return;
@@ -133,6 +352,11 @@ impl GPU {
             if self.modeclock >= 456 {
                 self.modeclock -= 456;
                 self.line = (self.line + 1) % 154;
+                if self.line == 0 {
+                    if let Some(timeline) = self.timeline.as_mut() {
+                        timeline.finish_frame();
+                    }
+                }
                 self.check_interrupt_lyc();
 
                 // This is a VBlank line
@@ -183,6 +407,9 @@ impl GPU {
                 self.interrupt |= 0x01;
                 self.updated = true;
                 self.first_frame = false;
+                self.blender.apply(&mut self.data);
+                self.sgb_vblank();
+                self.convert_frame();
                 self.m1_inte
             }
             2 => self.m2_inte,
@@ -262,19 +489,18 @@ impl GPU {
     }
 
     fn rbvram0(&self, a: u16) -> u8 {
-        if a < 0x8000 || a >= 0xA000 {
-            panic!("Shouldn't have used rbvram0");
-        }
         self.vram[a as usize & 0x1FFF]
     }
     fn rbvram1(&self, a: u16) -> u8 {
-        if a < 0x8000 || a >= 0xA000 {
-            panic!("Shouldn't have used rbvram1");
-        }
         self.vram[0x2000 + (a as usize & 0x1FFF)]
     }
 
     pub fn wb(&mut self, a: u16, v: u8) {
+        if let Some(timeline) = self.timeline.as_mut() {
+            if timeline::is_timeline_register(a) {
+                timeline.write(self.line, self.modeclock, a, v);
+            }
+        }
         match a {
             0x8000..=0x9FFF => self.vram[(self.vrambank * 0x2000) | (a as usize & 0x1FFF)] = v,
             0xFE00..=0xFE9F => self.voam[a as usize - 0xFE00] = v,
@@ -295,6 +521,9 @@ impl GPU {
                     self.wy_trigger = false;
                     self.first_frame = true;
                     self.clear_screen();
+                    if let Some(timeline) = self.timeline.as_mut() {
+                        timeline.finish_frame();
+                    }
                 }
                 if !orig_lcd_on && self.lcd_on {
                     self.change_mode(2);
@@ -314,7 +543,7 @@ impl GPU {
                 self.lyc = v;
                 self.check_interrupt_lyc();
             }
-            0xFF46 => panic!("0xFF46 should be handled by MMU"),
+            0xFF46 => {} // Handled by the MMU
             0xFF47 => {
                 self.palbr = v;
                 self.update_pal();
@@ -373,7 +602,7 @@ impl GPU {
                     self.csprit_ind = (self.csprit_ind + 1) & 0x3F;
                 };
             }
-            _ => panic!("GPU does not handle write {:04X}", a),
+            _ => {}
         }
     }
 
@@ -381,7 +610,9 @@ impl GPU {
         for v in self.data.iter_mut() {
             *v = 255;
         }
+        self.indices.fill(0);
         self.updated = true;
+        self.convert_frame();
     }
 
     fn update_pal(&mut self) {
@@ -393,12 +624,42 @@ impl GPU {
     }
 
     fn get_monochrome_pal_val(value: u8, index: usize) -> u8 {
-        match (value >> 2 * index) & 0x03 {
-            0 => 255,
-            1 => 192,
-            2 => 96,
-            _ => 0,
+        (value >> (2 * index)) & 0x03
+    }
+
+    fn sgb_vblank(&mut self) {
+        let transfer = match self.sgb.as_mut() {
+            Some(sgb) => sgb.take_pending_transfer(),
+            None => return,
+        };
+        if let Some(kind) = transfer {
+            let data = self.sgb_transfer_data();
+            if let Some(sgb) = self.sgb.as_mut() {
+                sgb.transfer(kind, &data);
+            }
+        }
+        if let Some(sgb) = self.sgb.as_mut() {
+            sgb.render(&self.data, &self.color_table);
+        }
+    }
+
+    /// Collects the 4KB of tile data displayed by the background map, as the SGB reads it
+    fn sgb_transfer_data(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(0x1000);
+        for i in 0..256u16 {
+            let mapaddress = self.bg_tilemap + (i / 20) * 32 + i % 20;
+            let tilenr = self.rbvram0(mapaddress);
+            let tileaddress = self.tilebase
+                + (if self.tilebase == 0x8000 {
+                    tilenr as u16
+                } else {
+                    (tilenr as i8 as i16 + 128) as u16
+                }) * 16;
+            for offset in 0..16 {
+                data.push(self.rbvram0(tileaddress + offset));
+            }
         }
+        data
     }
 
     fn renderscan(&mut self) {
@@ -407,37 +668,47 @@ impl GPU {
             return;
         }
 
+        // Sprites are not hidden behind a hidden background
+        let prio = if self.show_bg { PrioType::Normal } else { PrioType::Color0 };
         for x in 0..SCREEN_W {
-            self.setcolor(x, 255);
-            self.bgprio[x] = PrioType::Normal;
+            self.setcolor(x, 0, 0);
+            self.bgprio[x] = prio;
         }
         self.draw_bg();
         self.draw_sprites();
     }
 
-    fn setcolor(&mut self, x: usize, color: u8) {
-        self.data[self.line as usize * SCREEN_W * 3 + x * 3 + 0] = color;
-        self.data[self.line as usize * SCREEN_W * 3 + x * 3 + 1] = color;
-        self.data[self.line as usize * SCREEN_W * 3 + x * 3 + 2] = color;
+    // `palette` is 0 for the background, 1 and 2 for the sprite palettes
+    fn setcolor(&mut self, x: usize, palette: usize, shade: u8) {
+        let [r, g, b] = match self.sgb {
+            Some(ref sgb) => sgb.color(x, self.line as usize, shade, &self.color_table),
+            None => self.dmg_colors[palette][shade as usize],
+        };
+        self.data[self.line as usize * SCREEN_W * 3 + x * 3 + 0] = r;
+        self.data[self.line as usize * SCREEN_W * 3 + x * 3 + 1] = g;
+        self.data[self.line as usize * SCREEN_W * 3 + x * 3 + 2] = b;
+        let index = match palette {
+            0 => shade,
+            _ => shade | (palette as u8 - 1) << 2 | 0x20,
+        };
+        self.indices[self.line as usize * SCREEN_W + x] = index;
     }
 
-    fn setrgb(&mut self, x: usize, r: u8, g: u8, b: u8) {
-        // Gameboy Color RGB correction
-        // Taken from the Gambatte emulator
-        // assume r, g and b are between 0 and 1F
+    // `index` is the colour number, palette number and sprite flag as in `PixelFormat::Indexed`
+    fn setrgb(&mut self, x: usize, r: u8, g: u8, b: u8, index: u8) {
+        self.indices[self.line as usize * SCREEN_W + x] = index;
         let baseidx = self.line as usize * SCREEN_W * 3 + x * 3;
+        let color = r as u16 | (g as u16) << 5 | (b as u16) << 10;
+        self.cgb_colors[self.line as usize * SCREEN_W + x] = color;
+        let [r, g, b] = self.color_table.get(color);
 
-        let r = r as u32;
-        let g = g as u32;
-        let b = b as u32;
-
-        self.data[baseidx + 0] = ((r * 13 + g * 2 + b) >> 1) as u8;
-        self.data[baseidx + 1] = ((g * 3 + b) << 1) as u8;
-        self.data[baseidx + 2] = ((r * 3 + g * 2 + b * 11) >> 1) as u8;
+        self.data[baseidx + 0] = r;
+        self.data[baseidx + 1] = g;
+        self.data[baseidx + 2] = b;
     }
 
     fn draw_bg(&mut self) {
-        let drawbg = self.gbmode == GbMode::Color || self.lcdc0;
+        let drawbg = (self.gbmode == GbMode::Color || self.lcdc0) && self.show_bg;
 
         let wx_trigger = self.winx <= 166;
         let winy = if self.win_on && self.wy_trigger && wx_trigger {
@@ -446,6 +717,7 @@ impl GPU {
         } else {
             -1
         };
+        let winy = if self.show_window { winy } else { -1 };
 
         if winy < 0 && drawbg == false {
             return;
@@ -530,16 +802,16 @@ impl GPU {
                 let r = self.cbgpal[palnr][colnr][0];
                 let g = self.cbgpal[palnr][colnr][1];
                 let b = self.cbgpal[palnr][colnr][2];
-                self.setrgb(x as usize, r, g, b);
+                self.setrgb(x as usize, r, g, b, (palnr << 2 | colnr) as u8);
             } else {
                 let color = self.palb[colnr];
-                self.setcolor(x, color);
+                self.setcolor(x, 0, color);
             }
         }
     }
 
     fn draw_sprites(&mut self) {
-        if !self.sprite_on {
+        if !self.sprite_on || !self.show_sprites {
             return;
         }
 
@@ -619,17 +891,18 @@ impl GPU {
                     let r = self.csprit[c_palnr][colnr][0];
                     let g = self.csprit[c_palnr][colnr][1];
                     let b = self.csprit[c_palnr][colnr][2];
-                    self.setrgb((spritex + x) as usize, r, g, b);
+                    let index = (c_palnr << 2 | colnr | 0x20) as u8;
+                    self.setrgb((spritex + x) as usize, r, g, b, index);
                 } else {
                     if belowbg && self.bgprio[(spritex + x) as usize] != PrioType::Color0 {
                         continue 'xloop;
                     }
-                    let color = if usepal1 {
-                        self.pal1[colnr]
+                    let (palette, color) = if usepal1 {
+                        (2, self.pal1[colnr])
                     } else {
-                        self.pal0[colnr]
+                        (1, self.pal0[colnr])
                     };
-                    self.setcolor((spritex + x) as usize, color);
+                    self.setcolor((spritex + x) as usize, palette, color);
                 }
             }
         }
@@ -640,6 +913,214 @@ impl GPU {
     }
 }
 
+/// One of the two tile maps in VRAM
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TileMap {
+    Map9800,
+    Map9C00,
+}
+
+/// An entry of the sprite attribute table
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct OamEntry {
+    /// Y position on the screen plus 16
+    pub y: u8,
+    /// X position on the screen plus 8
+    pub x: u8,
+    pub tile: u8,
+    pub attributes: u8,
+}
+
+impl OamEntry {
+    pub fn behind_bg(&self) -> bool {
+        self.attributes & 0x80 != 0
+    }
+
+    pub fn y_flip(&self) -> bool {
+        self.attributes & 0x40 != 0
+    }
+
+    pub fn x_flip(&self) -> bool {
+        self.attributes & 0x20 != 0
+    }
+
+    /// OBP0 or OBP1 (DMG)
+    pub fn dmg_palette(&self) -> u8 {
+        (self.attributes >> 4) & 0x01
+    }
+
+    /// VRAM bank of the tile (CGB)
+    pub fn vram_bank(&self) -> u8 {
+        (self.attributes >> 3) & 0x01
+    }
+
+    /// OBJ palette 0-7 (CGB)
+    pub fn cgb_palette(&self) -> u8 {
+        self.attributes & 0x07
+    }
+}
+
+const VIEWPORT_COLOR: [u8; 3] = [0xFF, 0x00, 0x00];
+// Shown where sprites are transparent and around the palettes
+const DEBUG_BACKDROP: [u8; 3] = [0x40, 0x40, 0x48];
+
+// Debug views of the graphics state, these do not affect the emulation
+impl GPU {
+    /// The 384 tiles of both VRAM banks with BG palette 0, 16 tiles per row. Bank 0 is on the
+    /// left, bank 1 on the right.
+    pub fn render_tiles(&self) -> Image {
+        let mut image = Image::new(256, 192);
+        for bank in 0..2 {
+            for tile in 0..384 {
+                let (tx, ty) = (bank * 128 + (tile % 16) * 8, (tile / 16) * 8);
+                for y in 0..8 {
+                    for x in 0..8 {
+                        let colnr = self.tile_pixel(bank, tile, x, y);
+                        image.set_pixel(tx + x, ty + y, self.bg_rgb(0, colnr));
+                    }
+                }
+            }
+        }
+        image
+    }
+
+    /// The 256x256 pixels of a tile map, with the tile data and attributes the background
+    /// would use. The part of the background on the screen is outlined.
+    pub fn render_tile_map(&self, map: TileMap) -> Image {
+        let base = match map {
+            TileMap::Map9800 => 0x9800,
+            TileMap::Map9C00 => 0x9C00,
+        };
+        let mut image = Image::new(256, 256);
+        for i in 0..32 * 32 {
+            let tilenr = self.rbvram0(base + i as u16);
+            let tile = match self.tilebase {
+                0x8000 => tilenr as usize,
+                _ => (tilenr as i8 as i16 + 256) as usize,
+            };
+            let (palnr, bank, xflip, yflip) = if self.gbmode == GbMode::Color {
+                let flags = self.rbvram1(base + i as u16) as usize;
+                (flags & 0x07, (flags >> 3) & 1, flags & 0x20 != 0, flags & 0x40 != 0)
+            } else {
+                (0, 0, false, false)
+            };
+            for y in 0..8 {
+                for x in 0..8 {
+                    let tx = if xflip { 7 - x } else { x };
+                    let ty = if yflip { 7 - y } else { y };
+                    let colnr = self.tile_pixel(bank, tile, tx, ty);
+                    let color = self.bg_rgb(palnr, colnr);
+                    image.set_pixel((i % 32) * 8 + x, (i / 32) * 8 + y, color);
+                }
+            }
+        }
+
+        if self.bg_tilemap == base {
+            let (left, top) = (self.scx as usize, self.scy as usize);
+            for x in 0..SCREEN_W {
+                image.set_pixel((left + x) % 256, top, VIEWPORT_COLOR);
+                image.set_pixel((left + x) % 256, (top + SCREEN_H - 1) % 256, VIEWPORT_COLOR);
+            }
+            for y in 0..SCREEN_H {
+                image.set_pixel(left, (top + y) % 256, VIEWPORT_COLOR);
+                image.set_pixel((left + SCREEN_W - 1) % 256, (top + y) % 256, VIEWPORT_COLOR);
+            }
+        }
+        image
+    }
+
+    pub fn oam_entries(&self) -> [OamEntry; 40] {
+        std::array::from_fn(|i| OamEntry {
+            y: self.voam[i * 4],
+            x: self.voam[i * 4 + 1],
+            tile: self.voam[i * 4 + 2],
+            attributes: self.voam[i * 4 + 3],
+        })
+    }
+
+    /// The 40 sprites in OAM order, 8 per row, with their flips and palettes. Each sprite is
+    /// shown 8x16, the lower half stays empty for 8x8 sprites.
+    pub fn render_oam(&self) -> Image {
+        let mut image = Image::new(8 * 10, 5 * 18);
+        image.fill_rect(0, 0, image.width, image.height, DEBUG_BACKDROP);
+        for (i, entry) in self.oam_entries().iter().enumerate() {
+            let (left, top) = ((i % 8) * 10 + 1, (i / 8) * 18 + 1);
+            let (bank, palnr) = if self.gbmode == GbMode::Color {
+                (entry.vram_bank() as usize, entry.cgb_palette() as usize)
+            } else {
+                (0, entry.dmg_palette() as usize)
+            };
+            let height = self.sprite_size as usize;
+            let tile = match height {
+                16 => entry.tile & 0xFE,
+                _ => entry.tile,
+            } as usize;
+            for y in 0..height {
+                for x in 0..8 {
+                    let tx = if entry.x_flip() { 7 - x } else { x };
+                    let ty = if entry.y_flip() { height - 1 - y } else { y };
+                    let colnr = self.tile_pixel(bank, tile + ty / 8, tx, ty % 8);
+                    if colnr != 0 {
+                        image.set_pixel(left + x, top + y, self.obj_rgb(palnr, colnr));
+                    }
+                }
+            }
+        }
+        image
+    }
+
+    /// The 8 BG palettes on the left and the 8 OBJ palettes on the right, with 16x16 pixels
+    /// per colour. Classic mode only has BGP, OBP0 and OBP1.
+    pub fn render_palettes(&self) -> Image {
+        const SWATCH: usize = 16;
+        let mut image = Image::new(SWATCH * 9, SWATCH * 8);
+        image.fill_rect(0, 0, image.width, image.height, DEBUG_BACKDROP);
+        let (bg_count, obj_count) = match self.gbmode {
+            GbMode::Color => (8, 8),
+            _ => (1, 2),
+        };
+        for colnr in 0..4 {
+            for palnr in 0..bg_count {
+                let color = self.bg_rgb(palnr, colnr);
+                image.fill_rect(colnr * SWATCH, palnr * SWATCH, SWATCH, SWATCH, color);
+            }
+            for palnr in 0..obj_count {
+                let color = self.obj_rgb(palnr, colnr);
+                let x = (colnr + 5) * SWATCH;
+                image.fill_rect(x, palnr * SWATCH, SWATCH, SWATCH, color);
+            }
+        }
+        image
+    }
+
+    // Colour number of a pixel of one of the 384 tiles of a VRAM bank
+    fn tile_pixel(&self, bank: usize, tile: usize, x: usize, y: usize) -> usize {
+        let address = bank * 0x2000 + tile * 16 + y * 2;
+        let (b1, b2) = (self.vram[address], self.vram[address + 1]);
+        let bit = 7 - x;
+        ((b1 >> bit) & 1 | ((b2 >> bit) & 1) << 1) as usize
+    }
+
+    fn bg_rgb(&self, palnr: usize, colnr: usize) -> [u8; 3] {
+        match self.gbmode {
+            GbMode::Color => self.cgb_rgb(self.cbgpal[palnr][colnr]),
+            _ => self.dmg_colors[0][self.palb[colnr] as usize],
+        }
+    }
+
+    fn obj_rgb(&self, palnr: usize, colnr: usize) -> [u8; 3] {
+        match (self.gbmode, palnr) {
+            (GbMode::Color, _) => self.cgb_rgb(self.csprit[palnr][colnr]),
+            (_, 0) => self.dmg_colors[1][self.pal0[colnr] as usize],
+            _ => self.dmg_colors[2][self.pal1[colnr] as usize],
+        }
+    }
+
+    fn cgb_rgb(&self, [r, g, b]: [u8; 3]) -> [u8; 3] {
+        self.color_table.get(r as u16 | (g as u16) << 5 | (b as u16) << 10)
+    }
+}
+
 // Functions to determine the order of sprites. Input is a tuple x-coord, OAM position
 // These function ensures that sprites with a higher priority are 'larger'
 fn dmg_sprite_order(a: &(i32, i32, u8), b: &(i32, i32, u8)) -> Ordering {
@@ -654,3 +1135,267 @@ fn cgb_sprite_order(a: &(i32, i32, u8), b: &(i32, i32, u8)) -> Ordering {
     // CGB order: only prioritize based on OAM position.
     return b.2.cmp(&a.2);
 }
+
+#[cfg(test)]
+mod test {
+    use super::{Layer, TileMap, DEBUG_BACKDROP, GPU, VIEWPORT_COLOR};
+    use crate::color::ColorCorrection;
+    use crate::gbmode::GbMode;
+    use crate::pixel::PixelFormat;
+
+    const WHITE: [u8; 3] = [255, 255, 255];
+    const LIGHT: [u8; 3] = [192, 192, 192];
+    const DARK: [u8; 3] = [96, 96, 96];
+    const BLACK: [u8; 3] = [0, 0, 0];
+
+    // Runs the GPU until a line has been drawn, the LCD must be on
+    fn draw_line(gpu: &mut GPU, line: u8) {
+        while !(gpu.line == line && gpu.mode == 0) {
+            gpu.do_cycle(4);
+        }
+    }
+
+    // Fills all rows of a tile with the colour number `colnr`
+    fn fill_tile(gpu: &mut GPU, address: u16, colnr: u8) {
+        for row in 0..8 {
+            let lo = if colnr & 1 != 0 { 0xFF } else { 0x00 };
+            let hi = if colnr & 2 != 0 { 0xFF } else { 0x00 };
+            gpu.wb(address + row * 2, lo);
+            gpu.wb(address + row * 2 + 1, hi);
+        }
+    }
+
+    // A CGB GPU showing the colours without correction
+    fn cgb_gpu() -> GPU {
+        let mut gpu = GPU::new();
+        gpu.gbmode = GbMode::Color;
+        gpu.set_color_correction(ColorCorrection::Raw);
+        gpu
+    }
+
+    // `register` is BCPS or OCPS, `color` is BGR555
+    fn set_cgb_color(gpu: &mut GPU, register: u16, palnr: u8, colnr: u8, color: u16) {
+        let index = palnr * 8 + colnr * 2;
+        gpu.wb(register, index);
+        gpu.wb(register + 1, color as u8);
+        gpu.wb(register, index + 1);
+        gpu.wb(register + 1, (color >> 8) as u8);
+    }
+
+    // Tile 1 with colour 1 fills the background, sprite 0 at (8, 0) uses tile 2 with colour 2
+    fn setup_scene(gpu: &mut GPU, sprite_attributes: u8) {
+        fill_tile(gpu, 0x8010, 1);
+        fill_tile(gpu, 0x8020, 2);
+        for i in 0..32 * 32 {
+            gpu.wb(0x9800 + i, 0x01);
+        }
+        gpu.wb(0xFE00, 16);
+        gpu.wb(0xFE01, 16);
+        gpu.wb(0xFE02, 0x02);
+        gpu.wb(0xFE03, sprite_attributes);
+    }
+
+    #[test]
+    fn indexed_frame() {
+        let mut gpu = GPU::new();
+        gpu.set_pixel_format(PixelFormat::Indexed);
+        setup_scene(&mut gpu, 0x10);
+        gpu.wb(0xFF47, 0xE4);
+        // OBP1 reverses the shades, colour 2 is shade 1
+        gpu.wb(0xFF49, 0x1B);
+        gpu.wb(0xFF40, 0x93);
+        draw_line(&mut gpu, 0);
+        let frame = gpu.frame();
+        assert_eq!(frame.len(), 160 * 144);
+        // BG shade 1, then shade 1 of OBP1 with the sprite flag
+        assert_eq!(frame[0], 0x01);
+        assert_eq!(frame[8], 0x01 | 1 << 2 | 0x20);
+        assert_eq!(gpu.data[8 * 3..8 * 3 + 3], [192, 192, 192]);
+
+        let mut gpu = GPU::new();
+        gpu.gbmode = GbMode::Color;
+        gpu.set_pixel_format(PixelFormat::Indexed);
+        // OBJ palette 5
+        setup_scene(&mut gpu, 0x05);
+        // BG palette 3 in the attributes of the first tile
+        gpu.wb(0xFF4F, 0x01);
+        gpu.wb(0x9800, 0x03);
+        gpu.wb(0xFF4F, 0x00);
+        gpu.wb(0xFF40, 0x93);
+        draw_line(&mut gpu, 0);
+        let frame = gpu.frame();
+        assert_eq!(frame[0], 3 << 2 | 1);
+        assert_eq!(frame[8], 0x20 | 5 << 2 | 2);
+        // The second tile uses BG palette 0
+        assert_eq!(frame[16], 0x01);
+    }
+    #[test]
+    fn render_tiles() {
+        let mut gpu = GPU::new();
+        gpu.wb(0xFF47, 0xE4);
+        // The top left pixel of tile 3 and a full tile 300
+        gpu.wb(0x8030, 0x80);
+        fill_tile(&mut gpu, 0x8000 + 300 * 16, 3);
+        let image = gpu.render_tiles();
+        assert_eq!((image.width, image.height), (256, 192));
+        assert_eq!(image.pixel(24, 0), LIGHT);
+        assert_eq!(image.pixel(25, 0), WHITE);
+        assert_eq!(image.pixel(96 + 7, 144 + 7), BLACK);
+
+        // Bank 1 is on the right
+        let mut gpu = cgb_gpu();
+        set_cgb_color(&mut gpu, 0xFF68, 0, 2, 0x7C00);
+        gpu.wb(0xFF4F, 0x01);
+        fill_tile(&mut gpu, 0x8010, 2);
+        let image = gpu.render_tiles();
+        assert_eq!(image.pixel(128 + 8, 0), [0, 0, 255]);
+        assert_eq!(image.pixel(8, 0), BLACK);
+    }
+
+    #[test]
+    fn render_tile_map_signed_tiles() {
+        let mut gpu = GPU::new();
+        gpu.wb(0xFF47, 0xE4);
+        // LCDC selects the tiles at 8800 and the map at 9800
+        gpu.wb(0xFF40, 0x81);
+        // Tile 0 is at 9000, tile 80 at 8800
+        fill_tile(&mut gpu, 0x9000, 3);
+        fill_tile(&mut gpu, 0x8800, 2);
+        fill_tile(&mut gpu, 0x8000, 1);
+        gpu.wb(0x9800, 0x00);
+        gpu.wb(0x9801, 0x80);
+        let image = gpu.render_tile_map(TileMap::Map9800);
+        assert_eq!(image.pixel(4, 4), BLACK);
+        assert_eq!(image.pixel(12, 4), DARK);
+    }
+
+    #[test]
+    fn render_tile_map_attributes() {
+        let mut gpu = cgb_gpu();
+        set_cgb_color(&mut gpu, 0xFF68, 2, 1, 0x001F);
+        // Only the top left pixel of tile 5 in bank 1, shown with palette 2 and flipped in X
+        gpu.wb(0xFF4F, 0x01);
+        gpu.wb(0x8050, 0x80);
+        gpu.wb(0x9C00 + 33, 0x08 | 0x20 | 0x02);
+        gpu.wb(0xFF4F, 0x00);
+        gpu.wb(0x9C00 + 33, 0x05);
+        let image = gpu.render_tile_map(TileMap::Map9C00);
+        assert_eq!(image.pixel(8 + 7, 8), [255, 0, 0]);
+        assert_eq!(image.pixel(8, 8), BLACK);
+    }
+
+    #[test]
+    fn render_tile_map_viewport() {
+        let mut gpu = GPU::new();
+        gpu.wb(0xFF40, 0x81);
+        gpu.wb(0xFF43, 200);
+        gpu.wb(0xFF42, 250);
+        let image = gpu.render_tile_map(TileMap::Map9800);
+        // The outline wraps around both edges of the map
+        let (right, bottom) = ((200 + 159) % 256, (250 + 143) % 256);
+        for (x, y) in [(200, 250), (255, 250), (0, 250), (right, 250), (0, bottom)] {
+            assert_eq!(image.pixel(x, y), VIEWPORT_COLOR, "{} {}", x, y);
+        }
+        for (x, y) in [(200, 4), (right, 4), (200, bottom), (right, bottom)] {
+            assert_eq!(image.pixel(x, y), VIEWPORT_COLOR, "{} {}", x, y);
+        }
+        for (x, y) in [(right + 1, 250), (100, 100), (199, 4), (201, 4)] {
+            assert_ne!(image.pixel(x, y), VIEWPORT_COLOR, "{} {}", x, y);
+        }
+        // Only the map used by the background is outlined
+        let other = gpu.render_tile_map(TileMap::Map9C00);
+        assert!(other.data.chunks(3).all(|pixel| pixel != VIEWPORT_COLOR));
+    }
+
+    #[test]
+    fn render_oam_tall_sprites() {
+        let mut gpu = GPU::new();
+        gpu.wb(0xFF48, 0xE4);
+        gpu.wb(0xFF49, 0x1B);
+        // 8x16 sprites
+        gpu.wb(0xFF40, 0x84);
+        // Colour 1 in the top row of tile 4, colour 3 in the bottom row of tile 5
+        gpu.wb(0x8040, 0xFF);
+        gpu.wb(0x805E, 0xFF);
+        gpu.wb(0x805F, 0xFF);
+        // Sprite 1 uses the odd tile number of the pair and is flipped in Y
+        gpu.wb(0xFE04, 40);
+        gpu.wb(0xFE05, 30);
+        gpu.wb(0xFE06, 0x05);
+        gpu.wb(0xFE07, 0x40);
+        // Sprite 9 uses OBP1
+        gpu.wb(0xFE26, 0x04);
+        gpu.wb(0xFE27, 0x10);
+
+        let entries = gpu.oam_entries();
+        assert_eq!((entries[1].y, entries[1].x, entries[1].tile), (40, 30, 0x05));
+        assert!(entries[1].y_flip() && !entries[1].x_flip() && !entries[1].behind_bg());
+        assert_eq!(entries[9].dmg_palette(), 1);
+
+        let image = gpu.render_oam();
+        assert_eq!((image.width, image.height), (80, 90));
+        // Sprite 1 is at (11, 1), the bottom row of tile 5 is now on top
+        assert_eq!(image.pixel(11, 1), BLACK);
+        assert_eq!(image.pixel(11, 8), DEBUG_BACKDROP);
+        assert_eq!(image.pixel(11, 16), LIGHT);
+        assert_eq!(image.pixel(10, 1), DEBUG_BACKDROP);
+        // Sprite 9 is at (11, 19), colour 1 is shade 2 in OBP1
+        assert_eq!(image.pixel(11, 19), DARK);
+    }
+
+    #[test]
+    fn render_palettes() {
+        let mut gpu = cgb_gpu();
+        set_cgb_color(&mut gpu, 0xFF68, 7, 3, 0x001F);
+        set_cgb_color(&mut gpu, 0xFF6A, 6, 1, 0x03E0);
+        let image = gpu.render_palettes();
+        assert_eq!((image.width, image.height), (144, 128));
+        assert_eq!(image.pixel(3 * 16 + 8, 7 * 16 + 8), [255, 0, 0]);
+        assert_eq!(image.pixel(6 * 16, 6 * 16 + 15), [0, 255, 0]);
+        assert_eq!(image.pixel(4 * 16, 0), DEBUG_BACKDROP);
+
+        // Classic mode has one BG and two OBJ palettes
+        let mut gpu = GPU::new();
+        gpu.wb(0xFF47, 0xE4);
+        gpu.wb(0xFF49, 0x1B);
+        let image = gpu.render_palettes();
+        assert_eq!(image.pixel(2 * 16, 0), DARK);
+        assert_eq!(image.pixel(5 * 16, 16), BLACK);
+        assert_eq!(image.pixel(0, 16), DEBUG_BACKDROP);
+        assert_eq!(image.pixel(5 * 16, 2 * 16), DEBUG_BACKDROP);
+    }
+    // Draws the first line with the background in colour 1, sprite 0 in colour 2 at x = 8, a
+    // sprite behind the background at x = 40 and the window in colour 3 from x = 80
+    fn draw_layers(hidden: Option<Layer>) -> [[u8; 3]; 4] {
+        let mut gpu = GPU::new();
+        if let Some(layer) = hidden {
+            gpu.set_layer_enabled(layer, false);
+            assert!(!gpu.layer_enabled(layer));
+        }
+        setup_scene(&mut gpu, 0x00);
+        fill_tile(&mut gpu, 0x8030, 3);
+        for i in 0..32 * 32 {
+            gpu.wb(0x9C00 + i, 0x03);
+        }
+        gpu.wb(0xFE04, 16);
+        gpu.wb(0xFE05, 48);
+        gpu.wb(0xFE06, 0x02);
+        gpu.wb(0xFE07, 0x80);
+        gpu.wb(0xFF47, 0xE4);
+        gpu.wb(0xFF48, 0xE4);
+        gpu.wb(0xFF4A, 0);
+        gpu.wb(0xFF4B, 7 + 80);
+        gpu.wb(0xFF40, 0xF3);
+        draw_line(&mut gpu, 0);
+        [0, 8, 40, 80].map(|x| gpu.data[x * 3..x * 3 + 3].try_into().unwrap())
+    }
+
+    #[test]
+    fn hidden_layers() {
+        assert_eq!(draw_layers(None), [LIGHT, DARK, LIGHT, BLACK]);
+        // Sprites behind the background are not hidden by a hidden background
+        assert_eq!(draw_layers(Some(Layer::Background)), [WHITE, DARK, DARK, BLACK]);
+        assert_eq!(draw_layers(Some(Layer::Window)), [LIGHT, DARK, LIGHT, LIGHT]);
+        assert_eq!(draw_layers(Some(Layer::Sprites)), [LIGHT, LIGHT, LIGHT, BLACK]);
+    }
+}
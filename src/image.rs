@@ -0,0 +1,173 @@
+use crate::{Error, Result};
+use std::path::Path;
+
+/// An RGB888 picture, stored row by row
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub data: Vec<u8>,
+}
+
+impl Image {
+    pub fn new(width: usize, height: usize) -> Image {
+        Image {
+            width,
+            height,
+            data: vec![0; width * height * 3],
+        }
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> [u8; 3] {
+        let i = (y * self.width + x) * 3;
+        [self.data[i], self.data[i + 1], self.data[i + 2]]
+    }
+
+    pub fn set_pixel(&mut self, x: usize, y: usize, color: [u8; 3]) {
+        let i = (y * self.width + x) * 3;
+        self.data[i..i + 3].copy_from_slice(&color);
+    }
+
+    pub(crate) fn fill_rect(&mut self, x: usize, y: usize, w: usize, h: usize, color: [u8; 3]) {
+        for y in y..y + h {
+            for x in x..x + w {
+                self.set_pixel(x, y, color);
+            }
+        }
+    }
+
+    /// Enlarges the picture by an integer factor, without smoothing
+    pub fn scaled(&self, factor: usize) -> Image {
+        let factor = factor.max(1);
+        let mut res = Image::new(self.width * factor, self.height * factor);
+        for y in 0..res.height {
+            for x in 0..res.width {
+                res.set_pixel(x, y, self.pixel(x / factor, y / factor));
+            }
+        }
+        res
+    }
+
+    /// Encodes the picture as PNG file. The image data is stored without compression, which
+    /// keeps the encoder small.
+    pub fn to_png(&self) -> Vec<u8> {
+        // Every row starts with filter type 0 (none)
+        let row_len = self.width * 3;
+        let mut raw = Vec::with_capacity((row_len + 1) * self.height);
+        for row in self.data.chunks_exact(row_len.max(1)).take(self.height) {
+            raw.push(0);
+            raw.extend_from_slice(row);
+        }
+
+        let mut header = Vec::with_capacity(13);
+        header.extend_from_slice(&(self.width as u32).to_be_bytes());
+        header.extend_from_slice(&(self.height as u32).to_be_bytes());
+        // 8 bits per component, truecolour, deflate, adaptive filtering, no interlace
+        header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+        let mut res = b"\x89PNG\r\n\x1a\n".to_vec();
+        write_chunk(&mut res, b"IHDR", &header);
+        write_chunk(&mut res, b"IDAT", &zlib_stored(&raw));
+        write_chunk(&mut res, b"IEND", &[]);
+        res
+    }
+
+    pub fn save_png(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, self.to_png()).map_err(|source| Error::Io {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+}
+
+fn write_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let crc = crc32(&out[start..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+// A zlib stream of uncompressed deflate blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    const MAX_BLOCK: usize = 0xFFFF;
+    let mut res = vec![0x78, 0x01];
+    let mut blocks = data.chunks(MAX_BLOCK).peekable();
+    if blocks.peek().is_none() {
+        res.extend_from_slice(&[0x01, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        res.push(if blocks.peek().is_none() { 1 } else { 0 });
+        let len = block.len() as u16;
+        res.extend_from_slice(&len.to_le_bytes());
+        res.extend_from_slice(&(!len).to_le_bytes());
+        res.extend_from_slice(block);
+    }
+    res.extend_from_slice(&adler32(data).to_be_bytes());
+    res
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod test {
+    use super::{adler32, crc32, Image};
+
+    #[test]
+    fn checksums() {
+        assert_eq!(crc32(b"IEND"), 0xAE42_6082);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    }
+
+    #[test]
+    fn scaling() {
+        let mut image = Image::new(2, 1);
+        image.set_pixel(1, 0, [1, 2, 3]);
+        let scaled = image.scaled(3);
+        assert_eq!((scaled.width, scaled.height), (6, 3));
+        assert_eq!(scaled.pixel(2, 2), [0, 0, 0]);
+        assert_eq!(scaled.pixel(3, 0), [1, 2, 3]);
+        assert_eq!(scaled.pixel(5, 2), [1, 2, 3]);
+    }
+
+    #[test]
+    fn png_encoding() {
+        let mut image = Image::new(2, 1);
+        image.set_pixel(1, 0, [0xFF, 0x80, 0x00]);
+        let png = image.to_png();
+        assert_eq!(&png[0..8], b"\x89PNG\r\n\x1a\n");
+        // IHDR with the size
+        assert_eq!(&png[8..16], b"\x00\x00\x00\x0dIHDR");
+        assert_eq!(&png[16..24], &[0, 0, 0, 2, 0, 0, 0, 1]);
+        // IDAT with a single stored block holding both pixels
+        let idat = &png[33..];
+        assert_eq!(&idat[4..8], b"IDAT");
+        assert_eq!(&idat[8..15], &[0x78, 0x01, 0x01, 0x07, 0x00, 0xF8, 0xFF]);
+        assert_eq!(&idat[15..22], &[0, 0, 0, 0, 0xFF, 0x80, 0x00]);
+        assert_eq!(&png[png.len() - 12..], b"\x00\x00\x00\x00IEND\xae\x42\x60\x82");
+    }
+}
@@ -70,7 +70,7 @@ pub fn call(cpu: &mut CPU) -> u32 {
             1
         }
         0x10 => {
-            cpu.mmu.switch_speed();
+            cpu.stop();
             1
         } // STOP
         0x11 => {
@@ -141,7 +141,7 @@ pub fn call(cpu: &mut CPU) -> u32 {
                 cpu.cpu_jr();
                 3
             } else {
-                cpu.reg.pc += 1;
+                cpu.reg.pc = cpu.reg.pc.wrapping_add(1);
                 2
             }
         }
@@ -180,7 +180,7 @@ pub fn call(cpu: &mut CPU) -> u32 {
                 cpu.cpu_jr();
                 3
             } else {
-                cpu.reg.pc += 1;
+                cpu.reg.pc = cpu.reg.pc.wrapping_add(1);
                 2
             }
         }
@@ -221,7 +221,7 @@ pub fn call(cpu: &mut CPU) -> u32 {
                 cpu.cpu_jr();
                 3
             } else {
-                cpu.reg.pc += 1;
+                cpu.reg.pc = cpu.reg.pc.wrapping_add(1);
                 2
             }
         }
@@ -267,7 +267,7 @@ pub fn call(cpu: &mut CPU) -> u32 {
                 cpu.cpu_jr();
                 3
             } else {
-                cpu.reg.pc += 1;
+                cpu.reg.pc = cpu.reg.pc.wrapping_add(1);
                 2
             }
         }
@@ -820,7 +820,7 @@ pub fn call(cpu: &mut CPU) -> u32 {
                 cpu.reg.pc = cpu.fetchword();
                 4
             } else {
-                cpu.reg.pc += 2;
+                cpu.reg.pc = cpu.reg.pc.wrapping_add(2);
                 3
             }
         }
@@ -834,7 +834,7 @@ pub fn call(cpu: &mut CPU) -> u32 {
                 cpu.reg.pc = cpu.fetchword();
                 6
             } else {
-                cpu.reg.pc += 2;
+                cpu.reg.pc = cpu.reg.pc.wrapping_add(2);
                 3
             }
         }
@@ -869,7 +869,7 @@ pub fn call(cpu: &mut CPU) -> u32 {
                 cpu.reg.pc = cpu.fetchword();
                 4
             } else {
-                cpu.reg.pc += 2;
+                cpu.reg.pc = cpu.reg.pc.wrapping_add(2);
                 3
             }
         }
@@ -880,7 +880,7 @@ pub fn call(cpu: &mut CPU) -> u32 {
                 cpu.reg.pc = cpu.fetchword();
                 6
             } else {
-                cpu.reg.pc += 2;
+                cpu.reg.pc = cpu.reg.pc.wrapping_add(2);
                 3
             }
         }
@@ -917,7 +917,7 @@ pub fn call(cpu: &mut CPU) -> u32 {
                 cpu.reg.pc = cpu.fetchword();
                 4
             } else {
-                cpu.reg.pc += 2;
+                cpu.reg.pc = cpu.reg.pc.wrapping_add(2);
                 3
             }
         }
@@ -927,7 +927,7 @@ pub fn call(cpu: &mut CPU) -> u32 {
                 cpu.reg.pc = cpu.fetchword();
                 6
             } else {
-                cpu.reg.pc += 2;
+                cpu.reg.pc = cpu.reg.pc.wrapping_add(2);
                 3
             }
         }
@@ -963,7 +963,7 @@ pub fn call(cpu: &mut CPU) -> u32 {
                 cpu.reg.pc = cpu.fetchword();
                 4
             } else {
-                cpu.reg.pc += 2;
+                cpu.reg.pc = cpu.reg.pc.wrapping_add(2);
                 3
             }
         }
@@ -973,7 +973,7 @@ pub fn call(cpu: &mut CPU) -> u32 {
                 cpu.reg.pc = cpu.fetchword();
                 6
             } else {
-                cpu.reg.pc += 2;
+                cpu.reg.pc = cpu.reg.pc.wrapping_add(2);
                 3
             }
         }
@@ -1098,7 +1098,10 @@ pub fn call(cpu: &mut CPU) -> u32 {
             cpu.reg.pc = 0x38;
             4
         }
-        other => panic!("Instruction {:2X} is not implemented", other),
+        0xD3 | 0xDB | 0xDD | 0xE3 | 0xE4 | 0xEB | 0xEC | 0xED | 0xF4 | 0xFC | 0xFD => {
+            cpu.lock_up(opcode);
+            1
+        }
     }
 }
 
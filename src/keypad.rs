@@ -1,8 +1,73 @@
+const SGB_PACKET_BITS: usize = 128;
+const SGB_MLT_REQ: u8 = 0x11;
+
 pub struct Keypad {
-    row0: u8,
-    row1: u8,
+    rows: [[u8; 2]; 4],
     data: u8,
     pub interrupt: u8,
+    player: usize,
+    players: usize,
+    sgb: Option<PacketReceiver>,
+}
+
+/// Receives the command packets a game sends to the Super Game Boy through P14/P15
+struct PacketReceiver {
+    receiving: bool,
+    bit: usize,
+    packet: [u8; 16],
+    command: Vec<u8>,
+    remaining: usize,
+    complete: Option<Vec<u8>>,
+}
+
+impl PacketReceiver {
+    fn new() -> PacketReceiver {
+        PacketReceiver {
+            receiving: false,
+            bit: 0,
+            packet: [0; 16],
+            command: Vec::new(),
+            remaining: 0,
+            complete: None,
+        }
+    }
+
+    fn write(&mut self, old: u8, new: u8) {
+        if new == 0x00 {
+            // Reset pulse, starts a new packet
+            self.receiving = true;
+            self.bit = 0;
+            self.packet = [0; 16];
+            return;
+        }
+        // Every bit is a pulse on either line, separated by writes of 0x30
+        if !self.receiving || old != 0x30 || new == 0x30 {
+            return;
+        }
+
+        if self.bit == SGB_PACKET_BITS {
+            // Stop bit
+            self.receiving = false;
+            self.finish_packet();
+            return;
+        }
+        if new == 0x10 {
+            self.packet[self.bit / 8] |= 1 << (self.bit % 8);
+        }
+        self.bit += 1;
+    }
+
+    fn finish_packet(&mut self) {
+        if self.remaining == 0 {
+            self.command.clear();
+            self.remaining = ((self.packet[0] & 0x07) as usize).max(1);
+        }
+        self.command.extend_from_slice(&self.packet);
+        self.remaining -= 1;
+        if self.remaining == 0 {
+            self.complete = Some(std::mem::take(&mut self.command));
+        }
+    }
 }
 
 #[derive(Copy, Clone)]
@@ -20,31 +85,75 @@ pub enum KeypadKey {
 impl Keypad {
     pub fn new() -> Keypad {
         Keypad {
-            row0: 0x0F,
-            row1: 0x0F,
+            rows: [[0x0F; 2]; 4],
             data: 0xFF,
             interrupt: 0,
+            player: 0,
+            players: 1,
+            sgb: None,
         }
     }
 
+    pub fn new_sgb() -> Keypad {
+        let mut res = Keypad::new();
+        res.sgb = Some(PacketReceiver::new());
+        res
+    }
+
     pub fn rb(&self) -> u8 {
         self.data
     }
 
     pub fn wb(&mut self, value: u8) {
-        self.data = (self.data & 0xCF) | (value & 0x30);
+        let old = self.data & 0x30;
+        let new = value & 0x30;
+        self.data = (self.data & 0xCF) | new;
+
+        if let Some(sgb) = self.sgb.as_mut() {
+            let receiving = sgb.receiving;
+            sgb.write(old, new);
+            // The next controller is selected when P15 goes high outside of a packet
+            if !receiving && !sgb.receiving && old & 0x20 == 0 && new & 0x20 != 0 {
+                self.player = (self.player + 1) % self.players;
+            }
+        }
         self.update();
     }
 
+    /// Returns a completed Super Game Boy command, which may span multiple packets
+    pub fn take_sgb_command(&mut self) -> Option<Vec<u8>> {
+        let command = self.sgb.as_mut()?.complete.take()?;
+        if command[0] >> 3 == SGB_MLT_REQ {
+            self.players = match command[1] & 0x03 {
+                1 => 2,
+                3 => 4,
+                _ => 1,
+            };
+            self.player = 0;
+            self.update();
+        }
+        Some(command)
+    }
+
+    pub fn selected_pressed(&self) -> bool {
+        self.data & 0x0F != 0x0F
+    }
+
     fn update(&mut self) {
         let old_values = self.data & 0xF;
         let mut new_values = 0xF;
 
+        let rows = self.rows[self.player];
+
         if self.data & 0x10 == 0x00 {
-            new_values &= self.row0;
+            new_values &= rows[0];
         }
         if self.data & 0x20 == 0x00 {
-            new_values &= self.row1;
+            new_values &= rows[1];
+        }
+        if self.data & 0x30 == 0x30 && self.players > 1 {
+            // In multiplayer mode, the current controller is identified when no line is selected
+            new_values = 0xF - self.player as u8;
         }
 
         if old_values == 0xF && new_values != 0xF {
@@ -55,29 +164,40 @@ impl Keypad {
     }
 
     pub fn keydown(&mut self, key: KeypadKey) {
+        self.keydown_player(0, key);
+    }
+
+    pub fn keyup(&mut self, key: KeypadKey) {
+        self.keyup_player(0, key);
+    }
+
+    /// Presses a key on one of the four Super Game Boy controllers
+    pub fn keydown_player(&mut self, player: usize, key: KeypadKey) {
+        let rows = &mut self.rows[player & 0x03];
         match key {
-            KeypadKey::Right => self.row0 &= !(1 << 0),
-            KeypadKey::Left => self.row0 &= !(1 << 1),
-            KeypadKey::Up => self.row0 &= !(1 << 2),
-            KeypadKey::Down => self.row0 &= !(1 << 3),
-            KeypadKey::A => self.row1 &= !(1 << 0),
-            KeypadKey::B => self.row1 &= !(1 << 1),
-            KeypadKey::Select => self.row1 &= !(1 << 2),
-            KeypadKey::Start => self.row1 &= !(1 << 3),
+            KeypadKey::Right => rows[0] &= !(1 << 0),
+            KeypadKey::Left => rows[0] &= !(1 << 1),
+            KeypadKey::Up => rows[0] &= !(1 << 2),
+            KeypadKey::Down => rows[0] &= !(1 << 3),
+            KeypadKey::A => rows[1] &= !(1 << 0),
+            KeypadKey::B => rows[1] &= !(1 << 1),
+            KeypadKey::Select => rows[1] &= !(1 << 2),
+            KeypadKey::Start => rows[1] &= !(1 << 3),
         }
         self.update();
     }
 
-    pub fn keyup(&mut self, key: KeypadKey) {
+    pub fn keyup_player(&mut self, player: usize, key: KeypadKey) {
+        let rows = &mut self.rows[player & 0x03];
         match key {
-            KeypadKey::Right => self.row0 |= 1 << 0,
-            KeypadKey::Left => self.row0 |= 1 << 1,
-            KeypadKey::Up => self.row0 |= 1 << 2,
-            KeypadKey::Down => self.row0 |= 1 << 3,
-            KeypadKey::A => self.row1 |= 1 << 0,
-            KeypadKey::B => self.row1 |= 1 << 1,
-            KeypadKey::Select => self.row1 |= 1 << 2,
-            KeypadKey::Start => self.row1 |= 1 << 3,
+            KeypadKey::Right => rows[0] |= 1 << 0,
+            KeypadKey::Left => rows[0] |= 1 << 1,
+            KeypadKey::Up => rows[0] |= 1 << 2,
+            KeypadKey::Down => rows[0] |= 1 << 3,
+            KeypadKey::A => rows[1] |= 1 << 0,
+            KeypadKey::B => rows[1] |= 1 << 1,
+            KeypadKey::Select => rows[1] |= 1 << 2,
+            KeypadKey::Start => rows[1] |= 1 << 3,
         }
         self.update();
     }
@@ -144,4 +264,66 @@ mod test {
             keypad.keyup(keys1[i]);
         }
     }
+
+    fn send_packet(keypad: &mut super::Keypad, packet: &[u8; 16]) {
+        keypad.wb(0x00);
+        keypad.wb(0x30);
+        for bit in 0..128 {
+            let one = packet[bit / 8] & (1 << (bit % 8)) != 0;
+            keypad.wb(if one { 0x10 } else { 0x20 });
+            keypad.wb(0x30);
+        }
+        keypad.wb(0x20);
+        keypad.wb(0x30);
+    }
+
+    #[test]
+    fn sgb_packet() {
+        let mut keypad = super::Keypad::new_sgb();
+        let mut packet = [0u8; 16];
+        packet[0] = (0x17 << 3) | 1;
+        packet[1] = 0x02;
+        packet[15] = 0xA5;
+
+        send_packet(&mut keypad, &packet);
+        assert_eq!(keypad.take_sgb_command(), Some(packet.to_vec()));
+        assert_eq!(keypad.take_sgb_command(), None);
+    }
+
+    #[test]
+    fn sgb_multi_packet() {
+        let mut keypad = super::Keypad::new_sgb();
+        let mut first = [0u8; 16];
+        first[0] = (0x04 << 3) | 2;
+        let second = [0x5Au8; 16];
+
+        send_packet(&mut keypad, &first);
+        assert_eq!(keypad.take_sgb_command(), None);
+        send_packet(&mut keypad, &second);
+        let command = keypad.take_sgb_command().unwrap();
+        assert_eq!(command.len(), 32);
+        assert_eq!(&command[16..], &second);
+    }
+
+    #[test]
+    fn sgb_multiplayer() {
+        let mut keypad = super::Keypad::new_sgb();
+        let mut packet = [0u8; 16];
+        packet[0] = (0x11 << 3) | 1;
+        packet[1] = 0x01;
+        send_packet(&mut keypad, &packet);
+        keypad.take_sgb_command();
+
+        keypad.keydown_player(1, KeypadKey::A);
+        assert_eq!(keypad.rb() & 0x0F, 0x0F);
+
+        keypad.wb(0x10);
+        keypad.wb(0x30);
+        assert_eq!(keypad.rb() & 0x0F, 0x0E);
+        keypad.wb(0x10);
+        assert_eq!(keypad.rb() & 0x0F, 0x0E);
+
+        keypad.wb(0x30);
+        assert_eq!(keypad.rb() & 0x0F, 0x0F);
+    }
 }
@@ -1,20 +1,46 @@
 #![crate_type = "lib"]
 
-pub use crate::gpu::{SCREEN_H, SCREEN_W};
+pub use crate::blend::FrameBlending;
+pub use crate::color::ColorCorrection;
+pub use crate::cpu::{CpuStatus, Lockup};
+pub use crate::error::{Error, Result};
+pub use crate::filter::Filter;
+pub use crate::gpu::{Layer, OamEntry, TileMap, SCREEN_H, SCREEN_W};
+pub use crate::image::Image;
 pub use crate::keypad::KeypadKey;
-pub use crate::sound::AudioPlayer;
+pub use crate::mmu::{MemoryInit, RamPattern};
+pub use crate::palette::{DmgPalette, CGB_PALETTE_BUTTONS, CGB_PALETTE_COUNT};
+pub use crate::pixel::PixelFormat;
+pub use crate::sgb::{SGB_SCREEN_H, SGB_SCREEN_W};
+pub use crate::sound::{AudioPlayer, HighPassFilter, SoundChannel};
+pub use crate::timeline::{FrameTimeline, InterruptEvent, PpuWrite};
+pub use crate::vgm::{ApuLog, ApuWrite};
+pub use crate::video::Y4mWriter;
+pub use crate::wav::WavWriter;
 
 pub mod device;
+pub mod gbs;
 
+mod blend;
+mod color;
 mod cpu;
+mod error;
+mod filter;
 mod gbmode;
 mod gpu;
+mod image;
 mod instructions;
 mod keypad;
 mod mbc;
 mod mmu;
+mod palette;
+mod pixel;
 mod register;
+mod sgb;
 mod sound;
+mod timeline;
 mod timer;
-
-pub type StrResult<T> = Result<T, &'static str>;
+mod vgm;
+mod video;
+mod wav;
+mod xbrz;
@@ -1,19 +1,102 @@
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 use cpal::{FromSample, Sample};
 use gb_emulator::device::Device;
-use gb_emulator::KeypadKey;
+use gb_emulator::gbs::GbsPlayer;
+use gb_emulator::{
+    ColorCorrection, DmgPalette, Filter, FrameBlending, HighPassFilter, Image, KeypadKey, Layer,
+    MemoryInit, RamPattern, SoundChannel, TileMap,
+};
 use gb_emulator::AudioPlayer;
-use std::sync::mpsc::{self, Receiver, SyncSender, TryRecvError, TrySendError};
-use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TryRecvError, TrySendError};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::thread;
+use std::time::{Duration, Instant};
 use winit::platform::pump_events::{EventLoopExtPumpEvents, PumpStatus};
 
 const EXITCODE_SUCCESS: i32 = 0;
 const EXITCODE_CPULOADFAILS: i32 = 2;
+const EXITCODE_RECORDINGFAILS: i32 = 3;
+
+// Clock ticks per frame, used to run a fixed number of frames in headless mode
+const TICKS_PER_FRAME: u32 = 70224;
+const DEFAULT_HEADLESS_FRAMES: u32 = 600;
+// Play length and fade out of each song when converting GBS files
+const DEFAULT_GBS_LENGTH_SECS: f64 = 150.0;
+const DEFAULT_GBS_FADE_SECS: f64 = 8.0;
+
+// Refresh rate of the LCD, used to pace emulation when there is no audio to follow
+const FRAMES_PER_SECOND: f64 = 59.7275;
+// Amount of audio kept in the buffer of the audio device, unless set with --audio-latency
+const DEFAULT_AUDIO_LATENCY_MS: u32 = 60;
+// Preferred sample rate, unless set with --sample-rate. The nearest supported rate is used.
+const DEFAULT_SAMPLE_RATE: u32 = 44100;
+// Maximum deviation of the audio rate used to keep the buffer at the target level
+const MAX_RATE_ADJUSTMENT: f64 = 0.005;
+
+/// How the screen is stretched to the size of the window
+#[derive(PartialEq, Copy, Clone)]
+enum Scaling {
+    Nearest,
+    Linear,
+    /// The largest integer multiple that fits, centred with black bars
+    Integer,
+}
+
+#[derive(PartialEq, Copy, Clone)]
+enum Mode {
+    Classic,
+    Color,
+    Sgb,
+}
+
+struct Options {
+    filename: String,
+    mode: Mode,
+    palette: Option<DmgPalette>,
+    color_correction: ColorCorrection,
+    frame_blending: FrameBlending,
+    memory_init: MemoryInit,
+    filter: Filter,
+    scaling: Scaling,
+    record_audio: Option<String>,
+    record_vgm: Option<String>,
+    record_channels: Option<String>,
+    record_video: Option<String>,
+    debug_views: bool,
+    screenshot_dir: Option<String>,
+    screenshot_scale: usize,
+    screenshot_raw: bool,
+    screenshot: Option<String>,
+    dump_debug_views: Option<String>,
+    headless: bool,
+    frames: Option<u32>,
+    audio_latency_ms: u32,
+    no_audio: bool,
+    audio_device: Option<String>,
+    sample_rate: u32,
+    mono: bool,
+    high_pass: HighPassFilter,
+    list_audio_devices: bool,
+    gbs2wav: Option<String>,
+    track: Option<u8>,
+    length_secs: f64,
+    fade_secs: f64,
+}
 
 enum GBEvent {
     KeyUp(KeypadKey),
     KeyDown(KeypadKey),
+    Reset,
+    PowerCycle,
+    ToggleMute(SoundChannel),
+    ToggleSolo(SoundChannel),
+    ToggleAudioRecording,
+    ToggleVideoRecording,
+    CycleFrameBlending,
+    ToggleLayer(Layer),
+    Screenshot,
 }
 
 // #[cfg(target_os = "windows")]
@@ -31,67 +114,532 @@ fn create_window_builder(romname: &str) -> winit::window::WindowBuilder {
 
 fn main() {
     let args: Vec<String> = std::env::args().collect();
-    if args.len() != 2 {
-        eprintln!("Usage: game_boy <gamefile_name>");
-        std::process::exit(1);
-    }
-    let filename = &args[1];
+    let options = match parse_args(&args[1..]) {
+        Some(options) => options,
+        None => {
+            eprintln!(
+                "Usage: game_boy [--classic | --sgb] [--palette <palette>] [--color-correction <mode>] \
+                 [--frame-blending <mode>] [--filter <filter>] [--scaling <mode>] \
+                 [--memory-init <pattern>] [--record-audio <file.wav>] [--record-vgm <file.vgm>] \
+                 [--record-channels <dir>] [--record-video <file.y4m>] [--debug-views] \
+                 [--screenshot-dir <dir>] \
+                 [--screenshot-scale <n>] [--screenshot-raw] [--headless] [--frames <count>] \
+                 [--screenshot <file.png>] [--dump-debug-views <prefix>] \
+                 [--audio-latency <ms>] [--no-audio] \
+                 [--audio-device <name>] [--sample-rate <hz>] [--mono] \
+                 [--high-pass <mode>] <gamefile_name>\n       \
+                 game_boy --list-audio-devices\n       \
+                 game_boy --gbs2wav <file.wav> [--track <n>] [--length <secs>] \
+                 [--fade <secs>] <file.gbs>"
+            );
+            std::process::exit(1);
+        }
+    };
 
-    let exit_status = real_main_minimal(filename);
+    let exit_status = if options.list_audio_devices {
+        list_audio_devices()
+    } else if options.gbs2wav.is_some() {
+        real_main_gbs2wav(&options)
+    } else if options.headless {
+        real_main_headless(&options)
+    } else {
+        real_main_minimal(&options)
+    };
     if exit_status != EXITCODE_SUCCESS {
         std::process::exit(exit_status);
     }
 }
 
-fn real_main_minimal(filename: &str) -> i32 {
-    // Always use CGB mode, always enable audio, always scale 2
-    let opt_classic = false;
+fn parse_args(args: &[String]) -> Option<Options> {
+    let mut filename = None;
+    let mut mode = Mode::Color;
+    let mut palette = None;
+    let mut color_correction = ColorCorrection::Gambatte;
+    let mut frame_blending = FrameBlending::Off;
+    let mut memory_init = MemoryInit::default();
+    let mut filter = Filter::None;
+    let mut scaling = Scaling::Nearest;
+    let mut record_audio = None;
+    let mut record_vgm = None;
+    let mut record_channels = None;
+    let mut record_video = None;
+    let mut debug_views = false;
+    let mut screenshot_dir = None;
+    let mut screenshot_scale = 1;
+    let mut screenshot_raw = false;
+    let mut screenshot = None;
+    let mut dump_debug_views = None;
+    let mut headless = false;
+    let mut frames = None;
+    let mut audio_latency_ms = DEFAULT_AUDIO_LATENCY_MS;
+    let mut no_audio = false;
+    let mut audio_device = None;
+    let mut sample_rate = DEFAULT_SAMPLE_RATE;
+    let mut mono = false;
+    let mut high_pass = HighPassFilter::Accurate;
+    let mut list_audio_devices = false;
+    let mut gbs2wav = None;
+    let mut track = None;
+    let mut length_secs = DEFAULT_GBS_LENGTH_SECS;
+    let mut fade_secs = DEFAULT_GBS_FADE_SECS;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--classic" => mode = Mode::Classic,
+            "--sgb" => mode = Mode::Sgb,
+            "--palette" => palette = Some(parse_palette(args.next()?)?),
+            "--color-correction" => color_correction = parse_color_correction(args.next()?)?,
+            "--frame-blending" => frame_blending = parse_frame_blending(args.next()?)?,
+            "--memory-init" => {
+                let pattern = parse_ram_pattern(args.next()?)?;
+                memory_init = MemoryInit {
+                    wram: pattern,
+                    vram: pattern,
+                };
+            }
+            "--filter" => filter = parse_filter(args.next()?)?,
+            "--scaling" => scaling = parse_scaling(args.next()?)?,
+            "--record-audio" => record_audio = Some(args.next()?.clone()),
+            "--record-vgm" => record_vgm = Some(args.next()?.clone()),
+            "--record-channels" => record_channels = Some(args.next()?.clone()),
+            "--record-video" => record_video = Some(args.next()?.clone()),
+            "--debug-views" => debug_views = true,
+            "--screenshot-dir" => screenshot_dir = Some(args.next()?.clone()),
+            "--screenshot-scale" => {
+                screenshot_scale = args.next()?.parse().ok().filter(|v| (1..=16).contains(v))?
+            }
+            "--screenshot-raw" => screenshot_raw = true,
+            "--screenshot" => screenshot = Some(args.next()?.clone()),
+            "--dump-debug-views" => dump_debug_views = Some(args.next()?.clone()),
+            "--headless" => headless = true,
+            "--frames" => frames = Some(args.next()?.parse().ok()?),
+            "--audio-latency" => audio_latency_ms = args.next()?.parse().ok().filter(|&v| v > 0)?,
+            "--no-audio" => no_audio = true,
+            "--audio-device" => audio_device = Some(args.next()?.clone()),
+            "--sample-rate" => {
+                sample_rate = args.next()?.parse().ok().filter(|v| (8000..=192000).contains(v))?
+            }
+            "--mono" => mono = true,
+            "--high-pass" => high_pass = parse_high_pass(args.next()?)?,
+            "--list-audio-devices" => list_audio_devices = true,
+            "--gbs2wav" => gbs2wav = Some(args.next()?.clone()),
+            "--track" => track = Some(args.next()?.parse().ok().filter(|&v| v > 0)?),
+            "--length" => length_secs = parse_secs(args.next()?)?,
+            "--fade" => fade_secs = parse_secs(args.next()?)?,
+            _ if arg.starts_with("--") || filename.is_some() => return None,
+            _ => filename = Some(arg.clone()),
+        }
+    }
+    Some(Options {
+        // Listing the audio devices does not need a game
+        filename: if list_audio_devices {
+            filename.unwrap_or_default()
+        } else {
+            filename?
+        },
+        mode,
+        palette,
+        color_correction,
+        frame_blending,
+        memory_init,
+        filter,
+        scaling,
+        record_audio,
+        record_vgm,
+        record_channels,
+        record_video,
+        debug_views,
+        screenshot_dir,
+        screenshot_scale,
+        screenshot_raw,
+        screenshot,
+        dump_debug_views,
+        headless,
+        frames,
+        audio_latency_ms,
+        no_audio,
+        audio_device,
+        sample_rate,
+        mono,
+        high_pass,
+        list_audio_devices,
+        gbs2wav,
+        track,
+        length_secs,
+        fade_secs,
+    })
+}
+
+/// Accepts a preset name, `auto` for the choice of the CGB boot ROM, a CGB boot ROM button
+/// combination like `up+a`, `cgb:<id>`, or four colours like `ffffff,aaaaaa,555555,000000`
+fn parse_palette(arg: &str) -> Option<DmgPalette> {
+    let arg = arg.to_lowercase();
+    let palette = match arg.as_str() {
+        "grey" | "gray" => DmgPalette::Grey,
+        "green" => DmgPalette::Green,
+        "pocket" => DmgPalette::Pocket,
+        "light" => DmgPalette::Light,
+        "auto" => DmgPalette::CgbAuto,
+        _ => {
+            let mut buttons = gb_emulator::CGB_PALETTE_BUTTONS.iter();
+            if let Some(&(_, id)) = buttons.find(|(name, _)| *name == arg) {
+                return Some(DmgPalette::Cgb(id));
+            }
+            if let Some(id) = arg.strip_prefix("cgb:") {
+                let id = id.parse().ok().filter(|&id| id < gb_emulator::CGB_PALETTE_COUNT)?;
+                return Some(DmgPalette::Cgb(id));
+            }
+            let colors: Vec<[u8; 3]> = arg.split(',').map(parse_rgb).collect::<Option<_>>()?;
+            DmgPalette::Custom(colors.try_into().ok()?)
+        }
+    };
+    Some(palette)
+}
+
+fn parse_color_correction(arg: &str) -> Option<ColorCorrection> {
+    match arg.to_lowercase().as_str() {
+        "raw" | "off" => Some(ColorCorrection::Raw),
+        "gambatte" => Some(ColorCorrection::Gambatte),
+        "modern" => Some(ColorCorrection::ModernBalanced),
+        "gba" => Some(ColorCorrection::GbaScreen),
+        _ => None,
+    }
+}
+
+fn parse_frame_blending(arg: &str) -> Option<FrameBlending> {
+    match arg.to_lowercase().as_str() {
+        "off" => Some(FrameBlending::Off),
+        "simple" => Some(FrameBlending::Simple),
+        "exponential" => Some(FrameBlending::Exponential),
+        _ => None,
+    }
+}
+
+fn parse_high_pass(arg: &str) -> Option<HighPassFilter> {
+    match arg.to_lowercase().as_str() {
+        "accurate" => Some(HighPassFilter::Accurate),
+        "preserve" => Some(HighPassFilter::PreserveWaveform),
+        "off" => Some(HighPassFilter::Off),
+        _ => None,
+    }
+}
+
+/// Accepts `zero`, `fill:<byte>` with a hexadecimal byte, or `random` with an optional seed
+/// like `random:1234`
+fn parse_ram_pattern(arg: &str) -> Option<RamPattern> {
+    let arg = arg.to_lowercase();
+    match arg.split_once(':') {
+        None if arg == "zero" => Some(RamPattern::Zero),
+        None if arg == "random" => Some(RamPattern::Random { seed: timestamp() as u32 }),
+        Some(("fill", value)) => u8::from_str_radix(value, 16).ok().map(RamPattern::Fill),
+        Some(("random", seed)) => Some(RamPattern::Random { seed: seed.parse().ok()? }),
+        _ => None,
+    }
+}
+
+fn parse_filter(arg: &str) -> Option<Filter> {
+    match arg.to_lowercase().as_str() {
+        "none" => Some(Filter::None),
+        "scale2x" => Some(Filter::Scale2x),
+        "scale3x" => Some(Filter::Scale3x),
+        "xbrz2x" => Some(Filter::Xbrz2x),
+        "xbrz3x" => Some(Filter::Xbrz3x),
+        "xbrz4x" => Some(Filter::Xbrz4x),
+        "lcd" => Some(Filter::LcdGrid),
+        _ => None,
+    }
+}
+
+fn parse_scaling(arg: &str) -> Option<Scaling> {
+    match arg.to_lowercase().as_str() {
+        "nearest" => Some(Scaling::Nearest),
+        "linear" => Some(Scaling::Linear),
+        "integer" => Some(Scaling::Integer),
+        _ => None,
+    }
+}
+
+fn parse_rgb(hex: &str) -> Option<[u8; 3]> {
+    let hex = hex.trim().trim_start_matches('#');
+    let value = u32::from_str_radix(hex, 16).ok().filter(|_| hex.len() == 6)?;
+    Some([(value >> 16) as u8, (value >> 8) as u8, value as u8])
+}
+
+fn parse_secs(arg: &str) -> Option<f64> {
+    arg.parse().ok().filter(|v: &f64| v.is_finite() && *v >= 0.0)
+}
+
+/// Converts a song of a GBS file to a WAV file, as fast as possible
+fn real_main_gbs2wav(options: &Options) -> i32 {
+    let mut player = match GbsPlayer::new(Path::new(&options.filename)) {
+        Ok(player) => player,
+        Err(e) => {
+            warn(&e.to_string());
+            return EXITCODE_CPULOADFAILS;
+        }
+    };
+    // Tracks are numbered from 1 on the command line, like in the GBS header
+    let track = options.track.unwrap_or(player.header().first_song).saturating_sub(1);
+    player.enable_audio(Box::new(NullPlayer::new(options.sample_rate)));
+    player.set_length(Some(Duration::from_secs_f64(options.length_secs)));
+    player.set_fade(Duration::from_secs_f64(options.fade_secs));
+    if let Err(e) = player.start_track(track) {
+        warn(&e.to_string());
+        return EXITCODE_CPULOADFAILS;
+    }
+    let path = options.gbs2wav.as_deref().unwrap_or_default();
+    if let Err(e) = player.start_audio_recording(Path::new(path)) {
+        warn(&e.to_string());
+        return EXITCODE_RECORDINGFAILS;
+    }
+
+    while !player.is_finished() {
+        player.do_cycle();
+    }
+
+    player.flush_audio();
+    if let Err(e) = player.stop_audio_recording() {
+        warn(&e.to_string());
+        return EXITCODE_RECORDINGFAILS;
+    }
+    EXITCODE_SUCCESS
+}
+
+/// Runs a fixed number of frames without window or audio device, as fast as possible
+fn real_main_headless(options: &Options) -> i32 {
+    let cpu = construct_cpu(&options.filename, options.mode, false, None, options.memory_init);
+    let mut cpu = match cpu {
+        Some(cpu) => cpu,
+        None => return EXITCODE_CPULOADFAILS,
+    };
+    cpu.set_color_correction(options.color_correction);
+    cpu.set_frame_blending(options.frame_blending);
+    if let Some(palette) = options.palette {
+        cpu.set_dmg_palette(palette);
+    }
+    cpu.enable_audio(Box::new(NullPlayer::new(options.sample_rate)), false);
+    cpu.set_high_pass_filter(options.high_pass);
+    cpu.set_timeline_enabled(options.dump_debug_views.is_some());
+    if let Some(path) = &options.record_audio {
+        if let Err(e) = cpu.start_audio_recording(Path::new(path)) {
+            warn(&e.to_string());
+            return EXITCODE_RECORDINGFAILS;
+        }
+    }
+    if let Some(dir) = &options.record_channels {
+        if let Err(e) = cpu.start_channel_recording(Path::new(dir)) {
+            warn(&e.to_string());
+            return EXITCODE_RECORDINGFAILS;
+        }
+    }
+    if options.record_vgm.is_some() {
+        let _ = cpu.start_apu_log();
+    }
+    if let Some(path) = &options.record_video {
+        if !start_video_recording(&mut cpu, Path::new(path)) {
+            return EXITCODE_RECORDINGFAILS;
+        }
+    }
+
+    // Count clock ticks instead of frames, the LCD may be off for a long time
+    let frames = options.frames.unwrap_or(DEFAULT_HEADLESS_FRAMES);
+    let total_ticks = frames as u64 * TICKS_PER_FRAME as u64;
+    let mut ticks = 0u64;
+    while ticks < total_ticks {
+        ticks += cpu.do_cycle() as u64;
+        // Passes the frames to the video recording
+        cpu.check_and_reset_gpu_updated();
+        if let Some(lockup) = cpu.check_and_reset_lockup() {
+            warn(&format!(
+                "CPU locked up on illegal opcode {:02X} at {:04X}",
+                lockup.opcode, lockup.address
+            ));
+        }
+    }
+
+    if let Err(e) = cpu.stop_video_recording() {
+        warn(&e.to_string());
+        return EXITCODE_RECORDINGFAILS;
+    }
+    if let Err(e) = cpu.stop_audio_recording() {
+        warn(&e.to_string());
+        return EXITCODE_RECORDINGFAILS;
+    }
+    if let Err(e) = cpu.stop_channel_recording() {
+        warn(&e.to_string());
+        return EXITCODE_RECORDINGFAILS;
+    }
+    if let Some(path) = &options.record_vgm {
+        if !save_apu_log(&mut cpu, path) {
+            return EXITCODE_RECORDINGFAILS;
+        }
+    }
+    if let Some(path) = &options.screenshot {
+        let screenshots = Screenshots::new(options);
+        if let Err(e) = screenshots.render(&cpu).save_png(Path::new(path)) {
+            warn(&e.to_string());
+            return EXITCODE_RECORDINGFAILS;
+        }
+    }
+    if let Some(prefix) = &options.dump_debug_views {
+        for (name, image) in render_debug_views(&cpu) {
+            let path = format!("{}-{}.png", prefix, name.to_lowercase().replace(' ', "-"));
+            if let Err(e) = image.save_png(Path::new(&path)) {
+                warn(&e.to_string());
+                return EXITCODE_RECORDINGFAILS;
+            }
+        }
+        if let Some(timeline) = cpu.timeline() {
+            let path = format!("{}-timeline.txt", prefix);
+            if let Err(e) = timeline.save_text(Path::new(&path)) {
+                warn(&e.to_string());
+                return EXITCODE_RECORDINGFAILS;
+            }
+        }
+    }
+    EXITCODE_SUCCESS
+}
+
+/// The debug views of the frontend, with the names of their windows
+fn render_debug_views(cpu: &Device) -> Vec<(&'static str, Image)> {
+    let map_9800 = cpu.render_tile_map(TileMap::Map9800);
+    let map_9c00 = cpu.render_tile_map(TileMap::Map9C00);
+    // Both maps side by side, with a gap of 8 pixels
+    let mut maps = Image::new(map_9800.width * 2 + 8, map_9800.height);
+    for y in 0..map_9800.height {
+        for x in 0..map_9800.width {
+            maps.set_pixel(x, y, map_9800.pixel(x, y));
+            maps.set_pixel(map_9800.width + 8 + x, y, map_9c00.pixel(x, y));
+        }
+    }
+    let mut views = vec![
+        ("Tiles", cpu.render_tiles()),
+        ("Tile maps", maps),
+        ("OAM", cpu.render_oam()),
+        ("Palettes", cpu.render_palettes()),
+    ];
+    if let Some(timeline) = cpu.render_timeline() {
+        views.push(("Timeline", timeline));
+    }
+    views
+}
+
+fn real_main_minimal(options: &Options) -> i32 {
+    // Always enable audio, always scale 2
     let opt_skip_checksum = false;
     let scale = 2;
     let opt_reload: Option<String> = None;
     let is_new_start = true;
-    let cpu = construct_cpu(filename, opt_classic, opt_skip_checksum, opt_reload.clone());
+    let cpu = construct_cpu(
+        &options.filename,
+        options.mode,
+        opt_skip_checksum,
+        opt_reload.clone(),
+        options.memory_init,
+    );
     if cpu.is_none() {
         return EXITCODE_CPULOADFAILS;
     }
     let mut cpu = cpu.unwrap();
+    cpu.set_color_correction(options.color_correction);
+    cpu.set_frame_blending(options.frame_blending);
+    if let Some(palette) = options.palette {
+        cpu.set_dmg_palette(palette);
+    }
+    let screen_size = match cpu.get_sgb_data() {
+        Some(..) => (gb_emulator::SGB_SCREEN_W as u32, gb_emulator::SGB_SCREEN_H as u32),
+        None => (gb_emulator::SCREEN_W as u32, gb_emulator::SCREEN_H as u32),
+    };
 
-    // Always enable audio
-    let player = CpalPlayer::get();
-    let cpal_audio_stream = match player {
+    let player = if options.no_audio {
+        None
+    } else {
+        match CpalPlayer::get(options) {
+            Ok(player) => Some(player),
+            Err(e) => {
+                warn(&format!("Could not open audio device ({}), running without audio", e));
+                None
+            }
+        }
+    };
+    let mut audio_level = None;
+    let (cpal_audio_stream, pacer) = match player {
         Some((v, s)) => {
+            audio_level = Some(v.level());
+            let pacer = FramePacer::audio(v.level(), v.sample_rate, options.audio_latency_ms);
             cpu.enable_audio(Box::new(v) as Box<dyn AudioPlayer>, !is_new_start);
-            Some(s)
+            (Some(s), pacer)
         }
         None => {
-            warn("Could not open audio device");
-            return EXITCODE_CPULOADFAILS;
+            // Without an audio device to follow, the emulation is paced by the clock
+            cpu.enable_audio(Box::new(NullPlayer::new(options.sample_rate)), !is_new_start);
+            (None, FramePacer::clock())
         }
     };
+    cpu.set_high_pass_filter(options.high_pass);
     let romname = cpu.romname();
+    if let Some(path) = &options.record_audio {
+        if let Err(e) = cpu.start_audio_recording(Path::new(path)) {
+            warn(&e.to_string());
+            return EXITCODE_RECORDINGFAILS;
+        }
+    }
+    if let Some(dir) = &options.record_channels {
+        if let Err(e) = cpu.start_channel_recording(Path::new(dir)) {
+            warn(&e.to_string());
+            return EXITCODE_RECORDINGFAILS;
+        }
+    }
+    if options.record_vgm.is_some() {
+        let _ = cpu.start_apu_log();
+    }
+    if let Some(path) = &options.record_video {
+        if !start_video_recording(&mut cpu, Path::new(path)) {
+            return EXITCODE_RECORDINGFAILS;
+        }
+    }
+    cpu.set_timeline_enabled(options.debug_views);
+    let record_vgm = options.record_vgm.clone();
+    let screenshots = Screenshots::new(options);
 
     let (sender1, receiver1) = mpsc::channel();
     let (sender2, receiver2) = mpsc::sync_channel(1);
+    let (debug_sender, debug_receiver) = match options.debug_views {
+        true => {
+            let (sender, receiver) = mpsc::sync_channel(1);
+            (Some(sender), Some(receiver))
+        }
+        false => (None, None),
+    };
+    // Created when the first views arrive, `None` after the user closed the window
+    let mut debug_windows: Vec<(&'static str, Option<DebugWindow>)> = Vec::new();
 
     let mut event_loop = winit::event_loop::EventLoop::new().unwrap();
     let window_builder = create_window_builder(&romname);
     let (window, display) = glium::backend::glutin::SimpleWindowBuilder::new()
         .set_window_builder(window_builder)
         .build(&event_loop);
-    set_window_size(&window, scale);
+    // Large enough to show every pixel of the filter
+    let (filter, scaling) = (options.filter, options.scaling);
+    let factor = filter.factor() as u32;
+    set_window_size(&window, screen_size, scale.max(factor));
 
+    let texture_size = (screen_size.0 * factor, screen_size.1 * factor);
     let mut texture = glium::texture::texture2d::Texture2d::empty_with_format(
         &display,
         glium::texture::UncompressedFloatFormat::U8U8U8,
         glium::texture::MipmapsOption::NoMipmap,
-        gb_emulator::SCREEN_W as u32,
-        gb_emulator::SCREEN_H as u32,
+        texture_size.0,
+        texture_size.1,
     )
     .unwrap();
 
     // no render options
 
-    let cputhread = thread::spawn(move || run_cpu(cpu, sender2, receiver1));
+    let cputhread = thread::spawn(move || {
+        run_cpu(cpu, pacer, record_vgm, sender2, screenshots, debug_sender, receiver1)
+    });
 
     event_loop.set_control_flow(winit::event_loop::ControlFlow::Poll);
     'evloop: loop {
@@ -102,14 +650,25 @@ fn real_main_minimal(filename: &str) -> i32 {
             use winit::keyboard::{Key, NamedKey};
 
             match ev {
-                Event::WindowEvent { event, .. } => match event {
+                Event::WindowEvent { window_id, event } => match event {
+                    WindowEvent::CloseRequested if window_id != window.id() => {
+                        for (_, debug_window) in debug_windows.iter_mut() {
+                            if debug_window.as_ref().is_some_and(|w| w.window.id() == window_id) {
+                                *debug_window = None;
+                            }
+                        }
+                    }
                     WindowEvent::CloseRequested => elwt.exit(),
                     WindowEvent::KeyboardInput {
                         event: keyevent, ..
                     } => match (keyevent.state, keyevent.logical_key.as_ref()) {
                         (pressed, Key::Named(NamedKey::Escape)) => elwt.exit(),
                         (pressed, winitkey) => {
-                            if let Some(key) = winit_to_keypad(winitkey) {
+                            if let Some(event) = winit_to_hotkey(&winitkey) {
+                                if !keyevent.repeat {
+                                    let _ = sender1.send(event);
+                                }
+                            } else if let Some(key) = winit_to_keypad(winitkey) {
                                 let _ = sender1.send(GBEvent::KeyDown(key));
                             }
                         }
@@ -128,9 +687,34 @@ fn real_main_minimal(filename: &str) -> i32 {
         if let PumpStatus::Exit(_) = status {
             break 'evloop;
         }
-        match receiver2.recv() {
-            Ok(data) => recalculate_screen(&display, &mut texture, &*data),
-            Err(..) => break 'evloop, // Remote end has hung-up
+        // The CPU may not produce frames for a while (e.g. in STOP mode), so keep pumping events
+        match receiver2.recv_timeout(std::time::Duration::from_millis(20)) {
+            Ok(data) => {
+                let screen = Image {
+                    width: screen_size.0 as usize,
+                    height: screen_size.1 as usize,
+                    data,
+                };
+                let screen = filter.apply(&screen);
+                recalculate_screen(&display, &mut texture, &screen.data, texture_size, scaling);
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break 'evloop, // Remote end has hung-up
+        }
+        if let Some(Ok(views)) = debug_receiver.as_ref().map(|r| r.try_recv()) {
+            for (name, image) in views {
+                let index = match debug_windows.iter().position(|(n, _)| *n == name) {
+                    Some(index) => index,
+                    None => {
+                        let debug_window = DebugWindow::new(&event_loop, name, &image);
+                        debug_windows.push((name, Some(debug_window)));
+                        debug_windows.len() - 1
+                    }
+                };
+                if let Some(debug_window) = debug_windows[index].1.as_mut() {
+                    debug_window.show(&image);
+                }
+            }
         }
     }
 
@@ -138,6 +722,13 @@ fn real_main_minimal(filename: &str) -> i32 {
     drop(receiver2); // Stop CPU thread by disconnecting
     let _ = cputhread.join();
 
+    if let Some(level) = audio_level {
+        let (underruns, overruns) = level.counters();
+        if underruns > 0 || overruns > 0 {
+            warn(&format!("Audio: {} underruns, {} overruns", underruns, overruns));
+        }
+    }
+
     EXITCODE_SUCCESS
 }
 
@@ -156,43 +747,86 @@ fn winit_to_keypad(key: winit::keyboard::Key<&str>) -> Option<KeypadKey> {
     }
 }
 
+fn winit_to_hotkey(key: &winit::keyboard::Key<&str>) -> Option<GBEvent> {
+    use winit::keyboard::{Key, NamedKey};
+    match key {
+        Key::Named(NamedKey::F1) => Some(GBEvent::ToggleLayer(Layer::Background)),
+        Key::Named(NamedKey::F2) => Some(GBEvent::ToggleLayer(Layer::Window)),
+        Key::Named(NamedKey::F3) => Some(GBEvent::ToggleLayer(Layer::Sprites)),
+        Key::Named(NamedKey::F5) => Some(GBEvent::Reset),
+        Key::Named(NamedKey::F6) => Some(GBEvent::PowerCycle),
+        Key::Character("1") => Some(GBEvent::ToggleMute(SoundChannel::Square1)),
+        Key::Character("2") => Some(GBEvent::ToggleMute(SoundChannel::Square2)),
+        Key::Character("3") => Some(GBEvent::ToggleMute(SoundChannel::Wave)),
+        Key::Character("4") => Some(GBEvent::ToggleMute(SoundChannel::Noise)),
+        Key::Character("5") => Some(GBEvent::ToggleSolo(SoundChannel::Square1)),
+        Key::Character("6") => Some(GBEvent::ToggleSolo(SoundChannel::Square2)),
+        Key::Character("7") => Some(GBEvent::ToggleSolo(SoundChannel::Wave)),
+        Key::Character("8") => Some(GBEvent::ToggleSolo(SoundChannel::Noise)),
+        Key::Named(NamedKey::F7) => Some(GBEvent::CycleFrameBlending),
+        Key::Named(NamedKey::F9) => Some(GBEvent::ToggleAudioRecording),
+        Key::Named(NamedKey::F10) => Some(GBEvent::ToggleVideoRecording),
+        Key::Named(NamedKey::F12) => Some(GBEvent::Screenshot),
+        _ => None,
+    }
+}
+
 fn recalculate_screen<
     T: glium::glutin::surface::SurfaceTypeTrait + glium::glutin::surface::ResizeableSurface + 'static,
 >(
     display: &glium::Display<T>,
     texture: &mut glium::texture::texture2d::Texture2d,
     datavec: &[u8],
+    (width, height): (u32, u32),
+    scaling: Scaling,
 ) {
     use glium::Surface;
 
-    let interpolation_type = glium::uniforms::MagnifySamplerFilter::Nearest;
+    let interpolation_type = match scaling {
+        Scaling::Linear => glium::uniforms::MagnifySamplerFilter::Linear,
+        Scaling::Nearest | Scaling::Integer => glium::uniforms::MagnifySamplerFilter::Nearest,
+    };
 
     let rawimage2d = glium::texture::RawImage2d {
         data: std::borrow::Cow::Borrowed(datavec),
-        width: gb_emulator::SCREEN_W as u32,
-        height: gb_emulator::SCREEN_H as u32,
+        width,
+        height,
         format: glium::texture::ClientFormat::U8U8U8,
     };
     texture.write(
         glium::Rect {
             left: 0,
             bottom: 0,
-            width: gb_emulator::SCREEN_W as u32,
-            height: gb_emulator::SCREEN_H as u32,
+            width,
+            height,
         },
         rawimage2d,
     );
 
     // We use a custom BlitTarget to transform OpenGL coordinates to row-column coordinates
-    let target = display.draw();
+    let mut target = display.draw();
     let (target_w, target_h) = target.get_dimensions();
+    let (left, bottom, blit_w, blit_h) = match scaling {
+        Scaling::Integer => {
+            let factor = (target_w / width).min(target_h / height).max(1);
+            let (blit_w, blit_h) = (width * factor, height * factor);
+            target.clear_color(0.0, 0.0, 0.0, 1.0);
+            (
+                target_w.saturating_sub(blit_w) / 2,
+                target_h - target_h.saturating_sub(blit_h) / 2,
+                blit_w,
+                blit_h,
+            )
+        }
+        Scaling::Nearest | Scaling::Linear => (0, target_h, target_w, target_h),
+    };
     texture.as_surface().blit_whole_color_to(
         &target,
         &glium::BlitTarget {
-            left: 0,
-            bottom: target_h,
-            width: target_w as i32,
-            height: -(target_h as i32),
+            left,
+            bottom,
+            width: blit_w as i32,
+            height: -(blit_h as i32),
         },
         interpolation_type,
     );
@@ -205,18 +839,20 @@ fn warn(message: &str) {
 
 fn construct_cpu(
     filename: &str,
-    classic_mode: bool,
+    mode: Mode,
     skip_checksum: bool,
     reload_mode: Option<String>,
+    memory_init: MemoryInit,
 ) -> Option<Box<Device>> {
-    let opt_c = match classic_mode {
-        true => Device::new(filename, skip_checksum, reload_mode),
-        false => Device::new_cgb(filename, skip_checksum, reload_mode),
+    let opt_c = match mode {
+        Mode::Classic => Device::new(filename, skip_checksum, reload_mode),
+        Mode::Color => Device::new_cgb(filename, skip_checksum, reload_mode),
+        Mode::Sgb => Device::new_sgb(filename, skip_checksum, reload_mode),
     };
     let c = match opt_c {
-        Ok(cpu) => cpu,
+        Ok(cpu) => cpu.with_memory_init(memory_init),
         Err(message) => {
-            warn(message);
+            warn(&message.to_string());
             return None;
         }
     };
@@ -224,110 +860,481 @@ fn construct_cpu(
     Some(Box::new(c))
 }
 
-fn run_cpu(mut cpu: Box<Device>, sender: SyncSender<Vec<u8>>, receiver: Receiver<GBEvent>) {
-    let periodic = timer_periodic(16);
-
-    let waitticks = (4194304f64 / 1000.0 * 16.0).round() as u32;
+fn run_cpu(
+    mut cpu: Box<Device>,
+    mut pacer: FramePacer,
+    record_vgm: Option<String>,
+    sender: SyncSender<Vec<u8>>,
+    screenshots: Screenshots,
+    debug_sender: Option<SyncSender<Vec<(&'static str, Image)>>>,
+    receiver: Receiver<GBEvent>,
+) {
     let mut ticks = 0;
 
     'outer: loop {
-        while ticks < waitticks {
+        while ticks < TICKS_PER_FRAME {
             ticks += cpu.do_cycle();
+            if let Some(lockup) = cpu.check_and_reset_lockup() {
+                warn(&format!(
+                    "CPU locked up on illegal opcode {:02X} at {:04X}, press F5 to reset",
+                    lockup.opcode, lockup.address
+                ));
+            }
             if cpu.check_and_reset_gpu_updated() {
-                let data = cpu.get_gpu_data().to_vec();
+                let data = match cpu.get_sgb_data() {
+                    Some(data) => data.to_vec(),
+                    None => cpu.get_gpu_data().to_vec(),
+                };
                 if let Err(TrySendError::Disconnected(..)) = sender.try_send(data) {
                     break 'outer;
                 }
+                if let Some(debug_sender) = &debug_sender {
+                    let _ = debug_sender.try_send(render_debug_views(&cpu));
+                }
             }
         }
 
-        ticks -= waitticks;
+        ticks -= TICKS_PER_FRAME;
 
         'recv: loop {
             match receiver.try_recv() {
                 Ok(event) => match event {
                     GBEvent::KeyUp(key) => cpu.keyup(key),
                     GBEvent::KeyDown(key) => cpu.keydown(key),
+                    GBEvent::Reset => cpu.reset(),
+                    GBEvent::PowerCycle => cpu.power_cycle(cpu.memory_init()),
+                    GBEvent::ToggleMute(channel) => {
+                        let muted = !cpu.channel_muted(channel);
+                        cpu.set_channel_muted(channel, muted);
+                    }
+                    GBEvent::ToggleSolo(channel) => {
+                        let solo = !cpu.channel_solo(channel);
+                        cpu.set_channel_solo(channel, solo);
+                    }
+                    GBEvent::ToggleAudioRecording => toggle_audio_recording(&mut cpu),
+                    GBEvent::ToggleVideoRecording => toggle_video_recording(&mut cpu),
+                    GBEvent::CycleFrameBlending => {
+                        let mode = match cpu.frame_blending() {
+                            FrameBlending::Off => FrameBlending::Simple,
+                            FrameBlending::Simple => FrameBlending::Exponential,
+                            FrameBlending::Exponential => FrameBlending::Off,
+                        };
+                        cpu.set_frame_blending(mode);
+                    }
+                    GBEvent::ToggleLayer(layer) => {
+                        let enabled = !cpu.layer_enabled(layer);
+                        cpu.set_layer_enabled(layer, enabled);
+                    }
+                    GBEvent::Screenshot => screenshots.save(&cpu),
                 },
                 Err(TryRecvError::Empty) => break 'recv,
                 Err(TryRecvError::Disconnected) => break 'outer,
             }
         }
 
-        // throttle timing each frame
-        let _ = periodic.recv();
+        pacer.wait(&mut cpu);
+    }
+
+    if let Err(e) = cpu.stop_video_recording() {
+        warn(&e.to_string());
+    }
+    if let Err(e) = cpu.stop_audio_recording() {
+        warn(&e.to_string());
+    }
+    if let Err(e) = cpu.stop_channel_recording() {
+        warn(&e.to_string());
+    }
+    if let Some(path) = &record_vgm {
+        save_apu_log(&mut cpu, path);
     }
 }
 
-fn timer_periodic(ms: u64) -> Receiver<()> {
-    let (tx, rx) = std::sync::mpsc::sync_channel(1);
-    std::thread::spawn(move || loop {
-        std::thread::sleep(std::time::Duration::from_millis(ms));
-        if tx.send(()).is_err() {
-            break;
+/// Writes the sound register writes logged since startup to a VGM file
+fn save_apu_log(cpu: &mut Device, path: &str) -> bool {
+    let log = match cpu.stop_apu_log() {
+        Some(log) => log,
+        None => return true,
+    };
+    match log.save_vgm(Path::new(path)) {
+        Ok(()) => true,
+        Err(e) => {
+            warn(&e.to_string());
+            false
         }
-    });
-    rx
+    }
+}
+
+fn toggle_audio_recording(cpu: &mut Device) {
+    if cpu.is_recording_video() {
+        warn("The audio is already recorded with the video");
+        return;
+    }
+    if cpu.is_recording_audio() {
+        match cpu.stop_audio_recording() {
+            Ok(()) => warn("Audio recording stopped"),
+            Err(e) => warn(&e.to_string()),
+        }
+        return;
+    }
+
+    let path = format!("{}-{}.wav", cpu.romname(), timestamp());
+    match cpu.start_audio_recording(Path::new(&path)) {
+        Ok(()) => warn(&format!("Recording audio to {}", path)),
+        Err(e) => warn(&e.to_string()),
+    }
+}
+
+/// Where and how the screenshot hotkey saves the screen
+struct Screenshots {
+    dir: PathBuf,
+    filter: Filter,
+    scale: usize,
+    raw_colors: bool,
+}
+
+impl Screenshots {
+    fn new(options: &Options) -> Screenshots {
+        // Next to the ROM by default
+        let dir = match &options.screenshot_dir {
+            Some(dir) => PathBuf::from(dir),
+            None => Path::new(&options.filename)
+                .parent()
+                .map_or_else(PathBuf::new, Path::to_path_buf),
+        };
+        Screenshots {
+            dir,
+            filter: options.filter,
+            scale: options.screenshot_scale,
+            raw_colors: options.screenshot_raw,
+        }
+    }
+
+    fn save(&self, cpu: &Device) {
+        let name = format!("{}-{}", cpu.romname(), timestamp());
+        let mut path = self.dir.join(format!("{}.png", name));
+        // Several screenshots within a second get a counter
+        let mut count = 1;
+        while path.exists() {
+            count += 1;
+            path = self.dir.join(format!("{}-{}.png", name, count));
+        }
+        match self.render(cpu).save_png(&path) {
+            Ok(()) => warn(&format!("Saved screenshot to {}", path.display())),
+            Err(e) => warn(&e.to_string()),
+        }
+    }
+
+    /// The screen with the filter of the window, enlarged by the screenshot scale
+    fn render(&self, cpu: &Device) -> Image {
+        self.filter.apply(&cpu.screenshot(1, self.raw_colors)).scaled(self.scale)
+    }
+}
+
+fn toggle_video_recording(cpu: &mut Device) {
+    if cpu.is_recording_video() {
+        match cpu.stop_video_recording() {
+            Ok(()) => warn("Video recording stopped"),
+            Err(e) => warn(&e.to_string()),
+        }
+        return;
+    }
+
+    let path = format!("{}-{}.y4m", cpu.romname(), timestamp());
+    if start_video_recording(cpu, Path::new(&path)) {
+        warn(&format!("Recording video to {}", path));
+    }
+}
+
+/// Records the video to `path` and the audio next to it, with the extension `.wav`
+fn start_video_recording(cpu: &mut Device, path: &Path) -> bool {
+    match cpu.start_video_recording(path, &path.with_extension("wav")) {
+        Ok(()) => true,
+        Err(e) => {
+            warn(&e.to_string());
+            false
+        }
+    }
+}
+
+fn timestamp() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Decides when the next frame is emulated. With audio, emulation follows the clock of the
+/// audio device, so it neither drifts nor under- or overflows the audio buffer. Without audio,
+/// frames are timed by the LCD refresh rate.
+enum FramePacer {
+    Audio { level: AudioLevel, target: usize },
+    Clock { next_frame: Instant },
+}
+
+impl FramePacer {
+    fn audio(level: AudioLevel, sample_rate: u32, latency_ms: u32) -> FramePacer {
+        FramePacer::Audio {
+            level,
+            target: (sample_rate as u64 * latency_ms as u64 / 1000) as usize,
+        }
+    }
+
+    fn clock() -> FramePacer {
+        FramePacer::Clock {
+            next_frame: Instant::now(),
+        }
+    }
+
+    fn wait(&mut self, cpu: &mut Device) {
+        match self {
+            FramePacer::Audio { level, target } => {
+                cpu.flush_audio();
+
+                // Wait until the audio device played enough of the buffer. Give up after a
+                // while, the device may have stopped playing.
+                let give_up = Instant::now() + Duration::from_millis(100);
+                while level.buffered() > *target && Instant::now() < give_up {
+                    thread::sleep(Duration::from_millis(1));
+                }
+
+                // Slightly speed up or slow down the audio to keep the buffer at the target,
+                // e.g. when the emulation can not keep up or the clocks differ
+                let error = (*target as f64 - level.buffered() as f64) / *target as f64;
+                cpu.set_audio_rate_ratio(1.0 + error.clamp(-1.0, 1.0) * MAX_RATE_ADJUSTMENT);
+            }
+            FramePacer::Clock { next_frame } => {
+                let frame = Duration::from_secs_f64(1.0 / FRAMES_PER_SECOND);
+                *next_frame += frame;
+                let now = Instant::now();
+                if *next_frame > now {
+                    thread::sleep(*next_frame - now);
+                } else if now - *next_frame > frame * 4 {
+                    // Too far behind, do not try to catch up
+                    *next_frame = now;
+                }
+            }
+        }
+    }
+}
+
+/// A window showing one of the debug views at twice its size
+struct DebugWindow {
+    window: winit::window::Window,
+    display: glium::Display<glium::glutin::surface::WindowSurface>,
+    texture: glium::texture::texture2d::Texture2d,
+    size: (u32, u32),
+}
+
+impl DebugWindow {
+    fn new(
+        event_loop: &winit::event_loop::EventLoop<()>,
+        name: &str,
+        image: &Image,
+    ) -> DebugWindow {
+        let size = (image.width as u32, image.height as u32);
+        let (window, display) = glium::backend::glutin::SimpleWindowBuilder::new()
+            .with_title(&format!("Game Boy - {}", name))
+            .with_inner_size(size.0 * 2, size.1 * 2)
+            .build(event_loop);
+        let texture = glium::texture::texture2d::Texture2d::empty_with_format(
+            &display,
+            glium::texture::UncompressedFloatFormat::U8U8U8,
+            glium::texture::MipmapsOption::NoMipmap,
+            size.0,
+            size.1,
+        )
+        .unwrap();
+        DebugWindow {
+            window,
+            display,
+            texture,
+            size,
+        }
+    }
+
+    fn show(&mut self, image: &Image) {
+        let (display, texture) = (&self.display, &mut self.texture);
+        recalculate_screen(display, texture, &image.data, self.size, Scaling::Nearest);
+    }
 }
 
-fn set_window_size(window: &winit::window::Window, scale: u32) {
+fn set_window_size(window: &winit::window::Window, (width, height): (u32, u32), scale: u32) {
     let _ = window.request_inner_size(winit::dpi::LogicalSize::<u32>::from((
-        gb_emulator::SCREEN_W as u32 * scale,
-        gb_emulator::SCREEN_H as u32 * scale,
+        width * scale,
+        height * scale,
     )));
 }
 
-struct CpalPlayer {
-    buffer: Arc<Mutex<Vec<(f32, f32)>>>,
+/// Discards the audio, used when running without audio device. Recordings still use its
+/// sample rate.
+struct NullPlayer {
     sample_rate: u32,
 }
 
-impl CpalPlayer {
-    fn get() -> Option<(CpalPlayer, cpal::Stream)> {
-        let device = match cpal::default_host().default_output_device() {
-            Some(e) => e,
-            None => return None,
-        };
+impl NullPlayer {
+    fn new(sample_rate: u32) -> NullPlayer {
+        NullPlayer { sample_rate }
+    }
+}
 
-        // We want a config with:
-        // chanels = 2
-        // SampleFormat F32
-        // Rate at around 44100
+impl AudioPlayer for NullPlayer {
+    fn play(&mut self, _buf_left: &[f32], _buf_right: &[f32]) {}
 
-        let wanted_samplerate = cpal::SampleRate(44100);
-        let supported_configs = match device.supported_output_configs() {
-            Ok(e) => e,
-            Err(_) => return None,
-        };
-        let mut supported_config = None;
-        for f in supported_configs {
-            if f.channels() == 2 && f.sample_format() == cpal::SampleFormat::F32 {
-                if f.min_sample_rate() <= wanted_samplerate
-                    && wanted_samplerate <= f.max_sample_rate()
-                {
-                    supported_config = Some(f.with_sample_rate(wanted_samplerate));
-                } else {
-                    supported_config = Some(f.with_max_sample_rate());
-                }
+    fn samples_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn underflowed(&self) -> bool {
+        false
+    }
+}
+
+/// Bounded single producer, single consumer queue of stereo samples, shared between the
+/// emulation and the audio callback. Samples are stored as the bits of their f32 value, so
+/// neither side ever takes a lock.
+struct AudioRing {
+    samples: Box<[AtomicU32]>, // Interleaved left and right samples
+    capacity: usize,           // Capacity in stereo frames
+    read: AtomicUsize,         // Frames read by the consumer, wrapping
+    write: AtomicUsize,        // Frames written by the producer, wrapping
+    underruns: AtomicUsize,    // Callbacks which could not be filled completely
+    overruns: AtomicUsize,     // Blocks of samples which did not fit
+}
+
+impl AudioRing {
+    fn new(capacity: usize) -> AudioRing {
+        AudioRing {
+            samples: (0..capacity * 2).map(|_| AtomicU32::new(0)).collect(),
+            capacity,
+            read: AtomicUsize::new(0),
+            write: AtomicUsize::new(0),
+            underruns: AtomicUsize::new(0),
+            overruns: AtomicUsize::new(0),
+        }
+    }
+
+    fn len(&self) -> usize {
+        let read = self.read.load(Ordering::Acquire);
+        self.write.load(Ordering::Acquire).wrapping_sub(read)
+    }
+
+    // Only called by the producer. Samples which do not fit are dropped.
+    fn push(&self, left: &[f32], right: &[f32]) {
+        let read = self.read.load(Ordering::Acquire);
+        let mut write = self.write.load(Ordering::Relaxed);
+
+        for (l, r) in left.iter().zip(right) {
+            if write.wrapping_sub(read) == self.capacity {
+                self.overruns.fetch_add(1, Ordering::Relaxed);
                 break;
             }
+            let i = (write % self.capacity) * 2;
+            self.samples[i].store(l.to_bits(), Ordering::Relaxed);
+            self.samples[i + 1].store(r.to_bits(), Ordering::Relaxed);
+            write = write.wrapping_add(1);
         }
-        if supported_config.is_none() {
-            return None;
+        self.write.store(write, Ordering::Release);
+    }
+
+    // Only called by the consumer. Missing samples are replaced by silence.
+    fn pop_into<T: Sample + FromSample<f32>>(&self, out: &mut [T], layout: OutputLayout) {
+        let write = self.write.load(Ordering::Acquire);
+        let mut read = self.read.load(Ordering::Relaxed);
+        let mut underrun = false;
+
+        for frame in out.chunks_mut(layout.channels) {
+            if read == write {
+                underrun = true;
+                frame.fill(T::EQUILIBRIUM);
+                continue;
+            }
+            let i = (read % self.capacity) * 2;
+            let left = f32::from_bits(self.samples[i].load(Ordering::Relaxed));
+            let right = f32::from_bits(self.samples[i + 1].load(Ordering::Relaxed));
+            let (left, right) = if layout.mono || layout.channels == 1 {
+                let mixed = (left + right) * 0.5;
+                (mixed, mixed)
+            } else {
+                (left, right)
+            };
+            // Additional channels of surround devices stay silent
+            for (channel, out) in frame.iter_mut().enumerate() {
+                *out = match channel {
+                    0 => T::from_sample(left),
+                    1 => T::from_sample(right),
+                    _ => T::EQUILIBRIUM,
+                };
+            }
+            read = read.wrapping_add(1);
+        }
+        self.read.store(read, Ordering::Release);
+
+        // Before the first samples arrive, silence is expected
+        if underrun && write != 0 {
+            self.underruns.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+struct CpalPlayer {
+    ring: Arc<AudioRing>,
+    sample_rate: u32,
+}
+
+/// Reports the state of the audio buffer to the frame pacer and for diagnostics
+#[derive(Clone)]
+struct AudioLevel {
+    ring: Arc<AudioRing>,
+}
+
+impl AudioLevel {
+    fn buffered(&self) -> usize {
+        self.ring.len()
+    }
+
+    /// Number of underruns and overruns since the audio device was opened
+    fn counters(&self) -> (usize, usize) {
+        (
+            self.ring.underruns.load(Ordering::Relaxed),
+            self.ring.overruns.load(Ordering::Relaxed),
+        )
+    }
+}
+
+impl CpalPlayer {
+    fn level(&self) -> AudioLevel {
+        AudioLevel {
+            ring: self.ring.clone(),
         }
+    }
 
-        let selected_config = supported_config.unwrap();
+    fn get(options: &Options) -> Result<(CpalPlayer, cpal::Stream), String> {
+        let host = cpal::default_host();
+        let device = match &options.audio_device {
+            Some(name) => find_output_device(&host, name)?,
+            None => host
+                .default_output_device()
+                .ok_or_else(|| "no output device".to_owned())?,
+        };
+        let selected_config = select_output_config(&device, options.sample_rate)?;
 
         let sample_format = selected_config.sample_format();
         let config: cpal::StreamConfig = selected_config.into();
+        let layout = OutputLayout {
+            channels: config.channels as usize,
+            mono: options.mono,
+        };
+        let latency_ms = options.audio_latency_ms;
 
         let err_fn = |err| eprintln!("An error occurred on the output audio stream: {}", err);
 
-        let shared_buffer = Arc::new(Mutex::new(Vec::new()));
-        let stream_buffer = shared_buffer.clone();
+        // Leave plenty of room above the latency target, the emulation waits before the buffer
+        // gets full
+        let capacity = (config.sample_rate.0 as u64 * latency_ms as u64 * 4 / 1000) as usize;
+        let shared_ring = Arc::new(AudioRing::new(capacity.max(4096)));
+        let stream_buffer = shared_ring.clone();
 
         let player = CpalPlayer {
-            buffer: shared_buffer,
+            ring: shared_ring,
             sample_rate: config.sample_rate.0,
         };
 
@@ -335,7 +1342,7 @@ impl CpalPlayer {
             cpal::SampleFormat::I8 => device.build_output_stream(
                 &config,
                 move |data: &mut [i8], _callback_info: &cpal::OutputCallbackInfo| {
-                    cpal_thread(data, &stream_buffer)
+                    cpal_thread(data, &stream_buffer, layout)
                 },
                 err_fn,
                 None,
@@ -343,7 +1350,7 @@ impl CpalPlayer {
             cpal::SampleFormat::I16 => device.build_output_stream(
                 &config,
                 move |data: &mut [i16], _callback_info: &cpal::OutputCallbackInfo| {
-                    cpal_thread(data, &stream_buffer)
+                    cpal_thread(data, &stream_buffer, layout)
                 },
                 err_fn,
                 None,
@@ -351,7 +1358,7 @@ impl CpalPlayer {
             cpal::SampleFormat::I32 => device.build_output_stream(
                 &config,
                 move |data: &mut [i32], _callback_info: &cpal::OutputCallbackInfo| {
-                    cpal_thread(data, &stream_buffer)
+                    cpal_thread(data, &stream_buffer, layout)
                 },
                 err_fn,
                 None,
@@ -359,7 +1366,7 @@ impl CpalPlayer {
             cpal::SampleFormat::I64 => device.build_output_stream(
                 &config,
                 move |data: &mut [i64], _callback_info: &cpal::OutputCallbackInfo| {
-                    cpal_thread(data, &stream_buffer)
+                    cpal_thread(data, &stream_buffer, layout)
                 },
                 err_fn,
                 None,
@@ -367,7 +1374,7 @@ impl CpalPlayer {
             cpal::SampleFormat::U8 => device.build_output_stream(
                 &config,
                 move |data: &mut [u8], _callback_info: &cpal::OutputCallbackInfo| {
-                    cpal_thread(data, &stream_buffer)
+                    cpal_thread(data, &stream_buffer, layout)
                 },
                 err_fn,
                 None,
@@ -375,7 +1382,7 @@ impl CpalPlayer {
             cpal::SampleFormat::U16 => device.build_output_stream(
                 &config,
                 move |data: &mut [u16], _callback_info: &cpal::OutputCallbackInfo| {
-                    cpal_thread(data, &stream_buffer)
+                    cpal_thread(data, &stream_buffer, layout)
                 },
                 err_fn,
                 None,
@@ -383,7 +1390,7 @@ impl CpalPlayer {
             cpal::SampleFormat::U32 => device.build_output_stream(
                 &config,
                 move |data: &mut [u32], _callback_info: &cpal::OutputCallbackInfo| {
-                    cpal_thread(data, &stream_buffer)
+                    cpal_thread(data, &stream_buffer, layout)
                 },
                 err_fn,
                 None,
@@ -391,7 +1398,7 @@ impl CpalPlayer {
             cpal::SampleFormat::U64 => device.build_output_stream(
                 &config,
                 move |data: &mut [u64], _callback_info: &cpal::OutputCallbackInfo| {
-                    cpal_thread(data, &stream_buffer)
+                    cpal_thread(data, &stream_buffer, layout)
                 },
                 err_fn,
                 None,
@@ -399,7 +1406,7 @@ impl CpalPlayer {
             cpal::SampleFormat::F32 => device.build_output_stream(
                 &config,
                 move |data: &mut [f32], _callback_info: &cpal::OutputCallbackInfo| {
-                    cpal_thread(data, &stream_buffer)
+                    cpal_thread(data, &stream_buffer, layout)
                 },
                 err_fn,
                 None,
@@ -407,47 +1414,131 @@ impl CpalPlayer {
             cpal::SampleFormat::F64 => device.build_output_stream(
                 &config,
                 move |data: &mut [f64], _callback_info: &cpal::OutputCallbackInfo| {
-                    cpal_thread(data, &stream_buffer)
+                    cpal_thread(data, &stream_buffer, layout)
                 },
                 err_fn,
                 None,
             ),
-            sf => panic!("Unsupported sample format {}", sf),
+            sf => return Err(format!("unsupported sample format {}", sf)),
         }
-        .unwrap();
+        .map_err(|e| e.to_string())?;
+
+        stream.play().map_err(|e| e.to_string())?;
+
+        Ok((player, stream))
+    }
+}
+
+/// How the stereo output is written to the channels of the audio device
+#[derive(Copy, Clone)]
+struct OutputLayout {
+    channels: usize,
+    // Downmix to mono, always done for devices with a single channel
+    mono: bool,
+}
+
+fn find_output_device(host: &cpal::Host, name: &str) -> Result<cpal::Device, String> {
+    let devices = host.output_devices().map_err(|e| e.to_string())?;
+    let mut found = None;
+    for device in devices {
+        match device.name() {
+            Ok(n) if n == name => return Ok(device),
+            // Also accept a unique part of the name
+            Ok(n) if n.to_lowercase().contains(&name.to_lowercase()) => {
+                found = match found {
+                    None => Some(device),
+                    Some(..) => return Err(format!("more than one device matches '{}'", name)),
+                };
+            }
+            _ => (),
+        }
+    }
+    found.ok_or_else(|| format!("no output device named '{}'", name))
+}
 
-        stream.play().unwrap();
+/// Prefers stereo over mono or surround, and f32 samples over other formats. Rates outside
+/// the range of the config are replaced by the nearest supported rate.
+fn select_output_config(
+    device: &cpal::Device,
+    wanted_rate: u32,
+) -> Result<cpal::SupportedStreamConfig, String> {
+    let configs = device.supported_output_configs().map_err(|e| e.to_string())?;
+    let best = configs
+        .filter(|c| c.channels() > 0)
+        .filter_map(|c| format_rank(c.sample_format()).map(|rank| (rank, c)))
+        .min_by_key(|(rank, c)| {
+            let channel_rank = match c.channels() {
+                2 => 0,
+                1 => 1,
+                n => n,
+            };
+            let rate = wanted_rate.clamp(c.min_sample_rate().0, c.max_sample_rate().0);
+            (channel_rank, rate.abs_diff(wanted_rate), *rank)
+        });
+    match best {
+        Some((_, c)) => {
+            let rate = wanted_rate.clamp(c.min_sample_rate().0, c.max_sample_rate().0);
+            Ok(c.with_sample_rate(cpal::SampleRate(rate)))
+        }
+        None => Err("no supported output configuration".to_owned()),
+    }
+}
+
+// Preference of the sample formats, None for formats that cannot be played
+fn format_rank(format: cpal::SampleFormat) -> Option<u8> {
+    use cpal::SampleFormat::*;
+    [F32, I16, I32, F64, U16, I8, U8, I64, U32, U64]
+        .iter()
+        .position(|&f| f == format)
+        .map(|i| i as u8)
+}
 
-        Some((player, stream))
+fn list_audio_devices() -> i32 {
+    let host = cpal::default_host();
+    let default_name = host.default_output_device().and_then(|d| d.name().ok());
+    let devices = match host.output_devices() {
+        Ok(devices) => devices,
+        Err(e) => {
+            warn(&e.to_string());
+            return EXITCODE_SUCCESS;
+        }
+    };
+    for device in devices {
+        let name = match device.name() {
+            Ok(name) => name,
+            Err(..) => continue,
+        };
+        let marker = if Some(&name) == default_name.as_ref() { " (default)" } else { "" };
+        match device.default_output_config() {
+            Ok(c) => println!(
+                "{}{}: {} channels, {} Hz, {}",
+                name,
+                marker,
+                c.channels(),
+                c.sample_rate().0,
+                c.sample_format()
+            ),
+            Err(..) => println!("{}{}", name, marker),
+        }
     }
+    EXITCODE_SUCCESS
 }
 
 fn cpal_thread<T: Sample + FromSample<f32>>(
     outbuffer: &mut [T],
-    audio_buffer: &Arc<Mutex<Vec<(f32, f32)>>>,
+    audio_ring: &AudioRing,
+    layout: OutputLayout,
 ) {
-    let mut inbuffer = audio_buffer.lock().unwrap();
-    let outlen = ::std::cmp::min(outbuffer.len() / 2, inbuffer.len());
-    for (i, (in_l, in_r)) in inbuffer.drain(..outlen).enumerate() {
-        outbuffer[i * 2] = T::from_sample(in_l);
-        outbuffer[i * 2 + 1] = T::from_sample(in_r);
-    }
+    audio_ring.pop_into(outbuffer, layout);
 }
 
 impl AudioPlayer for CpalPlayer {
     fn play(&mut self, buf_left: &[f32], buf_right: &[f32]) {
         debug_assert!(buf_left.len() == buf_right.len());
 
-        let mut buffer = self.buffer.lock().unwrap();
-
-        for (l, r) in buf_left.iter().zip(buf_right) {
-            if buffer.len() > self.sample_rate as usize {
-                // Do not fill the buffer with more than 1 second of data
-                // This speeds up the resync after the turning on and off the speed limiter
-                return;
-            }
-            buffer.push((*l, *r));
-        }
+        // Emulation is paced by the buffer, so it only overruns when the audio device stopped
+        // playing
+        self.ring.push(buf_left, buf_right);
     }
 
     fn samples_rate(&self) -> u32 {
@@ -455,6 +1546,6 @@ impl AudioPlayer for CpalPlayer {
     }
 
     fn underflowed(&self) -> bool {
-        (*self.buffer.lock().unwrap()).len() == 0
+        self.ring.len() == 0
     }
 }
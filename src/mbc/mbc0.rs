@@ -1,19 +1,19 @@
 use crate::mbc::MBC;
-use crate::StrResult;
+use crate::Result;
 
 pub struct MBC0 {
     rom: Vec<u8>,
 }
 
 impl MBC0 {
-    pub fn new(data: Vec<u8>) -> StrResult<MBC0> {
+    pub fn new(data: Vec<u8>) -> Result<MBC0> {
         Ok(MBC0 { rom: data })
     }
 }
 
 impl MBC for MBC0 {
     fn readrom(&self, a: u16) -> u8 {
-        self.rom[a as usize]
+        self.rom.get(a as usize).copied().unwrap_or(0xFF)
     }
     fn readram(&self, _a: u16) -> u8 {
         0
@@ -28,7 +28,7 @@ impl MBC for MBC0 {
     fn is_battery_backed(&self) -> bool {
         false
     }
-    fn loadram(&mut self, _ramdata: &[u8]) -> StrResult<()> {
+    fn loadram(&mut self, _ramdata: &[u8]) -> Result<()> {
         Ok(())
     }
     fn dumpram(&self) -> Vec<u8> {
@@ -37,4 +37,5 @@ impl MBC for MBC0 {
     fn check_and_reset_ram_updated(&mut self) -> bool {
         false
     }
+    fn reset(&mut self) {}
 }
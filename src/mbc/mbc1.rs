@@ -1,5 +1,5 @@
 use crate::mbc::{ram_banks, rom_banks, MBC};
-use crate::StrResult;
+use crate::{Error, Result};
 
 pub struct MBC1 {
     rom: Vec<u8>,
@@ -15,7 +15,7 @@ pub struct MBC1 {
 }
 
 impl MBC1 {
-    pub fn new(data: Vec<u8>) -> StrResult<MBC1> {
+    pub fn new(data: Vec<u8>) -> Result<MBC1> {
         let (has_battery, rambanks) = match data[0x147] {
             0x02 => (false, ram_banks(data[0x149])),
             0x03 => (true, ram_banks(data[0x149])),
@@ -64,7 +64,8 @@ impl MBC for MBC1 {
         } else {
             0
         };
-        self.ram[(rambank * 0x2000) | ((a & 0x1FFF) as usize)]
+        let address = (rambank * 0x2000) | ((a & 0x1FFF) as usize);
+        self.ram.get(address).copied().unwrap_or(0xFF)
     }
 
     fn writerom(&mut self, a: u16, v: u8) {
@@ -91,7 +92,7 @@ impl MBC for MBC1 {
             0x6000..=0x7FFF => {
                 self.banking_mode = v & 0x01;
             }
-            _ => panic!("Could not write to {:04X} (MBC1)", a),
+            _ => {}
         }
     }
 
@@ -115,9 +116,12 @@ impl MBC for MBC1 {
         self.has_battery
     }
 
-    fn loadram(&mut self, ramdata: &[u8]) -> StrResult<()> {
+    fn loadram(&mut self, ramdata: &[u8]) -> Result<()> {
         if ramdata.len() != self.ram.len() {
-            return Err("Loaded RAM has incorrect length");
+            return Err(Error::InvalidSaveSize {
+                expected: self.ram.len(),
+                found: ramdata.len(),
+            });
         }
 
         self.ram = ramdata.to_vec();
@@ -134,4 +138,11 @@ impl MBC for MBC1 {
         self.ram_updated = false;
         result
     }
+
+    fn reset(&mut self) {
+        self.ram_on = false;
+        self.banking_mode = 0;
+        self.rombank = 1;
+        self.rambank = 0;
+    }
 }
@@ -1,5 +1,5 @@
 use crate::mbc::{rom_banks, MBC};
-use crate::StrResult;
+use crate::{Error, Result};
 
 pub struct MBC2 {
     rom: Vec<u8>,
@@ -12,7 +12,7 @@ pub struct MBC2 {
 }
 
 impl MBC2 {
-    pub fn new(data: Vec<u8>) -> StrResult<MBC2> {
+    pub fn new(data: Vec<u8>) -> Result<MBC2> {
         let has_battery = match data[0x147] {
             0x06 => true,
             _ => false,
@@ -74,9 +74,12 @@ impl MBC for MBC2 {
         self.has_battery
     }
 
-    fn loadram(&mut self, ramdata: &[u8]) -> StrResult<()> {
+    fn loadram(&mut self, ramdata: &[u8]) -> Result<()> {
         if ramdata.len() != self.ram.len() {
-            return Err("Loaded RAM has incorrect length");
+            return Err(Error::InvalidSaveSize {
+                expected: self.ram.len(),
+                found: ramdata.len(),
+            });
         }
 
         self.ram = ramdata.to_vec();
@@ -93,4 +96,9 @@ impl MBC for MBC2 {
         self.ram_updated = false;
         result
     }
+
+    fn reset(&mut self) {
+        self.ram_on = false;
+        self.rombank = 1;
+    }
 }
@@ -1,5 +1,5 @@
 use crate::mbc::{ram_banks, MBC};
-use crate::StrResult;
+use crate::{Error, Result};
 
 use std::convert::TryInto;
 use std::io::prelude::*;
@@ -22,7 +22,7 @@ pub struct MBC3 {
 }
 
 impl MBC3 {
-    pub fn new(data: Vec<u8>) -> StrResult<MBC3> {
+    pub fn new(data: Vec<u8>) -> Result<MBC3> {
         let subtype = data[0x147];
         let has_battery = match subtype {
             0x0F | 0x10 | 0x13 => true,
@@ -67,8 +67,11 @@ impl MBC3 {
             return;
         }
 
+        // A start time that does not fit in SystemTime, from a corrupt save, counts from now
         let tzero = match self.rtc_zero {
-            Some(t) => time::UNIX_EPOCH + time::Duration::from_secs(t),
+            Some(t) => time::UNIX_EPOCH
+                .checked_add(time::Duration::from_secs(t))
+                .unwrap_or_else(time::SystemTime::now),
             None => return,
         };
 
@@ -97,16 +100,17 @@ impl MBC3 {
         if self.rtc_zero.is_none() {
             return None;
         }
-        let mut difftime = match time::SystemTime::now().duration_since(time::UNIX_EPOCH) {
+        // A system clock set before the unix epoch is treated as the epoch itself
+        let now = match time::SystemTime::now().duration_since(time::UNIX_EPOCH) {
             Ok(t) => t.as_secs(),
-            Err(_) => panic!("System clock is set to a time before the unix epoch (1970-01-01)"),
+            Err(_) => 0,
         };
-        difftime -= self.rtc_ram[0] as u64;
-        difftime -= (self.rtc_ram[1] as u64) * 60;
-        difftime -= (self.rtc_ram[2] as u64) * 3600;
         let days = ((self.rtc_ram[4] as u64 & 0x1) << 8) | (self.rtc_ram[3] as u64);
-        difftime -= days * 3600 * 24;
-        Some(difftime)
+        let elapsed = self.rtc_ram[0] as u64
+            + (self.rtc_ram[1] as u64) * 60
+            + (self.rtc_ram[2] as u64) * 3600
+            + days * 3600 * 24;
+        Some(now.saturating_sub(elapsed))
     }
 
     fn calc_rtc_zero(&mut self) {
@@ -149,7 +153,7 @@ impl MBC for MBC3 {
                 self.rambank = (v & 0x7) as usize;
             }
             0x6000..=0x7FFF => self.latch_rtc_reg(),
-            _ => panic!("Could not write to {:04X} (MBC3)", a),
+            _ => {}
         }
     }
     fn writeram(&mut self, a: u16, v: u8) {
@@ -177,9 +181,12 @@ impl MBC for MBC3 {
         self.has_battery
     }
 
-    fn loadram(&mut self, ramdata: &[u8]) -> StrResult<()> {
+    fn loadram(&mut self, ramdata: &[u8]) -> Result<()> {
         if ramdata.len() != 8 + self.ram.len() {
-            return Err("Loaded ram is too small");
+            return Err(Error::InvalidSaveSize {
+                expected: 8 + self.ram.len(),
+                found: ramdata.len(),
+            });
         }
 
         let (int_bytes, rest) = ramdata.split_at(8);
@@ -216,4 +223,12 @@ impl MBC for MBC3 {
         self.ram_updated = false;
         result
     }
+
+    fn reset(&mut self) {
+        // The RTC keeps running, only the mapper registers are reset
+        self.ram_on = false;
+        self.selectrtc = false;
+        self.rombank = 1;
+        self.rambank = 0;
+    }
 }
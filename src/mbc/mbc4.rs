@@ -1,5 +1,5 @@
 use crate::mbc::{ram_banks, rom_banks, MBC};
-use crate::StrResult;
+use crate::{Error, Result};
 
 pub struct MBC4 {
     rom: Vec<u8>,
@@ -14,7 +14,7 @@ pub struct MBC4 {
 }
 
 impl MBC4 {
-    pub fn new(data: Vec<u8>) -> StrResult<MBC4> {
+    pub fn new(data: Vec<u8>) -> Result<MBC4> {
         let subtype = data[0x147];
         let has_battery = matches!(subtype, 0x1B | 0x1E);
         let rambanks = match subtype {
@@ -54,7 +54,8 @@ impl MBC for MBC4 {
         if !self.ram_on {
             return 0;
         }
-        self.ram[self.rambank * 0x2000 | ((a as usize) & 0x1FFF)]
+        let address = self.rambank * 0x2000 | ((a as usize) & 0x1FFF);
+        self.ram.get(address).copied().unwrap_or(0xFF)
     }
 
     fn writerom(&mut self, a: u16, v: u8) {
@@ -66,9 +67,11 @@ impl MBC for MBC4 {
             0x3000..=0x3FFF => {
                 self.rombank = ((self.rombank & 0x0FF) | (((v & 0x1) as usize) << 8)) % self.rombanks
             }
-            0x4000..=0x5FFF => self.rambank = ((v & 0x0F) as usize) % self.rambanks,
+            0x4000..=0x5FFF if self.rambanks > 0 => {
+                self.rambank = ((v & 0x0F) as usize) % self.rambanks
+            }
             0x6000..=0x7FFF => { /* unused */ }
-            _ => panic!("Could not write to {:04X} (MBC4)", a),
+            _ => {}
         }
     }
 
@@ -76,17 +79,23 @@ impl MBC for MBC4 {
         if !self.ram_on {
             return;
         }
-        self.ram[self.rambank * 0x2000 | ((a as usize) & 0x1FFF)] = v;
-        self.ram_updated = true;
+        let address = self.rambank * 0x2000 | ((a as usize) & 0x1FFF);
+        if let Some(value) = self.ram.get_mut(address) {
+            *value = v;
+            self.ram_updated = true;
+        }
     }
 
     fn is_battery_backed(&self) -> bool {
         self.has_battery
     }
 
-    fn loadram(&mut self, ramdata: &[u8]) -> StrResult<()> {
+    fn loadram(&mut self, ramdata: &[u8]) -> Result<()> {
         if ramdata.len() != self.ram.len() {
-            return Err("Loaded RAM has incorrect length");
+            return Err(Error::InvalidSaveSize {
+                expected: self.ram.len(),
+                found: ramdata.len(),
+            });
         }
         self.ram = ramdata.to_vec();
         Ok(())
@@ -101,4 +110,10 @@ impl MBC for MBC4 {
         self.ram_updated = false;
         res
     }
+
+    fn reset(&mut self) {
+        self.ram_on = false;
+        self.rombank = 1;
+        self.rambank = 0;
+    }
 }
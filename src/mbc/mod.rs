@@ -1,4 +1,4 @@
-use crate::StrResult;
+use crate::{Error, Result};
 use std::fs::{self, File};
 use std::io;
 use std::io::prelude::*;
@@ -16,9 +16,10 @@ pub trait MBC: Send {
     fn writerom(&mut self, a: u16, v: u8);
     fn writeram(&mut self, a: u16, v: u8);
     fn check_and_reset_ram_updated(&mut self) -> bool;
+    fn reset(&mut self);
 
     fn is_battery_backed(&self) -> bool;
-    fn loadram(&mut self, ramdata: &[u8]) -> StrResult<()>;
+    fn loadram(&mut self, ramdata: &[u8]) -> Result<()>;
     fn dumpram(&self) -> Vec<u8>;
 
     fn romname(&self) -> String {
@@ -43,20 +44,24 @@ pub trait MBC: Send {
     }
 }
 
-pub fn get_mbc(data: Vec<u8>, skip_checksum: bool) -> StrResult<Box<dyn MBC + 'static>> {
+pub fn get_mbc(data: Vec<u8>, skip_checksum: bool) -> Result<Box<dyn MBC + 'static>> {
     if data.len() < 0x150 {
-        return Err("Rom size to small");
+        return Err(Error::RomTooSmall { size: data.len() });
     }
     if !skip_checksum {
         check_checksum(&data)?;
     }
+    // The bank number written by the game is reduced modulo the bank count
+    if rom_banks(data[0x148]) == 0 {
+        return Err(Error::InvalidRomSize { value: data[0x148] });
+    }
     match data[0x147] {
         0x00 => mbc0::MBC0::new(data).map(|v| Box::new(v) as Box<dyn MBC>),
         0x01..=0x03 => mbc1::MBC1::new(data).map(|v| Box::new(v) as Box<dyn MBC>),
         0x05..=0x06 => mbc2::MBC2::new(data).map(|v| Box::new(v) as Box<dyn MBC>),
         0x0F..=0x13 => mbc3::MBC3::new(data).map(|v| Box::new(v) as Box<dyn MBC>),
         0x19..=0x1E => mbc4::MBC4::new(data).map(|v| Box::new(v) as Box<dyn MBC>),
-        _ => Err("Unsupported MBC type"),
+        cartridge_type => Err(Error::UnsupportedCartridge { cartridge_type }),
     }
 }
 
@@ -66,12 +71,18 @@ pub struct FileBackedMBC {
 }
 
 impl FileBackedMBC {
-    pub fn new(rompath: path::PathBuf, skip_checksum: bool) -> StrResult<FileBackedMBC> {
+    pub fn new(rompath: path::PathBuf, skip_checksum: bool) -> Result<FileBackedMBC> {
         let mut data = vec![];
         File::open(&rompath)
             .and_then(|mut f| f.read_to_end(&mut data))
-            .map_err(|_| "Could not read ROM")?;
-        let mut mbc = get_mbc(data, skip_checksum)?;
+            .map_err(|source| Error::Io {
+                path: rompath.clone(),
+                source,
+            })?;
+        let mut mbc = get_mbc(data, skip_checksum).map_err(|e| Error::Rom {
+            path: rompath.clone(),
+            source: Box::new(e),
+        })?;
 
         let rampath = rompath.with_extension("gbsave");
 
@@ -80,19 +91,43 @@ impl FileBackedMBC {
                 Ok(mut file) => {
                     let mut ramdata: Vec<u8> = vec![];
                     match file.read_to_end(&mut ramdata) {
-                        Err(..) => return Err("Error while reading existing save file"),
+                        Err(source) => {
+                            return Err(Error::Io {
+                                path: rampath,
+                                source,
+                            })
+                        }
                         Ok(..) => {
-                            mbc.loadram(&ramdata)?;
+                            mbc.loadram(&ramdata).map_err(|e| Error::Rom {
+                                path: rampath.clone(),
+                                source: Box::new(e),
+                            })?;
                         }
                     }
                 }
                 Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
-                Err(_) => return Err("Error loading existing save file"),
+                Err(source) => {
+                    return Err(Error::Io {
+                        path: rampath,
+                        source,
+                    })
+                }
             }
         }
 
         Ok(FileBackedMBC { rampath, mbc })
     }
+
+    /// Writes the battery backed RAM to the save file
+    pub fn save(&self) -> Result<()> {
+        if !self.mbc.is_battery_backed() {
+            return Ok(());
+        }
+        fs::write(&self.rampath, self.mbc.dumpram()).map_err(|source| Error::Io {
+            path: self.rampath.clone(),
+            source,
+        })
+    }
 }
 
 // Implement MBC for FileBackedMBC such that the MMU can use this transparently
@@ -117,7 +152,7 @@ impl MBC for FileBackedMBC {
         self.mbc.is_battery_backed()
     }
 
-    fn loadram(&mut self, ramdata: &[u8]) -> StrResult<()> {
+    fn loadram(&mut self, ramdata: &[u8]) -> Result<()> {
         self.mbc.loadram(ramdata)
     }
 
@@ -128,18 +163,16 @@ impl MBC for FileBackedMBC {
     fn check_and_reset_ram_updated(&mut self) -> bool {
         self.mbc.check_and_reset_ram_updated()
     }
+
+    fn reset(&mut self) {
+        self.mbc.reset()
+    }
 }
 
 impl Drop for FileBackedMBC {
     fn drop(&mut self) {
-        if self.mbc.is_battery_backed() {
-            // TODO: error handling
-            let mut file = match fs::File::create(&self.rampath) {
-                Ok(f) => f,
-                Err(..) => return,
-            };
-            let _ = file.write_all(&self.mbc.dumpram());
-        }
+        // Errors can not be reported from drop, hosts wanting to know should call save first
+        let _ = self.save();
     }
 }
 
@@ -168,19 +201,24 @@ fn rom_banks(v: u8) -> usize {
     }
 }
 
-fn check_checksum(data: &[u8]) -> StrResult<()> {
+fn check_checksum(data: &[u8]) -> Result<()> {
     let mut value: u8 = 0;
     for i in 0x134..0x14D {
         value = value.wrapping_sub(data[i]).wrapping_sub(1);
     }
     match data[0x14D] == value {
         true => Ok(()),
-        false => Err("Cartridge checksum is invalid"),
+        false => Err(Error::InvalidChecksum {
+            expected: value,
+            found: data[0x14D],
+        }),
     }
 }
 
 #[cfg(test)]
 mod test {
+    use crate::Error;
+
     #[test]
     fn checksum_zero() {
         let mut data = vec![0; 0x150];
@@ -194,4 +232,74 @@ mod test {
         data[0x14D] = (-(0x14D_i32 - 0x134_i32) * 2) as u8;
         super::check_checksum(&data).unwrap();
     }
+
+    #[test]
+    fn checksum_invalid() {
+        let mut data = vec![0; 0x150];
+        data[0x14D] = 0x12;
+        match super::check_checksum(&data) {
+            Err(Error::InvalidChecksum { expected, found }) => {
+                assert_eq!(expected, 0xE7);
+                assert_eq!(found, 0x12);
+            }
+            _ => panic!("expected an invalid checksum error"),
+        }
+    }
+
+    #[test]
+    fn unsupported_cartridge() {
+        let mut data = vec![0; 0x150];
+        data[0x147] = 0xFC;
+        match super::get_mbc(data, true) {
+            Err(Error::UnsupportedCartridge { cartridge_type }) => assert_eq!(cartridge_type, 0xFC),
+            _ => panic!("expected an unsupported cartridge error"),
+        }
+    }
+
+    #[test]
+    fn invalid_rom_size() {
+        let mut data = vec![0; 0x150];
+        data[0x147] = 0x01;
+        data[0x148] = 0x52;
+        match super::get_mbc(data, true) {
+            Err(Error::InvalidRomSize { value }) => assert_eq!(value, 0x52),
+            _ => panic!("expected an invalid ROM size error"),
+        }
+    }
+
+    #[test]
+    fn missing_memory() {
+        // A ROM only image shorter than 32 KiB
+        let mbc = super::get_mbc(vec![0; 0x150], true).unwrap();
+        assert_eq!(mbc.readrom(0x7FFF), 0xFF);
+
+        // Cartridges without RAM, accessed as if they had some
+        for cartridge_type in [0x01, 0x02, 0x19, 0x1C] {
+            let mut data = vec![0; 0x8000];
+            data[0x147] = cartridge_type;
+            let mut mbc = super::get_mbc(data, true).unwrap();
+            mbc.writerom(0x0000, 0x0A);
+            mbc.writerom(0x4000, 0x01);
+            mbc.writerom(0x2000, 0x03);
+            mbc.writeram(0xA000, 0x12);
+            assert_eq!(mbc.readram(0xA000), 0xFF);
+        }
+    }
+
+    #[test]
+    fn corrupt_rtc_save() {
+        // MBC3 with timer and 8 KiB RAM
+        let mut data = vec![0; 0x8000];
+        data[0x147] = 0x10;
+        data[0x149] = 0x02;
+        let mut mbc = super::get_mbc(data, true).unwrap();
+        let mut save = vec![0xFF; 8];
+        save.extend_from_slice(&[0; 0x2000]);
+        mbc.loadram(&save).unwrap();
+        // Latches the clock, which is computed from the start time in the save
+        mbc.writerom(0x0000, 0x0A);
+        mbc.writerom(0x4000, 0x08);
+        mbc.writerom(0x6000, 0x01);
+        assert!(mbc.readram(0xA000) < 60);
+    }
 }
@@ -2,9 +2,10 @@ use crate::gbmode::{GbMode, GbSpeed};
 use crate::gpu::GPU;
 use crate::keypad::Keypad;
 use crate::mbc;
+use crate::palette::DmgPalette;
 use crate::sound::Sound;
 use crate::timer::Timer;
-use crate::StrResult;
+use crate::{Error, Result};
 
 const WRAM_SIZE: usize = 0x8000;
 const ZRAM_SIZE: usize = 0x7F;
@@ -38,6 +39,41 @@ pub struct MMU {
     undocumented_cgb_regs: [u8; 3], // 0xFF72, 0xFF73, 0xFF75
 }
 
+/// Contents of a RAM chip after power on
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RamPattern {
+    Zero,
+    Fill(u8),
+    /// Pseudo random contents, the same seed always results in the same contents
+    Random { seed: u32 },
+}
+
+impl RamPattern {
+    pub(crate) fn apply(self, slice: &mut [u8]) {
+        match self {
+            RamPattern::Zero => slice.fill(0),
+            RamPattern::Fill(value) => slice.fill(value),
+            RamPattern::Random { seed } => fill_random(slice, seed),
+        }
+    }
+}
+
+/// Initial contents of WRAM and VRAM used when powering on
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct MemoryInit {
+    pub wram: RamPattern,
+    pub vram: RamPattern,
+}
+
+impl Default for MemoryInit {
+    fn default() -> MemoryInit {
+        MemoryInit {
+            wram: RamPattern::Random { seed: 42 },
+            vram: RamPattern::Zero,
+        }
+    }
+}
+
 fn fill_random(slice: &mut [u8], start: u32) {
     // Simple LCG to generate (non-cryptographic) random values
     // Each distinct invocation should use a different start value
@@ -54,7 +90,7 @@ fn fill_random(slice: &mut [u8], start: u32) {
 impl MMU {
     pub fn new(
         cart: Box<dyn mbc::MBC + 'static>,
-    ) -> StrResult<MMU> {
+    ) -> Result<MMU> {
         let mut res = MMU {
             wram: [0; WRAM_SIZE],
             zram: [0; ZRAM_SIZE],
@@ -76,9 +112,9 @@ impl MMU {
             hdma_len: 0xFF,
             undocumented_cgb_regs: [0; 3],
         };
-        fill_random(&mut res.wram, 42);
+        MemoryInit::default().wram.apply(&mut res.wram);
         if res.rb(0x0143) == 0xC0 {
-            return Err("This game does not work in Classic mode");
+            return Err(Error::CgbOnly);
         }
         res.set_initial();
         Ok(res)
@@ -86,7 +122,7 @@ impl MMU {
 
     pub fn new_cgb(
         cart: Box<dyn mbc::MBC + 'static>,
-    ) -> StrResult<MMU> {
+    ) -> Result<MMU> {
         let mut res = MMU {
             wram: [0; WRAM_SIZE],
             zram: [0; ZRAM_SIZE],
@@ -108,12 +144,66 @@ impl MMU {
             hdma_len: 0xFF,
             undocumented_cgb_regs: [0; 3],
         };
-        fill_random(&mut res.wram, 42);
+        MemoryInit::default().wram.apply(&mut res.wram);
         res.determine_mode();
         res.set_initial();
         Ok(res)
     }
 
+    pub fn new_sgb(
+        cart: Box<dyn mbc::MBC + 'static>,
+    ) -> Result<MMU> {
+        let mut res = MMU::new(cart)?;
+        res.gbmode = GbMode::Sgb;
+        res.gpu = GPU::new_sgb();
+        res.keypad = res.new_keypad();
+        res.set_initial();
+        Ok(res)
+    }
+
+    fn new_keypad(&self) -> Keypad {
+        // The SGB only listens to command packets of games which declare SGB support
+        if self.gbmode == GbMode::Sgb && self.mbc.readrom(0x0146) == 0x03
+            && self.mbc.readrom(0x014B) == 0x33 {
+            Keypad::new_sgb()
+        } else {
+            Keypad::new()
+        }
+    }
+
+    /// Puts all I/O back into the post-boot state. Memory contents are kept.
+    pub fn reset(&mut self) {
+        self.hdma = [0; 4];
+        self.inte = 0;
+        self.intf = 0;
+        self.timer = Timer::new();
+        self.keypad = self.new_keypad();
+        self.gpu.reset();
+        if let Some(sound) = self.sound.as_mut() {
+            sound.reset();
+            // Turn the APU on first, so the initial register values are not ignored
+            sound.set_on();
+        }
+        self.hdma_status = DMAType::NoDMA;
+        self.hdma_src = 0;
+        self.hdma_dst = 0;
+        self.hdma_len = 0xFF;
+        self.wrambank = 1;
+        self.gbspeed = GbSpeed::Single;
+        self.speed_switch_req = false;
+        self.undocumented_cgb_regs = [0; 3];
+        self.mbc.reset();
+        self.set_initial();
+    }
+
+    /// Like reset, but also reinitializes the volatile memory. Cartridge RAM is kept.
+    pub fn power_cycle(&mut self, init: MemoryInit) {
+        self.reset();
+        init.wram.apply(&mut self.wram);
+        self.zram = [0; ZRAM_SIZE];
+        self.gpu.power_cycle(init.vram);
+    }
+
     fn set_initial(&mut self) {
         self.wb(0xFF05, 0);
         self.wb(0xFF06, 0);
@@ -155,6 +245,16 @@ impl MMU {
         };
         self.gbmode = mode;
         self.gpu.gbmode = mode;
+        if mode == GbMode::ColorAsClassic {
+            // Like the CGB boot ROM, colourize classic games
+            self.set_dmg_palette(DmgPalette::CgbAuto);
+        }
+    }
+
+    /// Selects the colours of classic games, in classic mode and when run on a CGB
+    pub fn set_dmg_palette(&mut self, palette: DmgPalette) {
+        let palette = palette.resolve(&*self.mbc);
+        self.gpu.set_dmg_palette(palette);
     }
 
     pub fn do_cycle(&mut self, ticks: u32) -> u32 {
@@ -164,14 +264,13 @@ impl MMU {
         let cputicks = ticks + vramticks * cpudivider;
 
         self.timer.do_cycle(cputicks);
-        self.intf |= self.timer.interrupt;
+        self.gpu.do_cycle(gputicks);
+        // Placed on the timeline at the end of the step, when the GPU has caught up
+        let raised = self.timer.interrupt | self.keypad.interrupt | self.gpu.interrupt;
+        self.gpu.record_interrupts(raised);
+        self.intf |= raised;
         self.timer.interrupt = 0;
-
-        self.intf |= self.keypad.interrupt;
         self.keypad.interrupt = 0;
-
-        self.gpu.do_cycle(gputicks);
-        self.intf |= self.gpu.interrupt;
         self.gpu.interrupt = 0;
 
         let _ = self.sound.as_mut().map_or((), |s| s.do_cycle(gputicks));
@@ -179,6 +278,24 @@ impl MMU {
         return gputicks;
     }
 
+    pub fn do_cycle_speed_switch(&mut self, ticks: u32) -> u32 {
+        // Like do_cycle, but with the timer and VRAM DMA frozen
+        let gputicks = ticks / self.gbspeed as u32;
+
+        self.gpu.do_cycle(gputicks);
+        let raised = self.keypad.interrupt | self.gpu.interrupt;
+        self.gpu.record_interrupts(raised);
+        self.intf |= raised;
+        self.keypad.interrupt = 0;
+        self.gpu.interrupt = 0;
+
+        if let Some(sound) = self.sound.as_mut() {
+            sound.do_cycle(gputicks);
+        }
+
+        gputicks
+    }
+
     pub fn rb(&mut self, address: u16) -> u8 {
         match address {
             0x0000..=0x7FFF => self.mbc.readrom(address),
@@ -196,7 +313,8 @@ impl MMU {
             0xFF4D | 0xFF4F | 0xFF51..=0xFF55 | 0xFF6C | 0xFF70 if self.gbmode != GbMode::Color => {
                 0xFF
             }
-            0xFF72..=0xFF73 | 0xFF75..=0xFF77 if self.gbmode == GbMode::Classic => 0xFF,
+            0xFF72..=0xFF73 | 0xFF75..=0xFF77
+                if matches!(self.gbmode, GbMode::Classic | GbMode::Sgb) => 0xFF,
             0xFF4D => {
                 0b01111110
                     | (if self.gbspeed == GbSpeed::Double {
@@ -212,7 +330,7 @@ impl MMU {
             0xFF70 => self.wrambank as u8,
             0xFF72..=0xFF73 => self.undocumented_cgb_regs[address as usize - 0xFF72],
             0xFF75 => self.undocumented_cgb_regs[2] | 0b10001111,
-            0xFF76..=0xFF77 => 0x00, // CGB PCM registers. Not yet implemented.
+            0xFF76..=0xFF77 => self.sound.as_mut().map_or(0x00, |s| s.rb(address)),
             0xFF80..=0xFFFE => self.zram[address as usize & 0x007F],
             0xFFFF => self.inte,
             _ => 0xFF,
@@ -220,7 +338,7 @@ impl MMU {
     }
 
     pub fn rw(&mut self, address: u16) -> u16 {
-        (self.rb(address) as u16) | ((self.rb(address + 1) as u16) << 8)
+        (self.rb(address) as u16) | ((self.rb(address.wrapping_add(1)) as u16) << 8)
     }
 
     pub fn wb(&mut self, address: u16, value: u8) {
@@ -233,13 +351,19 @@ impl MMU {
                 self.wram[(self.wrambank * 0x1000) | (address as usize & 0x0FFF)] = value
             }
             0xFE00..=0xFE9F => self.gpu.wb(address, value),
-            0xFF00 => self.keypad.wb(value),
+            0xFF00 => {
+                self.keypad.wb(value);
+                if let Some(command) = self.keypad.take_sgb_command() {
+                    self.gpu.sgb_command(&command);
+                }
+            }
             0xFF04..=0xFF07 => self.timer.wb(address, value),
             0xFF10..=0xFF3F => self.sound.as_mut().map_or((), |s| s.wb(address, value)),
             0xFF46 => self.oamdma(value),
             0xFF4D | 0xFF4F | 0xFF51..=0xFF55 | 0xFF6C | 0xFF70 | 0xFF76..=0xFF77
                 if self.gbmode != GbMode::Color => {}
-            0xFF72..=0xFF73 | 0xFF75..=0xFF77 if self.gbmode == GbMode::Classic => {}
+            0xFF72..=0xFF73 | 0xFF75..=0xFF77
+                if matches!(self.gbmode, GbMode::Classic | GbMode::Sgb) => {}
             0xFF4D => {
                 if value & 0x1 == 0x1 {
                     self.speed_switch_req = true;
@@ -265,10 +389,11 @@ impl MMU {
 
     pub fn ww(&mut self, address: u16, value: u16) {
         self.wb(address, (value & 0xFF) as u8);
-        self.wb(address + 1, (value >> 8) as u8);
+        self.wb(address.wrapping_add(1), (value >> 8) as u8);
     }
 
-    pub fn switch_speed(&mut self) {
+    pub fn switch_speed(&mut self) -> bool {
+        let switched = self.speed_switch_req;
         if self.speed_switch_req {
             if self.gbspeed == GbSpeed::Double {
                 self.gbspeed = GbSpeed::Single;
@@ -277,6 +402,7 @@ impl MMU {
             }
         }
         self.speed_switch_req = false;
+        switched
     }
 
     fn oamdma(&mut self, value: u8) {
@@ -298,7 +424,7 @@ impl MMU {
                         0
                     }
             }
-            _ => panic!("The address {:04X} should not be handled by hdma_read", a),
+            _ => 0xFF,
         }
     }
 
@@ -317,9 +443,6 @@ impl MMU {
                 }
                 let src = ((self.hdma[0] as u16) << 8) | (self.hdma[1] as u16);
                 let dst = ((self.hdma[2] as u16) << 8) | (self.hdma[3] as u16) | 0x8000;
-                if !(src <= 0x7FF0 || (src >= 0xA000 && src <= 0xDFF0)) {
-                    panic!("HDMA transfer with illegal start address {:04X}", src);
-                }
 
                 self.hdma_src = src;
                 self.hdma_dst = dst;
@@ -331,10 +454,20 @@ impl MMU {
                     DMAType::GDMA
                 };
             }
-            _ => panic!("The address {:04X} should not be handled by hdma_write", a),
+            _ => {}
         };
     }
 
+    fn hdma_rb(&mut self, address: u16) -> u8 {
+        match address {
+            // VRAM can not be used as a source, the transfer reads open bus
+            0x8000..=0x9FFF => 0xFF,
+            // Sources beyond WRAM access the cartridge RAM area instead
+            0xE000..=0xFFFF => self.rb(address - 0x4000),
+            _ => self.rb(address),
+        }
+    }
+
     fn perform_vramdma(&mut self) -> u32 {
         match self.hdma_status {
             DMAType::NoDMA => 0,
@@ -369,11 +502,12 @@ impl MMU {
     fn perform_vramdma_row(&mut self) {
         let mmu_src = self.hdma_src;
         for j in 0..0x10 {
-            let b: u8 = self.rb(mmu_src + j);
+            let b: u8 = self.hdma_rb(mmu_src.wrapping_add(j));
             self.gpu.wb(self.hdma_dst + j, b);
         }
-        self.hdma_src += 0x10;
-        self.hdma_dst += 0x10;
+        self.hdma_src = self.hdma_src.wrapping_add(0x10);
+        // The destination wraps around within VRAM
+        self.hdma_dst = 0x8000 | (self.hdma_dst.wrapping_add(0x10) & 0x1FF0);
 
         if self.hdma_len == 0 {
             self.hdma_len = 0x7F;
@@ -0,0 +1,228 @@
+use crate::mbc::MBC;
+
+/// Colours of the four shades of classic games, from lightest to darkest.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DmgPalette {
+    /// Neutral greys
+    Grey,
+    /// The green screen of the original Game Boy
+    Green,
+    /// The screen of the Game Boy Pocket
+    Pocket,
+    /// The backlit screen of the Game Boy Light
+    Light,
+    /// RGB triples, from lightest to darkest
+    Custom([[u8; 3]; 4]),
+    /// The colours the CGB boot ROM selects for the game, based on the checksum of its title
+    CgbAuto,
+    /// One of the 51 palette combinations of the CGB boot ROM, by ID. The boot ROM lets the
+    /// player choose some of them with a button combination, see `CGB_PALETTE_BUTTONS`.
+    Cgb(u8),
+}
+
+/// Number of palette combinations in the CGB boot ROM
+pub const CGB_PALETTE_COUNT: u8 = 51;
+
+/// The palette IDs selected by holding a direction and optionally A or B during the CGB boot
+/// animation, in the order Up, Down, Left, Right, each alone, with A and with B.
+pub const CGB_PALETTE_BUTTONS: [(&str, u8); 12] = [
+    ("up", 5),
+    ("up+a", 43),
+    ("up+b", 28),
+    ("down", 8),
+    ("down+a", 3),
+    ("down+b", 49),
+    ("left", 48),
+    ("left+a", 40),
+    ("left+b", 7),
+    ("right", 1),
+    ("right+a", 0),
+    ("right+b", 6),
+];
+
+// Colours of the BG, OBJ0 and OBJ1 palettes
+pub(crate) type DmgColors = [[[u8; 3]; 4]; 3];
+
+const GREY: [[u8; 3]; 4] = [[255, 255, 255], [192, 192, 192], [96, 96, 96], [0, 0, 0]];
+const GREEN: [[u8; 3]; 4] = [
+    [0x9B, 0xBC, 0x0F],
+    [0x8B, 0xAC, 0x0F],
+    [0x30, 0x62, 0x30],
+    [0x0F, 0x38, 0x0F],
+];
+const POCKET: [[u8; 3]; 4] = [
+    [0xC4, 0xCF, 0xA1],
+    [0x8B, 0x95, 0x6D],
+    [0x4D, 0x53, 0x3C],
+    [0x1F, 0x1F, 0x1F],
+];
+const LIGHT: [[u8; 3]; 4] = [
+    [0x00, 0xB5, 0x81],
+    [0x00, 0x9A, 0x71],
+    [0x00, 0x69, 0x4A],
+    [0x00, 0x4F, 0x3B],
+];
+
+impl DmgPalette {
+    /// Replaces `CgbAuto` by the palette the boot ROM would select for the cartridge
+    pub(crate) fn resolve(self, cart: &dyn MBC) -> DmgPalette {
+        match self {
+            DmgPalette::CgbAuto => DmgPalette::Cgb(boot_rom_palette_id(cart)),
+            palette => palette,
+        }
+    }
+
+    /// The RGB colours of the BG, OBJ0 and OBJ1 palettes. CGB colours are converted with
+    /// `cgb_color`, so they look like the colours games set in CGB mode.
+    pub(crate) fn colors(self, cgb_color: impl Fn(u16) -> [u8; 3]) -> DmgColors {
+        let colors = match self {
+            DmgPalette::Grey => GREY,
+            DmgPalette::Green => GREEN,
+            DmgPalette::Pocket => POCKET,
+            DmgPalette::Light => LIGHT,
+            DmgPalette::Custom(colors) => colors,
+            DmgPalette::CgbAuto => return DmgPalette::Cgb(0).colors(cgb_color),
+            DmgPalette::Cgb(id) => {
+                let offsets = PALETTE_COMBINATIONS[(id % CGB_PALETTE_COUNT) as usize];
+                let palette = |offset: u8| {
+                    std::array::from_fn(|i| cgb_color(BOOT_ROM_COLORS[offset as usize + i]))
+                };
+                // The table lists OBJ0, OBJ1 and BG
+                return [
+                    palette(offsets[2]),
+                    palette(offsets[0]),
+                    palette(offsets[1]),
+                ];
+            }
+        };
+        [colors; 3]
+    }
+}
+
+/// Selects the palette like the CGB boot ROM does. Only games published by Nintendo are
+/// looked up, everything else gets the default palette.
+fn boot_rom_palette_id(cart: &dyn MBC) -> u8 {
+    let old_licensee = cart.readrom(0x014B);
+    let nintendo = old_licensee == 0x01
+        || (old_licensee == 0x33 && cart.readrom(0x0144) == b'0' && cart.readrom(0x0145) == b'1');
+    if !nintendo {
+        return 0;
+    }
+
+    let checksum = (0x0134..=0x0143).fold(0u8, |sum, a| sum.wrapping_add(cart.readrom(a)));
+    let fourth_letter = cart.readrom(0x0137);
+    for (i, &v) in TITLE_CHECKSUMS.iter().enumerate() {
+        if v != checksum {
+            continue;
+        }
+        // Some titles share a checksum, these are told apart by their fourth letter
+        if i < FIRST_DUPLICATE || FOURTH_LETTERS[i - FIRST_DUPLICATE] == fourth_letter {
+            return PALETTE_PER_CHECKSUM[i];
+        }
+    }
+    0
+}
+
+// Sums of the title bytes 0134-0143 of the games the boot ROM knows
+#[rustfmt::skip]
+const TITLE_CHECKSUMS: [u8; 94] = [
+    0x00, 0x88, 0x16, 0x36, 0xD1, 0xDB, 0xF2, 0x3C, 0x8C, 0x92, 0x3D, 0x5C, 0x58, 0xC9, 0x3E, 0x70,
+    0x1D, 0x59, 0x69, 0x19, 0x35, 0xA8, 0x14, 0xAA, 0x75, 0x95, 0x99, 0x34, 0x6F, 0x15, 0xFF, 0x97,
+    0x4B, 0x90, 0x17, 0x10, 0x39, 0xF7, 0xF6, 0xA2, 0x49, 0x4E, 0x43, 0x68, 0xE0, 0x8B, 0xF0, 0xCE,
+    0x0C, 0x29, 0xE8, 0xB7, 0x86, 0x9A, 0x52, 0x01, 0x9D, 0x71, 0x9C, 0xBD, 0x5D, 0x6D, 0x67, 0x3F,
+    0x6B, 0xB3, 0x46, 0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D, 0xF4, 0xB3,
+    0x46, 0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D, 0xF4, 0xB3,
+];
+
+// Checksums from this index on are only used when the fourth letter of the title matches
+const FIRST_DUPLICATE: usize = 65;
+const FOURTH_LETTERS: &[u8; 29] = b"BEFAARBEKEK R-URAR INAILICE R";
+
+#[rustfmt::skip]
+const PALETTE_PER_CHECKSUM: [u8; 94] = [
+    0, 4, 5, 35, 34, 3, 31, 15, 10, 5, 19, 36, 7, 37, 30, 44, 21, 32, 31, 20, 5, 33, 13, 14, 5,
+    29, 5, 18, 9, 3, 2, 26, 25, 25, 41, 42, 26, 45, 42, 45, 36, 38, 26, 42, 30, 41, 34, 34, 5,
+    42, 6, 5, 33, 25, 42, 42, 40, 2, 16, 25, 42, 42, 5, 0, 39, 36, 22, 25, 6, 32, 12, 36, 11,
+    39, 18, 39, 24, 31, 50, 17, 46, 6, 27, 0, 47, 41, 41, 0, 0, 19, 34, 23, 18, 29,
+];
+
+// Offsets into BOOT_ROM_COLORS of the OBJ0, OBJ1 and BG palettes. A few combinations start
+// in the middle of a palette, like they do in the boot ROM.
+#[rustfmt::skip]
+const PALETTE_COMBINATIONS: [[u8; 3]; CGB_PALETTE_COUNT as usize] = [
+    [16, 16, 116], [72, 72, 72], [80, 80, 80], [96, 96, 96], [36, 36, 36], [0, 0, 0],
+    [108, 108, 108], [20, 20, 20], [48, 48, 48], [104, 104, 104], [64, 32, 32], [16, 112, 112],
+    [16, 8, 8], [12, 16, 16], [16, 116, 116], [112, 16, 112], [8, 68, 8], [64, 64, 32],
+    [16, 16, 28], [16, 16, 72], [16, 16, 80], [76, 76, 36], [15, 15, 44], [68, 68, 8],
+    [16, 16, 8], [16, 16, 12], [112, 112, 0], [12, 12, 0], [0, 0, 4], [72, 88, 72],
+    [80, 88, 80], [96, 88, 96], [64, 88, 32], [68, 16, 52], [111, 0, 56], [111, 16, 60],
+    [76, 88, 36], [64, 112, 40], [16, 92, 112], [68, 88, 8], [16, 0, 8], [16, 112, 12],
+    [112, 12, 0], [12, 112, 16], [84, 112, 16], [12, 112, 0], [100, 12, 112], [0, 112, 32],
+    [16, 12, 112], [112, 12, 24], [16, 112, 116],
+];
+
+// The palettes of the boot ROM, four colours each in the CGB's BGR555 format
+#[rustfmt::skip]
+const BOOT_ROM_COLORS: [u16; 120] = [
+    0x7FFF, 0x32BF, 0x00D0, 0x0000, 0x639F, 0x4279, 0x15B0, 0x04CB,
+    0x7FFF, 0x6E31, 0x454A, 0x0000, 0x7FFF, 0x1BEF, 0x0200, 0x0000,
+    0x7FFF, 0x421F, 0x1CF2, 0x0000, 0x7FFF, 0x5294, 0x294A, 0x0000,
+    0x7FFF, 0x03FF, 0x012F, 0x0000, 0x7FFF, 0x03EF, 0x01D6, 0x0000,
+    0x7FFF, 0x42B5, 0x3DC8, 0x0000, 0x7E74, 0x03FF, 0x0180, 0x0000,
+    0x67FF, 0x77AC, 0x1A13, 0x2D6B, 0x7ED6, 0x4BFF, 0x2175, 0x0000,
+    0x53FF, 0x4A5F, 0x7E52, 0x0000, 0x4FFF, 0x7ED2, 0x3A4C, 0x1CE0,
+    0x03ED, 0x7FFF, 0x255F, 0x0000, 0x036A, 0x021F, 0x03FF, 0x7FFF,
+    0x7FFF, 0x01DF, 0x0112, 0x0000, 0x231F, 0x035F, 0x00F2, 0x0009,
+    0x7FFF, 0x03EA, 0x011F, 0x0000, 0x299F, 0x001A, 0x000C, 0x0000,
+    0x7FFF, 0x027F, 0x001F, 0x0000, 0x7FFF, 0x03E0, 0x0206, 0x0120,
+    0x7FFF, 0x7EEB, 0x001F, 0x7C00, 0x7FFF, 0x3FFF, 0x7E00, 0x001F,
+    0x7FFF, 0x03FF, 0x001F, 0x0000, 0x03FF, 0x001F, 0x000C, 0x0000,
+    0x7FFF, 0x033F, 0x0193, 0x0000, 0x0000, 0x4200, 0x037F, 0x7FFF,
+    0x7FFF, 0x7E8C, 0x7C00, 0x0000, 0x7FFF, 0x1BEF, 0x6180, 0x0000,
+];
+
+#[cfg(test)]
+mod test {
+    use super::{boot_rom_palette_id, DmgPalette, BOOT_ROM_COLORS};
+    use crate::mbc::{self, MBC};
+
+    fn cart(title: &str, licensee: u8) -> Box<dyn MBC> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0134..0x0134 + title.len()].copy_from_slice(title.as_bytes());
+        rom[0x014B] = licensee;
+        mbc::get_mbc(rom, true).unwrap()
+    }
+
+    #[test]
+    fn title_lookup() {
+        assert_eq!(boot_rom_palette_id(&*cart("TETRIS", 0x01)), 3);
+        assert_eq!(boot_rom_palette_id(&*cart("SUPER MARIOLAND", 0x01)), 22);
+        assert_eq!(boot_rom_palette_id(&*cart("POKEMON BLUE", 0x01)), 11);
+        // Same checksum as POKEMON BLUE, but a different fourth letter
+        assert_eq!(boot_rom_palette_id(&*cart("POKOMON BLKE", 0x01)), 0);
+        // Only games by Nintendo are colourized
+        assert_eq!(boot_rom_palette_id(&*cart("TETRIS", 0x08)), 0);
+        assert_eq!(
+            DmgPalette::CgbAuto.resolve(&*cart("TETRIS", 0x01)),
+            DmgPalette::Cgb(3)
+        );
+        assert_eq!(
+            DmgPalette::Green.resolve(&*cart("TETRIS", 0x01)),
+            DmgPalette::Green
+        );
+    }
+
+    #[test]
+    fn combination_order() {
+        let raw = |c: u16| [c as u8, (c >> 8) as u8, 0];
+        // The default combination has a green background and red sprites
+        let [bg, obj0, obj1] = DmgPalette::Cgb(0).colors(raw);
+        assert_eq!(bg[1], raw(0x1BEF));
+        assert_eq!(obj0[1], raw(0x421F));
+        assert_eq!(obj1, obj0);
+        assert_eq!(bg, std::array::from_fn(|i| raw(BOOT_ROM_COLORS[116 + i])));
+
+        let [bg, obj0, obj1] = DmgPalette::Pocket.colors(raw);
+        assert!(bg == obj0 && obj0 == obj1);
+    }
+}
@@ -0,0 +1,74 @@
+/// Layout of the frames returned by `Device::get_gpu_data` and `Device::get_sgb_data`.
+/// Pixels are stored row by row, without padding.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PixelFormat {
+    /// 3 bytes per pixel: red, green and blue
+    Rgb888,
+    /// 4 bytes per pixel: red, green, blue and an opaque alpha
+    Rgba8888,
+    /// 4 bytes per pixel: blue, green, red and an opaque alpha
+    Bgra8888,
+    /// 2 bytes per pixel, a little endian `u16` with red in the upper 5 bits
+    Rgb565,
+    /// 1 byte per pixel, the colour before it is looked up in a palette. Bits 0-1 hold the
+    /// shade (DMG) or colour number (CGB), bits 2-4 the palette number and bit 5 is set for
+    /// sprites. DMG sprites use palette 0 for OBP0 and 1 for OBP1. The SGB picture with its
+    /// border has no such indices and stays `Rgb888`.
+    Indexed,
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            PixelFormat::Rgb888 => 3,
+            PixelFormat::Rgba8888 | PixelFormat::Bgra8888 => 4,
+            PixelFormat::Rgb565 => 2,
+            PixelFormat::Indexed => 1,
+        }
+    }
+
+    /// Converts an RGB888 picture to this format. `Indexed` frames cannot be derived from the
+    /// colours, the GPU draws them itself.
+    pub(crate) fn convert(self, rgb: &[u8], out: &mut Vec<u8>) {
+        out.clear();
+        for pixel in rgb.chunks_exact(3) {
+            let [r, g, b] = [pixel[0], pixel[1], pixel[2]];
+            match self {
+                PixelFormat::Rgb888 => out.extend_from_slice(pixel),
+                PixelFormat::Indexed => unreachable!("indexed frames are drawn by the GPU"),
+                PixelFormat::Rgba8888 => out.extend_from_slice(&[r, g, b, 0xFF]),
+                PixelFormat::Bgra8888 => out.extend_from_slice(&[b, g, r, 0xFF]),
+                PixelFormat::Rgb565 => {
+                    let value = (r as u16 >> 3) << 11 | (g as u16 >> 2) << 5 | b as u16 >> 3;
+                    out.extend_from_slice(&value.to_le_bytes());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::PixelFormat;
+
+    #[test]
+    fn conversion() {
+        let rgb = [0xFF, 0x80, 0x08, 0x00, 0x00, 0xFF];
+        let convert = |format: PixelFormat| {
+            let mut out = Vec::new();
+            format.convert(&rgb, &mut out);
+            assert_eq!(out.len(), 2 * format.bytes_per_pixel());
+            out
+        };
+        assert_eq!(convert(PixelFormat::Rgb888), rgb);
+        assert_eq!(
+            convert(PixelFormat::Rgba8888),
+            [0xFF, 0x80, 0x08, 0xFF, 0x00, 0x00, 0xFF, 0xFF]
+        );
+        assert_eq!(
+            convert(PixelFormat::Bgra8888),
+            [0x08, 0x80, 0xFF, 0xFF, 0xFF, 0x00, 0x00, 0xFF]
+        );
+        assert_eq!(convert(PixelFormat::Rgb565), [0x01, 0xFC, 0x1F, 0x00]);
+    }
+}
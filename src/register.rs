@@ -62,6 +62,18 @@ impl Registers {
                 pc: 0x0100,
                 sp: 0xFFFE,
             },
+            GbMode::Sgb => Registers {
+                a: 0x01,
+                f: 0x00,
+                b: 0x00,
+                c: 0x14,
+                d: 0x00,
+                e: 0x00,
+                h: 0xC0,
+                l: 0x60,
+                pc: 0x0100,
+                sp: 0xFFFE,
+            },
         }
     }
 
@@ -79,12 +91,12 @@ impl Registers {
     }
     pub fn hld(&mut self) -> u16 {
         let res = self.hl();
-        self.sethl(res - 1);
+        self.sethl(res.wrapping_sub(1));
         res
     }
     pub fn hli(&mut self) -> u16 {
         let res = self.hl();
-        self.sethl(res + 1);
+        self.sethl(res.wrapping_add(1));
         res
     }
 
@@ -0,0 +1,404 @@
+use crate::color::ColorTable;
+
+pub const SGB_SCREEN_W: usize = 256;
+pub const SGB_SCREEN_H: usize = 224;
+
+// Position of the Game Boy screen within the border
+const SCREEN_X: usize = 48;
+const SCREEN_Y: usize = 40;
+
+const BLOCKS_W: usize = 20;
+const BLOCKS_H: usize = 18;
+const ATTR_FILE_SIZE: usize = 90;
+const ATTR_FILE_COUNT: usize = 45;
+const SYSTEM_PALETTE_COUNT: usize = 512;
+
+// Palette shown until the game sends its own
+const DEFAULT_PALETTE: [u16; 4] = [0x67BF, 0x265B, 0x10B5, 0x2866];
+
+#[derive(PartialEq, Copy, Clone)]
+enum MaskMode {
+    Cancel,
+    Freeze,
+    Black,
+    Color0,
+}
+
+/// VRAM transfers are executed on the next VBlank, as the data is read from the screen
+#[derive(PartialEq, Copy, Clone)]
+pub enum Transfer {
+    Chr { upper: bool },
+    Pct,
+    Pal,
+    Attr,
+}
+
+#[derive(Clone, PartialEq)]
+pub struct Sgb {
+    palettes: [[u16; 4]; 4],               // Active palettes 0-3
+    system_palettes: Vec<[u16; 4]>,        // Palettes received with PAL_TRN
+    attr_map: [u8; BLOCKS_W * BLOCKS_H],   // Palette number of each 8x8 block
+    attr_files: Vec<[u8; ATTR_FILE_SIZE]>, // Attribute files received with ATTR_TRN
+    mask: MaskMode,                        // Current MASK_EN setting
+    border_tiles: Vec<u8>,                 // 256 SNES 4bpp tiles received with CHR_TRN
+    border_map: Vec<u8>,                   // 32x32 tile map received with PCT_TRN
+    border_palettes: [[u16; 16]; 4],       // Border palettes 4-7
+    pending: Option<Transfer>,             // Transfer to execute at the next VBlank
+    pub output: Vec<u8>,                   // Bordered RGB output
+}
+
+impl Sgb {
+    pub fn new() -> Sgb {
+        Sgb {
+            palettes: [DEFAULT_PALETTE; 4],
+            system_palettes: vec![[0; 4]; SYSTEM_PALETTE_COUNT],
+            attr_map: [0; BLOCKS_W * BLOCKS_H],
+            attr_files: vec![[0; ATTR_FILE_SIZE]; ATTR_FILE_COUNT],
+            mask: MaskMode::Cancel,
+            border_tiles: vec![0; 256 * 32],
+            border_map: vec![0; 32 * 32 * 2],
+            border_palettes: [[0; 16]; 4],
+            pending: None,
+            output: vec![0; SGB_SCREEN_W * SGB_SCREEN_H * 3],
+        }
+    }
+
+    /// Color of a pixel of the Game Boy screen with the given shade. The SNES colours are
+    /// BGR555 like the CGB ones and use the same colour correction.
+    pub fn color(&self, x: usize, y: usize, shade: u8, colors: &ColorTable) -> [u8; 3] {
+        // Color 0 is shared by all palettes
+        let color = if shade == 0 {
+            self.palettes[0][0]
+        } else {
+            let palnr = self.attr_map[(y / 8) * BLOCKS_W + x / 8] as usize;
+            self.palettes[palnr][shade as usize & 0x03]
+        };
+        colors.get(color)
+    }
+
+    pub fn take_pending_transfer(&mut self) -> Option<Transfer> {
+        self.pending.take()
+    }
+
+    pub fn command(&mut self, data: &[u8]) {
+        match data[0] >> 3 {
+            0x00 => self.set_palettes(0, 1, data),
+            0x01 => self.set_palettes(2, 3, data),
+            0x02 => self.set_palettes(0, 3, data),
+            0x03 => self.set_palettes(1, 2, data),
+            0x04 => self.attr_blk(data),
+            0x05 => self.attr_lin(data),
+            0x06 => self.attr_div(data),
+            0x07 => self.attr_chr(data),
+            0x0A => self.pal_set(data),
+            0x0B => self.pending = Some(Transfer::Pal),
+            0x13 => {
+                self.pending = Some(Transfer::Chr {
+                    upper: data[1] & 0x01 == 0x01,
+                })
+            }
+            0x14 => self.pending = Some(Transfer::Pct),
+            0x15 => self.pending = Some(Transfer::Attr),
+            0x16 => self.attr_set(data[1]),
+            0x17 => {
+                self.mask = match data[1] & 0x03 {
+                    0 => MaskMode::Cancel,
+                    1 => MaskMode::Freeze,
+                    2 => MaskMode::Black,
+                    _ => MaskMode::Color0,
+                }
+            }
+            // Sound, SNES program and multiplayer commands are not emulated here
+            _ => {}
+        }
+    }
+
+    pub fn transfer(&mut self, kind: Transfer, data: &[u8]) {
+        match kind {
+            Transfer::Chr { upper } => {
+                let offset = if upper { 0x1000 } else { 0 };
+                self.border_tiles[offset..offset + 0x1000].copy_from_slice(&data[..0x1000]);
+            }
+            Transfer::Pct => {
+                self.border_map.copy_from_slice(&data[..0x800]);
+                for (i, color) in data[0x800..0x880].chunks(2).enumerate() {
+                    self.border_palettes[i / 16][i % 16] = read_color(color);
+                }
+            }
+            Transfer::Pal => {
+                for (i, color) in data[..0x1000].chunks(2).enumerate() {
+                    self.system_palettes[i / 4][i % 4] = read_color(color);
+                }
+            }
+            Transfer::Attr => {
+                for (i, file) in data[..ATTR_FILE_COUNT * ATTR_FILE_SIZE]
+                    .chunks(ATTR_FILE_SIZE)
+                    .enumerate()
+                {
+                    self.attr_files[i].copy_from_slice(file);
+                }
+            }
+        }
+    }
+
+    /// Composes the border and the Game Boy screen into the output buffer
+    pub fn render(&mut self, screen: &[u8], colors: &ColorTable) {
+        let backdrop = colors.get(self.palettes[0][0]);
+
+        for y in 0..SGB_SCREEN_H {
+            for x in 0..SGB_SCREEN_W {
+                let in_screen = (SCREEN_X..SCREEN_X + 160).contains(&x)
+                    && (SCREEN_Y..SCREEN_Y + 144).contains(&y);
+                let color = if in_screen {
+                    let (sx, sy) = (x - SCREEN_X, y - SCREEN_Y);
+                    match self.mask {
+                        MaskMode::Cancel => {
+                            let i = (sy * 160 + sx) * 3;
+                            [screen[i], screen[i + 1], screen[i + 2]]
+                        }
+                        // Keep the last picture
+                        MaskMode::Freeze => continue,
+                        MaskMode::Black => [0, 0, 0],
+                        MaskMode::Color0 => backdrop,
+                    }
+                } else {
+                    match self.border_pixel(x, y) {
+                        Some(color) => colors.get(color),
+                        None => backdrop,
+                    }
+                };
+
+                let i = (y * SGB_SCREEN_W + x) * 3;
+                self.output[i..i + 3].copy_from_slice(&color);
+            }
+        }
+    }
+
+    fn border_pixel(&self, x: usize, y: usize) -> Option<u16> {
+        let entry = ((y / 8) * 32 + x / 8) * 2;
+        let tilenr = self.border_map[entry] as usize;
+        let flags = self.border_map[entry + 1];
+        let palnr = ((flags >> 2) & 0x07) as usize;
+        let xflip = flags & 0x40 != 0;
+        let yflip = flags & 0x80 != 0;
+
+        let px = if xflip { x % 8 } else { 7 - x % 8 };
+        let py = if yflip { 7 - y % 8 } else { y % 8 };
+
+        // SNES 4bpp: bitplanes 0 and 1 are interleaved in the first half, 2 and 3 in the second
+        let tile = &self.border_tiles[tilenr * 32..tilenr * 32 + 32];
+        let colnr = (0..4).fold(0, |acc, plane| {
+            let b = tile[(plane / 2) * 16 + py * 2 + plane % 2];
+            acc | (((b >> px) & 0x01) << plane)
+        });
+
+        // Color 0 is transparent, only palettes 4-7 are available to the border
+        if colnr == 0 || !(4..8).contains(&palnr) {
+            None
+        } else {
+            Some(self.border_palettes[palnr - 4][colnr as usize])
+        }
+    }
+
+    fn set_palettes(&mut self, p0: usize, p1: usize, data: &[u8]) {
+        let color0 = read_color(&data[1..3]);
+        for palette in self.palettes.iter_mut() {
+            palette[0] = color0;
+        }
+        for i in 0..3 {
+            self.palettes[p0][i + 1] = read_color(&data[3 + i * 2..5 + i * 2]);
+            self.palettes[p1][i + 1] = read_color(&data[9 + i * 2..11 + i * 2]);
+        }
+    }
+
+    fn pal_set(&mut self, data: &[u8]) {
+        for i in 0..4 {
+            let id = (data[1 + i * 2] as usize | ((data[2 + i * 2] as usize) << 8)) & 0x1FF;
+            self.palettes[i] = self.system_palettes[id];
+        }
+        let color0 = self.palettes[0][0];
+        for palette in self.palettes.iter_mut() {
+            palette[0] = color0;
+        }
+        if data[9] & 0x80 != 0 {
+            self.attr_set(data[9]);
+        }
+        if data[9] & 0x40 != 0 {
+            self.mask = MaskMode::Cancel;
+        }
+    }
+
+    fn attr_set(&mut self, v: u8) {
+        let file = self.attr_files[((v & 0x3F) as usize).min(ATTR_FILE_COUNT - 1)];
+        for (i, block) in self.attr_map.iter_mut().enumerate() {
+            *block = (file[i / 4] >> (6 - (i % 4) * 2)) & 0x03;
+        }
+        if v & 0x40 != 0 {
+            self.mask = MaskMode::Cancel;
+        }
+    }
+
+    fn attr_blk(&mut self, data: &[u8]) {
+        let count = (data[1] as usize).min((data.len() - 2) / 6);
+        for set in data[2..2 + count * 6].chunks(6) {
+            let (inside, border, outside) = (set[0] & 1 != 0, set[0] & 2 != 0, set[0] & 4 != 0);
+            let pal_inside = set[1] & 0x03;
+            let pal_outside = (set[1] >> 4) & 0x03;
+            // Without an explicit border setting, the border belongs to the given area
+            let pal_border = match (inside, border, outside) {
+                (true, false, false) => Some(pal_inside),
+                (false, false, true) => Some(pal_outside),
+                (_, true, _) => Some((set[1] >> 2) & 0x03),
+                _ => None,
+            };
+            let (x1, y1, x2, y2) = (
+                (set[2] & 0x1F) as usize,
+                (set[3] & 0x1F) as usize,
+                (set[4] & 0x1F) as usize,
+                (set[5] & 0x1F) as usize,
+            );
+
+            for y in 0..BLOCKS_H {
+                for x in 0..BLOCKS_W {
+                    let palette = if x < x1 || x > x2 || y < y1 || y > y2 {
+                        Some(pal_outside).filter(|_| outside)
+                    } else if x == x1 || x == x2 || y == y1 || y == y2 {
+                        pal_border
+                    } else {
+                        Some(pal_inside).filter(|_| inside)
+                    };
+                    if let Some(palette) = palette {
+                        self.attr_map[y * BLOCKS_W + x] = palette;
+                    }
+                }
+            }
+        }
+    }
+
+    fn attr_lin(&mut self, data: &[u8]) {
+        let count = (data[1] as usize).min(data.len() - 2);
+        for &v in &data[2..2 + count] {
+            let line = (v & 0x1F) as usize;
+            let palette = (v >> 5) & 0x03;
+            if v & 0x80 != 0 {
+                if line < BLOCKS_H {
+                    for x in 0..BLOCKS_W {
+                        self.attr_map[line * BLOCKS_W + x] = palette;
+                    }
+                }
+            } else if line < BLOCKS_W {
+                for y in 0..BLOCKS_H {
+                    self.attr_map[y * BLOCKS_W + line] = palette;
+                }
+            }
+        }
+    }
+
+    fn attr_div(&mut self, data: &[u8]) {
+        let pal_after = data[1] & 0x03;
+        let pal_before = (data[1] >> 2) & 0x03;
+        let pal_line = (data[1] >> 4) & 0x03;
+        let horizontal = data[1] & 0x40 != 0;
+        let coord = (data[2] & 0x1F) as usize;
+
+        for y in 0..BLOCKS_H {
+            for x in 0..BLOCKS_W {
+                let pos = if horizontal { y } else { x };
+                self.attr_map[y * BLOCKS_W + x] = match pos {
+                    p if p < coord => pal_before,
+                    p if p == coord => pal_line,
+                    _ => pal_after,
+                };
+            }
+        }
+    }
+
+    fn attr_chr(&mut self, data: &[u8]) {
+        let mut x = (data[1] as usize).min(BLOCKS_W - 1);
+        let mut y = (data[2] as usize).min(BLOCKS_H - 1);
+        let count = (data[3] as usize | ((data[4] as usize) << 8)).min((data.len() - 6) * 4);
+        let vertical = data[5] & 0x01 != 0;
+
+        for i in 0..count {
+            let palette = (data[6 + i / 4] >> (6 - (i % 4) * 2)) & 0x03;
+            self.attr_map[y * BLOCKS_W + x] = palette;
+
+            if vertical {
+                y += 1;
+                if y == BLOCKS_H {
+                    y = 0;
+                    x += 1;
+                    if x == BLOCKS_W {
+                        break;
+                    }
+                }
+            } else {
+                x += 1;
+                if x == BLOCKS_W {
+                    x = 0;
+                    y += 1;
+                    if y == BLOCKS_H {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn read_color(data: &[u8]) -> u16 {
+    (data[0] as u16) | ((data[1] as u16) << 8)
+}
+
+#[cfg(test)]
+mod test {
+    use super::Sgb;
+    use crate::color::{ColorCorrection, ColorTable};
+
+    fn packet(command: u8, data: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0; 16];
+        packet[0] = (command << 3) | 1;
+        packet[1..1 + data.len()].copy_from_slice(data);
+        packet
+    }
+
+    #[test]
+    fn pal01_shares_color0() {
+        let mut sgb = Sgb::new();
+        sgb.command(&packet(
+            0x00,
+            &[0x1F, 0x00, 0xE0, 0x03, 0x00, 0x7C, 0x00, 0x00, 0xFF, 0x7F],
+        ));
+        assert_eq!(sgb.palettes[0], [0x001F, 0x03E0, 0x7C00, 0x0000]);
+        assert_eq!(sgb.palettes[1][0], 0x001F);
+        assert_eq!(sgb.palettes[1][1], 0x7FFF);
+        assert_eq!(sgb.palettes[3][0], 0x001F);
+        let raw = ColorTable::new(ColorCorrection::Raw);
+        assert_eq!(sgb.color(0, 0, 2, &raw), [0, 0, 255]);
+        let gambatte = ColorTable::new(ColorCorrection::Gambatte);
+        assert_eq!(sgb.color(0, 0, 2, &gambatte), gambatte.get(0x7C00));
+    }
+
+    #[test]
+    fn attr_blk_border_follows_inside() {
+        let mut sgb = Sgb::new();
+        // Only the inside flag is set, palette 2 for the inside
+        sgb.command(&packet(0x04, &[1, 0x01, 0x02, 2, 3, 5, 6]));
+        assert_eq!(sgb.attr_map[3 * 20 + 2], 2);
+        assert_eq!(sgb.attr_map[4 * 20 + 3], 2);
+        assert_eq!(sgb.attr_map[6 * 20 + 5], 2);
+        assert_eq!(sgb.attr_map[7 * 20 + 5], 0);
+        assert_eq!(sgb.attr_map[0], 0);
+    }
+
+    #[test]
+    fn attr_set_uses_transferred_file() {
+        let mut sgb = Sgb::new();
+        let mut data = vec![0; 0x1000];
+        // File 1 starts with palettes 3, 2, 1, 0
+        data[90] = 0b11_10_01_00;
+        sgb.transfer(super::Transfer::Attr, &data);
+        sgb.command(&packet(0x16, &[0x01]));
+        assert_eq!(&sgb.attr_map[..5], &[3, 2, 1, 0, 0]);
+    }
+}
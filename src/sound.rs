@@ -1,24 +1,90 @@
+use crate::vgm::ApuLog;
+use crate::wav::WavWriter;
+use crate::{Error, Result};
 use blip_buf::BlipBuf;
-
-const WAVE_PATTERN: [[i32; 8]; 4] = [
-    [-1, -1, -1, -1, 1, -1, -1, -1],
-    [-1, -1, -1, -1, 1, 1, -1, -1],
-    [-1, -1, 1, 1, 1, 1, -1, -1],
-    [1, 1, 1, 1, -1, -1, 1, 1],
+use std::fs;
+use std::path::Path;
+
+const WAVE_PATTERN: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 1, 0, 0, 0],
+    [0, 0, 0, 0, 1, 1, 0, 0],
+    [0, 0, 1, 1, 1, 1, 0, 0],
+    [1, 1, 1, 1, 0, 0, 1, 1],
 ];
 const CLOCKS_PER_SECOND: u32 = 1 << 22;
 const CLOCKS_PER_FRAME: u32 = CLOCKS_PER_SECOND / 512;
 const OUTPUT_SAMPLE_COUNT: usize = 2000; // this should be less than blip_buf::MAX_FRAME
 const SWEEP_DELAY_ZERO_PERIOD: u8 = 8;
+const MAX_RATE_ADJUSTMENT: f64 = 0.005;
+
+// BlipBuf::read_samples applies a high-pass filter of its own, which leaks 1/2^9 of the
+// output per sample. It is undone when mixing, so the output is only filtered as selected.
+const BLIP_BASS_SHIFT: u32 = 9;
 
 // Additional delay on trigger of the wave channel (channel 3). In other emulators it is 6, but we
 // need 4 since we run the wave after delay == 0, instead of at delay == 0
 const WAVE_INITIAL_DELAY: u32 = 4;
 
+// Files written by `Sound::start_channel_recording`, one per channel
+const CHANNEL_FILE_NAMES: [&str; 4] = [
+    "ch1-square1.wav",
+    "ch2-square2.wav",
+    "ch3-wave.wav",
+    "ch4-noise.wav",
+];
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum SoundChannel {
+    Square1,
+    Square2,
+    Wave,
+    Noise,
+}
+
+/// Model of the capacitors between the mixer and the audio output
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum HighPassFilter {
+    /// Behaves like the capacitors of the DMG or CGB. Removes the DC offset, but long pulses
+    /// droop towards the center.
+    Accurate,
+    /// Only removes the DC offset of the enabled DACs, keeping the shape of the waveform
+    PreserveWaveform,
+    /// The raw DAC output, including the DC offset
+    Off,
+}
+
+// Host side mixer settings of a channel, these do not affect the emulated registers
+#[derive(Copy, Clone)]
+struct ChannelMix {
+    muted: bool,
+    solo: bool,
+    gain: f32,
+}
+
+impl ChannelMix {
+    fn new() -> ChannelMix {
+        ChannelMix {
+            muted: false,
+            solo: false,
+            gain: 1.0,
+        }
+    }
+}
+
 pub trait AudioPlayer: Send {
     fn play(&mut self, left_channel: &[f32], right_channel: &[f32]);
     fn samples_rate(&self) -> u32;
     fn underflowed(&self) -> bool;
+
+    /// Whether the player wants the separate channel streams passed to `play_channels`
+    fn wants_channels(&self) -> bool {
+        false
+    }
+
+    /// Receives the stereo (left, right) output of CH1-CH4 separately, right after the mixed
+    /// output was passed to `play`. The streams are panned and scaled by the master volume, but
+    /// not affected by mute, solo or gain.
+    fn play_channels(&mut self, _channels: &[(&[f32], &[f32]); 4]) {}
 }
 
 struct VolumeEnvelope {
@@ -47,7 +113,7 @@ impl VolumeEnvelope {
                     | if self.goes_up { 0x08 } else { 0 }
                     | (self.period & 0x7)
             }
-            _ => unimplemented!(),
+            _ => 0xFF,
         }
     }
 
@@ -144,6 +210,7 @@ struct SquareChannel {
     frequency: u16,
     period: u32,
     last_amp: i32,
+    output: u8,
     delay: u32,
     has_sweep: bool,
     sweep_enabled: bool,
@@ -168,6 +235,7 @@ impl SquareChannel {
             frequency: 0,
             period: 2048,
             last_amp: 0,
+            output: 0,
             delay: 0,
             has_sweep: with_sweep,
             sweep_enabled: false,
@@ -186,6 +254,18 @@ impl SquareChannel {
         self.active
     }
 
+    // The 4-bit value currently fed into the DAC
+    fn digital_output(&self) -> u8 {
+        self.output
+    }
+
+    fn set_amp(&mut self, time: u32, amp: i32) {
+        if amp != self.last_amp {
+            self.blip.add_delta(time, amp - self.last_amp);
+            self.last_amp = amp;
+        }
+    }
+
     fn rb(&self, a: u16) -> u8 {
         match a {
             0xFF10 => {
@@ -197,7 +277,7 @@ impl SquareChannel {
             0xFF12 | 0xFF17 => self.volume_envelope.rb(a),
             0xFF13 | 0xFF18 => 0xFF,
             0xFF14 | 0xFF19 => 0x80 | if self.length.enabled { 0x40 } else { 0 } | 0x3F,
-            _ => unimplemented!(),
+            _ => 0xFF,
         }
     }
 
@@ -270,22 +350,18 @@ impl SquareChannel {
     // This assumes no volume or sweep adjustments need to be done in the meantime
     fn run(&mut self, start_time: u32, end_time: u32) {
         if !self.active || self.period == 0 {
-            if self.last_amp != 0 {
-                self.blip.add_delta(start_time, -self.last_amp);
-                self.last_amp = 0;
-                self.delay = 0;
-            }
+            // A disabled channel outputs 0, which the DAC still converts when it is on
+            self.output = 0;
+            self.set_amp(start_time, dac_output(self.dac_enabled, 0));
+            self.delay = 0;
         } else {
             let mut time = start_time + self.delay;
             let pattern = WAVE_PATTERN[self.duty as usize];
-            let vol = self.volume_envelope.volume as i32;
+            let vol = self.volume_envelope.volume;
 
             while time < end_time {
-                let amp = vol * pattern[self.phase as usize];
-                if amp != self.last_amp {
-                    self.blip.add_delta(time, amp - self.last_amp);
-                    self.last_amp = amp;
-                }
+                self.output = vol * pattern[self.phase as usize];
+                self.set_amp(time, dac_output(true, self.output));
                 time += self.period;
                 self.phase = (self.phase + 1) % 8;
             }
@@ -349,6 +425,7 @@ struct WaveChannel {
     frequency: u16,
     period: u32,
     last_amp: i32,
+    output: u8,
     delay: u32,
     volume_shift: u8,
     waveram: [u8; 16],
@@ -367,6 +444,7 @@ impl WaveChannel {
             frequency: 0,
             period: 2048,
             last_amp: 0,
+            output: 0,
             delay: 0,
             volume_shift: 0,
             waveram: [0; 16],
@@ -395,7 +473,7 @@ impl WaveChannel {
                     }
                 }
             }
-            _ => unimplemented!(),
+            _ => 0xFF,
         }
     }
 
@@ -456,23 +534,31 @@ impl WaveChannel {
         self.active
     }
 
+    // The 4-bit value currently fed into the DAC, after the volume shift
+    fn digital_output(&self) -> u8 {
+        self.output
+    }
+
+    fn set_amp(&mut self, time: u32, amp: i32) {
+        if amp != self.last_amp {
+            self.blip.add_delta(time, amp - self.last_amp);
+            self.last_amp = amp;
+        }
+    }
+
     fn run(&mut self, start_time: u32, end_time: u32) {
         self.sample_recently_accessed = false;
         if !self.active || self.period == 0 {
-            if self.last_amp != 0 {
-                self.blip.add_delta(start_time, -self.last_amp);
-                self.last_amp = 0;
-                self.delay = 0;
-            }
+            // A disabled channel outputs 0, which the DAC still converts when it is on
+            self.output = 0;
+            self.set_amp(start_time, dac_output(self.dac_enabled, 0));
+            self.delay = 0;
         } else {
             let mut time = start_time + self.delay;
 
             // A sample may be muted, 100%, 50% or 25%.
-            // To avoid loss of precision, the WaveChannel will output
-            // i32 samples at 4x the usual amplitude. This will be taken
-            // into account when mixing all the samples.
             let volshift = match self.volume_shift {
-                0 => 4 + 2, // to mute a 4 bit sample mutiplied by 2^2
+                0 => 4,
                 1 => 0,
                 2 => 1,
                 3 => 2,
@@ -487,13 +573,8 @@ impl WaveChannel {
                     wavebyte & 0xF
                 };
 
-                // shifted by 2 so that 25% does not lose precision
-                let amp = ((sample << 2) >> volshift) as i32;
-
-                if amp != self.last_amp {
-                    self.blip.add_delta(time, amp - self.last_amp);
-                    self.last_amp = amp;
-                }
+                self.output = sample >> volshift;
+                self.set_amp(time, dac_output(true, self.output));
 
                 if time >= end_time - 2 {
                     // Mark the wave sample as recently accessed.
@@ -549,6 +630,7 @@ struct NoiseChannel {
     state: u16,
     delay: u32,
     last_amp: i32,
+    output: u8,
     blip: BlipBuf,
 }
 
@@ -565,6 +647,7 @@ impl NoiseChannel {
             state: 1,
             delay: 0,
             last_amp: 0,
+            output: 0,
             blip: blip,
         }
     }
@@ -575,7 +658,7 @@ impl NoiseChannel {
             0xFF21 => self.volume_envelope.rb(a),
             0xFF22 => self.reg_ff22,
             0xFF23 => 0x80 | if self.length.enabled { 0x40 } else { 0 } | 0x3F,
-            _ => unimplemented!(),
+            _ => 0xFF,
         }
     }
 
@@ -619,13 +702,24 @@ impl NoiseChannel {
         self.active
     }
 
+    // The 4-bit value currently fed into the DAC
+    fn digital_output(&self) -> u8 {
+        self.output
+    }
+
+    fn set_amp(&mut self, time: u32, amp: i32) {
+        if amp != self.last_amp {
+            self.blip.add_delta(time, amp - self.last_amp);
+            self.last_amp = amp;
+        }
+    }
+
     fn run(&mut self, start_time: u32, end_time: u32) {
         if !self.active {
-            if self.last_amp != 0 {
-                self.blip.add_delta(start_time, -self.last_amp);
-                self.last_amp = 0;
-                self.delay = 0;
-            }
+            // A disabled channel outputs 0, which the DAC still converts when it is on
+            self.output = 0;
+            self.set_amp(start_time, dac_output(self.dac_enabled, 0));
+            self.delay = 0;
         } else {
             let mut time = start_time + self.delay;
             while time < end_time {
@@ -634,15 +728,11 @@ impl NoiseChannel {
                 let bit = ((oldstate >> self.shift_width) ^ (self.state >> self.shift_width)) & 1;
                 self.state |= bit;
 
-                let amp = match (oldstate >> self.shift_width) & 1 {
-                    0 => -(self.volume_envelope.volume as i32),
-                    _ => self.volume_envelope.volume as i32,
+                self.output = match (oldstate >> self.shift_width) & 1 {
+                    0 => 0,
+                    _ => self.volume_envelope.volume,
                 };
-
-                if self.last_amp != amp {
-                    self.blip.add_delta(time, amp - self.last_amp);
-                    self.last_amp = amp;
-                }
+                self.set_amp(time, dac_output(true, self.output));
 
                 time += self.period;
             }
@@ -663,6 +753,7 @@ pub struct Sound {
     next_time: u32,
     frame_step: u8,
     output_period: u32,
+    rate_ratio: f64,
     channel1: SquareChannel,
     channel2: SquareChannel,
     channel3: WaveChannel,
@@ -673,6 +764,17 @@ pub struct Sound {
     reg_ff25: u8,
     need_sync: bool,
     dmg_mode: bool,
+    mix: [ChannelMix; 4],
+    master_volume: f32,
+    high_pass_filter: HighPassFilter,
+    charge_factor: f32,
+    capacitors: [[f32; 2]; 4],
+    blip_leaks: [i64; 4],
+    recorder: Option<WavWriter>,
+    channel_recorders: Option<[WavWriter; 4]>,
+    // The last value written to each register, also where reads return something else
+    registers: [u8; 0x30],
+    apu_log: Option<ApuLog>,
     player: Box<dyn AudioPlayer>,
 }
 
@@ -689,14 +791,36 @@ impl Sound {
         self.on = true;
     }
 
-    fn new_internal(player: Box<dyn AudioPlayer>, dmg_mode: bool) -> Sound {
-        let blipbuf1 = create_blipbuf(player.samples_rate());
-        let blipbuf2 = create_blipbuf(player.samples_rate());
-        let blipbuf3 = create_blipbuf(player.samples_rate());
-        let blipbuf4 = create_blipbuf(player.samples_rate());
+    pub fn reset(&mut self) {
+        let samples_rate = self.output_rate();
+        if let Some(log) = self.apu_log.as_mut() {
+            log.end_frame(self.time);
+        }
+        self.on = false;
+        self.time = 0;
+        self.prev_time = 0;
+        self.next_time = CLOCKS_PER_FRAME;
+        self.frame_step = 0;
+        self.channel1 = SquareChannel::new(create_blipbuf(samples_rate), true);
+        self.channel2 = SquareChannel::new(create_blipbuf(samples_rate), false);
+        self.channel3 = WaveChannel::new(create_blipbuf(samples_rate), self.dmg_mode);
+        self.channel4 = NoiseChannel::new(create_blipbuf(samples_rate));
+        self.volume_left = 7;
+        self.volume_right = 7;
+        self.reg_vin_to_so = 0x00;
+        self.reg_ff25 = 0x00;
+        self.need_sync = false;
+        self.capacitors = [[0.0; 2]; 4];
+        self.blip_leaks = [0; 4];
+        self.registers = [0; 0x30];
+    }
 
-        let output_period =
-            (OUTPUT_SAMPLE_COUNT as u64 * CLOCKS_PER_SECOND as u64) / player.samples_rate() as u64;
+    fn new_internal(player: Box<dyn AudioPlayer>, dmg_mode: bool) -> Sound {
+        let samples_rate = player.samples_rate() as f64;
+        let blipbuf1 = create_blipbuf(samples_rate);
+        let blipbuf2 = create_blipbuf(samples_rate);
+        let blipbuf3 = create_blipbuf(samples_rate);
+        let blipbuf4 = create_blipbuf(samples_rate);
 
         Sound {
             on: false,
@@ -704,7 +828,8 @@ impl Sound {
             prev_time: 0,
             next_time: CLOCKS_PER_FRAME,
             frame_step: 0,
-            output_period: output_period as u32,
+            output_period: output_period(samples_rate),
+            rate_ratio: 1.0,
             channel1: SquareChannel::new(blipbuf1, true),
             channel2: SquareChannel::new(blipbuf2, false),
             channel3: WaveChannel::new(blipbuf3, dmg_mode),
@@ -715,6 +840,16 @@ impl Sound {
             reg_ff25: 0x00,
             need_sync: false,
             dmg_mode: dmg_mode,
+            mix: [ChannelMix::new(); 4],
+            master_volume: 1.0,
+            high_pass_filter: HighPassFilter::Accurate,
+            charge_factor: charge_factor(dmg_mode, player.samples_rate()),
+            capacitors: [[0.0; 2]; 4],
+            blip_leaks: [0; 4],
+            recorder: None,
+            channel_recorders: None,
+            registers: [0; 0x30],
+            apu_log: None,
             player: player,
         }
     }
@@ -737,12 +872,192 @@ impl Sound {
                     | if self.channel1.on() { 0x1 } else { 0x0 })
             }
             0xFF30..=0xFF3F => self.channel3.rb(a),
+            0xFF76 => (self.channel2.digital_output() << 4) | self.channel1.digital_output(),
+            0xFF77 => (self.channel4.digital_output() << 4) | self.channel3.digital_output(),
             _ => 0xFF,
         };
         return v;
     }
 
+    pub fn set_muted(&mut self, channel: SoundChannel, muted: bool) {
+        self.mix[channel as usize].muted = muted;
+    }
+
+    pub fn muted(&self, channel: SoundChannel) -> bool {
+        self.mix[channel as usize].muted
+    }
+
+    pub fn set_solo(&mut self, channel: SoundChannel, solo: bool) {
+        self.mix[channel as usize].solo = solo;
+    }
+
+    pub fn solo(&self, channel: SoundChannel) -> bool {
+        self.mix[channel as usize].solo
+    }
+
+    pub fn set_gain(&mut self, channel: SoundChannel, gain: f32) {
+        self.mix[channel as usize].gain = gain.max(0.0);
+    }
+
+    pub fn gain(&self, channel: SoundChannel) -> f32 {
+        self.mix[channel as usize].gain
+    }
+
+    /// Scales the mixed output, e.g. to fade out. The channel streams are not affected.
+    pub fn set_master_volume(&mut self, volume: f32) {
+        self.master_volume = volume.max(0.0);
+    }
+
+    pub fn master_volume(&self) -> f32 {
+        self.master_volume
+    }
+
+    pub fn set_high_pass_filter(&mut self, filter: HighPassFilter) {
+        self.high_pass_filter = filter;
+        self.capacitors = [[0.0; 2]; 4];
+    }
+
+    pub fn high_pass_filter(&self) -> HighPassFilter {
+        self.high_pass_filter
+    }
+
+    /// Records the mixed output, exactly as it is passed to the player
+    pub fn start_recording(&mut self, path: &Path) -> Result<()> {
+        self.stop_recording()?;
+        let recorder = WavWriter::create(path, self.player.samples_rate(), 2)?;
+        self.begin_recording();
+        self.recorder = Some(recorder);
+        Ok(())
+    }
+
+    pub fn stop_recording(&mut self) -> Result<()> {
+        match self.recorder.take() {
+            Some(recorder) => recorder.finish(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    /// Records each channel to its own stereo WAV file in `dir`, like the streams passed to
+    /// `AudioPlayer::play_channels`. The files start at the same sample, so they line up when
+    /// imported as tracks.
+    pub fn start_channel_recording(&mut self, dir: &Path) -> Result<()> {
+        self.stop_channel_recording()?;
+        fs::create_dir_all(dir).map_err(|source| Error::Io {
+            path: dir.to_path_buf(),
+            source,
+        })?;
+        let rate = self.player.samples_rate();
+        let create = |name: &str| WavWriter::create(dir.join(name), rate, 2);
+        let [ch1, ch2, ch3, ch4] = CHANNEL_FILE_NAMES;
+        let recorders = [create(ch1)?, create(ch2)?, create(ch3)?, create(ch4)?];
+        self.begin_recording();
+        self.channel_recorders = Some(recorders);
+        Ok(())
+    }
+
+    // Recordings use the sample rate of the player without rate adjustments, so their length
+    // follows the emulated time. The samples generated at another rate are passed on first.
+    fn begin_recording(&mut self) {
+        self.flush();
+        self.set_rate_ratio(1.0);
+    }
+
+    fn is_recording_any(&self) -> bool {
+        self.recorder.is_some() || self.channel_recorders.is_some()
+    }
+
+    pub fn stop_channel_recording(&mut self) -> Result<()> {
+        match self.channel_recorders.take() {
+            Some(recorders) => recorders
+                .into_iter()
+                .map(WavWriter::finish)
+                .fold(Ok(()), Result::and),
+            None => Ok(()),
+        }
+    }
+
+    pub fn is_recording_channels(&self) -> bool {
+        self.channel_recorders.is_some()
+    }
+
+    /// Starts logging all register writes. The log begins with the writes needed to bring the
+    /// APU from power on into its current state, as far as the registers allow.
+    pub fn start_apu_log(&mut self) {
+        let mut log = ApuLog::new(self.time);
+        let time = self.time;
+        if self.on {
+            log.record(time, 0xFF26, 0x80);
+            // Wave RAM is written with the DAC of channel 3 off, so it is not playing
+            log.record(time, 0xFF1A, 0x00);
+            for a in 0xFF30..=0xFF3F {
+                log.record(time, a, self.registers[(a - 0xFF10) as usize]);
+            }
+            let active = [
+                self.channel1.on(),
+                self.channel2.on(),
+                self.channel3.on(),
+                self.channel4.on(),
+            ];
+            for a in 0xFF10..=0xFF25 {
+                let v = self.registers[(a - 0xFF10) as usize];
+                let v = match a {
+                    0xFF15 | 0xFF1F => continue,
+                    // Trigger the channels which are currently playing
+                    0xFF14 | 0xFF19 | 0xFF1E | 0xFF23 => {
+                        let n = (a - 0xFF14) as usize / 5;
+                        (v & 0x7F) | if active[n] { 0x80 } else { 0 }
+                    }
+                    _ => v,
+                };
+                log.record(time, a, v);
+            }
+        }
+        self.apu_log = Some(log);
+    }
+
+    /// Stops logging and returns the log, or `None` when logging was not started
+    pub fn stop_apu_log(&mut self) -> Option<ApuLog> {
+        let mut log = self.apu_log.take()?;
+        log.finish(self.time);
+        Some(log)
+    }
+
+    // Gain applied to each channel when mixing. While any channel is soloed, only soloed
+    // channels are heard.
+    fn mix_gains(&self) -> [f32; 4] {
+        let any_solo = self.mix.iter().any(|mix| mix.solo);
+        self.mix.map(|mix| {
+            if mix.muted || (any_solo && !mix.solo) {
+                0.0
+            } else {
+                mix.gain * self.master_volume
+            }
+        })
+    }
+
+    /// Current digital output (0-15) of each channel, as also seen through PCM12 and PCM34
+    pub fn channel_amplitudes(&mut self) -> [u8; 4] {
+        self.run();
+        [
+            self.channel1.digital_output(),
+            self.channel2.digital_output(),
+            self.channel3.digital_output(),
+            self.channel4.digital_output(),
+        ]
+    }
+
     pub fn wb(&mut self, a: u16, v: u8) {
+        if let Some(log) = self.apu_log.as_mut() {
+            log.record(self.time, a, v);
+        }
+        self.write_register(a, v);
+    }
+
+    fn write_register(&mut self, a: u16, v: u8) {
         if !self.on {
             // Allow writes to the length register when in DMG mode
             if self.dmg_mode {
@@ -760,6 +1075,7 @@ impl Sound {
             }
         }
         self.run();
+        self.registers[(a - 0xFF10) as usize] = v;
         match a {
             0xFF10..=0xFF14 => self.channel1.wb(a, v, self.frame_step),
             0xFF16..=0xFF19 => self.channel2.wb(a, v, self.frame_step),
@@ -776,7 +1092,7 @@ impl Sound {
                 if self.on && turn_on == false {
                     // Reset all registers to 0 when turning off
                     for i in 0xFF10..=0xFF25 {
-                        self.wb(i, 0);
+                        self.write_register(i, 0);
                     }
                 }
                 if !self.on && turn_on {
@@ -791,10 +1107,8 @@ impl Sound {
     }
 
     pub fn do_cycle(&mut self, cycles: u32) {
-        if !self.on {
-            return;
-        }
-
+        // While the APU is off all channels and DACs are disabled, but the output keeps
+        // running with silence. Hosts pace emulation by the audio, so it must not stop.
         self.time += cycles;
 
         if self.time >= self.output_period {
@@ -806,6 +1120,38 @@ impl Sound {
         self.need_sync = true;
     }
 
+    /// Passes all samples generated so far to the player, instead of waiting for a full buffer
+    pub fn flush(&mut self) {
+        if self.time > 0 {
+            self.do_output();
+        }
+    }
+
+    /// Scales the rate at which samples are generated, relative to the sample rate of the
+    /// player. Hosts use this to keep the amount of buffered audio constant. The ratio is
+    /// limited to 1 +/- 0.5%, which is not audible as a change in pitch. While recording the
+    /// ratio stays at 1, as the files must match the sample rate in their header.
+    pub fn set_rate_ratio(&mut self, ratio: f64) {
+        self.rate_ratio = match self.is_recording_any() {
+            true => 1.0,
+            false => ratio.clamp(1.0 - MAX_RATE_ADJUSTMENT, 1.0 + MAX_RATE_ADJUSTMENT),
+        };
+        let samples_rate = self.output_rate();
+        for blip in [
+            &mut self.channel1.blip,
+            &mut self.channel2.blip,
+            &mut self.channel3.blip,
+            &mut self.channel4.blip,
+        ] {
+            let _ = blip.set_rates(CLOCKS_PER_SECOND as f64, samples_rate);
+        }
+        self.output_period = output_period(samples_rate);
+    }
+
+    fn output_rate(&self) -> f64 {
+        self.player.samples_rate() as f64 * self.rate_ratio
+    }
+
     fn do_output(&mut self) {
         self.run();
         debug_assert!(self.time == self.prev_time);
@@ -814,12 +1160,18 @@ impl Sound {
         self.channel3.blip.end_frame(self.time);
         self.channel4.blip.end_frame(self.time);
         self.next_time -= self.time;
+        if let Some(log) = self.apu_log.as_mut() {
+            log.end_frame(self.time);
+        }
         self.time = 0;
         self.prev_time = 0;
 
         if !self.need_sync || self.player.underflowed() {
             self.need_sync = false;
-            self.mix_buffers();
+            self.mix_buffers(true);
+        } else if self.is_recording_any() {
+            // The recording must not have gaps, even when the player has enough data
+            self.mix_buffers(false);
         } else {
             // Prevent the BlipBuf's from filling up and triggering an assertion
             self.clear_buffers();
@@ -864,7 +1216,7 @@ impl Sound {
         }
     }
 
-    fn mix_buffers(&mut self) {
+    fn mix_buffers(&mut self, play: bool) {
         let sample_count = self.channel1.blip.samples_avail() as usize;
         debug_assert!(sample_count == self.channel2.blip.samples_avail() as usize);
         debug_assert!(sample_count == self.channel3.blip.samples_avail() as usize);
@@ -874,61 +1226,93 @@ impl Sound {
 
         let left_vol = (self.volume_left as f32 / 7.0) * (1.0 / 15.0) * 0.25;
         let right_vol = (self.volume_right as f32 / 7.0) * (1.0 / 15.0) * 0.25;
+        let gains = self.mix_gains();
+        let wants_channels = self.player.wants_channels();
 
         while outputted < sample_count {
             let buf_left = &mut [0f32; OUTPUT_SAMPLE_COUNT + 10];
             let buf_right = &mut [0f32; OUTPUT_SAMPLE_COUNT + 10];
+            // Panned output of each channel, before mute, solo and gain are applied
+            let channels = &mut [[[0f32; OUTPUT_SAMPLE_COUNT + 10]; 2]; 4];
             let buf = &mut [0i16; OUTPUT_SAMPLE_COUNT + 10];
-
-            let count1 = self.channel1.blip.read_samples(buf, false);
-            for (i, v) in buf[..count1].iter().enumerate() {
-                if self.reg_ff25 & 0x10 == 0x10 {
-                    buf_left[i] += *v as f32 * left_vol;
-                }
-                if self.reg_ff25 & 0x01 == 0x01 {
-                    buf_right[i] += *v as f32 * right_vol;
+            let levels = &mut [0f32; OUTPUT_SAMPLE_COUNT + 10];
+            let mut count = 0;
+
+            for (n, channel) in channels.iter_mut().enumerate() {
+                let (blip, dac_enabled) = match n {
+                    0 => (&mut self.channel1.blip, self.channel1.dac_enabled),
+                    1 => (&mut self.channel2.blip, self.channel2.dac_enabled),
+                    2 => (&mut self.channel3.blip, self.channel3.dac_enabled),
+                    _ => (&mut self.channel4.blip, self.channel4.dac_enabled),
+                };
+                let channel_count = blip.read_samples(buf, false);
+                debug_assert!(n == 0 || channel_count == count);
+                count = channel_count;
+
+                let leak = &mut self.blip_leaks[n];
+                for (level, v) in levels.iter_mut().zip(&buf[..count]) {
+                    *level = (*v as f64 + *leak as f64 / (1 << BLIP_BASS_SHIFT) as f64) as f32;
+                    *leak += *v as i64;
                 }
-            }
 
-            let count2 = self.channel2.blip.read_samples(buf, false);
-            for (i, v) in buf[..count2].iter().enumerate() {
-                if self.reg_ff25 & 0x20 == 0x20 {
-                    buf_left[i] += *v as f32 * left_vol;
-                }
-                if self.reg_ff25 & 0x02 == 0x02 {
-                    buf_right[i] += *v as f32 * right_vol;
+                let sides = [
+                    (self.reg_ff25 & (0x10 << n) != 0, left_vol),
+                    (self.reg_ff25 & (0x01 << n) != 0, right_vol),
+                ];
+                for (side, (panned, vol)) in sides.into_iter().enumerate() {
+                    let vol = if panned { vol } else { 0.0 };
+                    // Level of the DAC when the channel outputs 0
+                    let dc_offset = dac_output(dac_enabled, 0) as f32 * vol;
+                    let capacitor = &mut self.capacitors[n][side];
+
+                    for (i, level) in levels[..count].iter().enumerate() {
+                        let input = level * vol;
+                        channel[side][i] = match self.high_pass_filter {
+                            HighPassFilter::Accurate => {
+                                let output = input - *capacitor;
+                                *capacitor = input - output * self.charge_factor;
+                                output
+                            }
+                            HighPassFilter::PreserveWaveform => {
+                                *capacitor =
+                                    dc_offset + (*capacitor - dc_offset) * self.charge_factor;
+                                input - *capacitor
+                            }
+                            HighPassFilter::Off => input,
+                        };
+                    }
                 }
             }
 
-            // channel3 is the WaveChannel, that outputs samples with a 4x
-            // increase in amplitude in order to avoid a loss of precision.
-            let count3 = self.channel3.blip.read_samples(buf, false);
-            for (i, v) in buf[..count3].iter().enumerate() {
-                if self.reg_ff25 & 0x40 == 0x40 {
-                    buf_left[i] += ((*v as f32) / 4.0) * left_vol;
-                }
-                if self.reg_ff25 & 0x04 == 0x04 {
-                    buf_right[i] += ((*v as f32) / 4.0) * right_vol;
+            for (channel, gain) in channels.iter().zip(gains) {
+                for i in 0..count {
+                    buf_left[i] += channel[0][i] * gain;
+                    buf_right[i] += channel[1][i] * gain;
                 }
             }
 
-            let count4 = self.channel4.blip.read_samples(buf, false);
-            for (i, v) in buf[..count4].iter().enumerate() {
-                if self.reg_ff25 & 0x80 == 0x80 {
-                    buf_left[i] += *v as f32 * left_vol;
-                }
-                if self.reg_ff25 & 0x08 == 0x08 {
-                    buf_right[i] += *v as f32 * right_vol;
+            if let Some(recorder) = self.recorder.as_mut() {
+                recorder.write_stereo(&buf_left[..count], &buf_right[..count]);
+            }
+            if let Some(recorders) = self.channel_recorders.as_mut() {
+                for (recorder, channel) in recorders.iter_mut().zip(channels.iter()) {
+                    recorder.write_stereo(&channel[0][..count], &channel[1][..count]);
                 }
             }
+            if !play {
+                outputted += count;
+                continue;
+            }
 
-            debug_assert!(count1 == count2);
-            debug_assert!(count1 == count3);
-            debug_assert!(count1 == count4);
-
-            self.player.play(&buf_left[..count1], &buf_right[..count1]);
+            self.player.play(&buf_left[..count], &buf_right[..count]);
+            if wants_channels {
+                let streams = channels
+                    .each_ref()
+                    .map(|c| (&c[0][..count], &c[1][..count]));
+                self.player.play_channels(&streams);
+            }
 
-            outputted += count1;
+            outputted += count;
         }
     }
 
@@ -937,13 +1321,311 @@ impl Sound {
         self.channel2.blip.clear();
         self.channel3.blip.clear();
         self.channel4.blip.clear();
+
+        // Restore the current levels, the next deltas are relative to them
+        self.blip_leaks = [0; 4];
+        let _ = self.channel1.blip.add_delta(0, self.channel1.last_amp);
+        let _ = self.channel2.blip.add_delta(0, self.channel2.last_amp);
+        let _ = self.channel3.blip.add_delta(0, self.channel3.last_amp);
+        let _ = self.channel4.blip.add_delta(0, self.channel4.last_amp);
     }
 }
 
-fn create_blipbuf(samples_rate: u32) -> BlipBuf {
+// The DACs convert the digital value 0-15 to an analogue level. With the DAC turned off the
+// channel contributes nothing, which causes a pop when a DAC is switched.
+fn dac_output(enabled: bool, digital: u8) -> i32 {
+    if enabled {
+        digital as i32 * 2 - 15
+    } else {
+        0
+    }
+}
+
+// How much of the charge the capacitor keeps per output sample. The DMG and CGB use
+// different capacitors, the values are given per clock tick.
+fn charge_factor(dmg_mode: bool, samples_rate: u32) -> f32 {
+    let per_tick: f64 = if dmg_mode { 0.999958 } else { 0.998943 };
+    per_tick.powf(CLOCKS_PER_SECOND as f64 / samples_rate as f64) as f32
+}
+
+fn output_period(samples_rate: f64) -> u32 {
+    (OUTPUT_SAMPLE_COUNT as f64 * CLOCKS_PER_SECOND as f64 / samples_rate) as u32
+}
+
+fn create_blipbuf(samples_rate: f64) -> BlipBuf {
     // Create a BlipBuf which can hold OUTPUT_SAMPLE_COUNT + 1 samples.
     // Not sure why the +1 is needed. May need to correct the constant instead.
     let mut blipbuf = BlipBuf::new((OUTPUT_SAMPLE_COUNT + 1) as u32);
-    blipbuf.set_rates(CLOCKS_PER_SECOND as f64, samples_rate as f64);
+    blipbuf.set_rates(CLOCKS_PER_SECOND as f64, samples_rate);
     blipbuf
 }
+
+#[cfg(test)]
+mod test {
+    use super::{AudioPlayer, HighPassFilter, Sound, SoundChannel, CLOCKS_PER_SECOND};
+    use std::sync::{Arc, Mutex};
+
+    struct NullPlayer;
+
+    impl AudioPlayer for NullPlayer {
+        fn play(&mut self, _left_channel: &[f32], _right_channel: &[f32]) {}
+
+        fn samples_rate(&self) -> u32 {
+            44100
+        }
+
+        fn underflowed(&self) -> bool {
+            false
+        }
+    }
+
+    #[test]
+    fn pcm_registers() {
+        let mut sound = Sound::new_cgb(Box::new(NullPlayer));
+        sound.wb(0xFF26, 0x80);
+        sound.wb(0xFF12, 0xF0);
+        sound.wb(0xFF11, 0x80);
+        sound.wb(0xFF14, 0x87);
+        assert_eq!(sound.rb(0xFF77), 0x00);
+
+        let mut seen = [false; 16];
+        for _ in 0..1000 {
+            sound.do_cycle(4);
+            let pcm12 = sound.rb(0xFF76);
+            assert_eq!(pcm12 & 0xF0, 0x00);
+            seen[pcm12 as usize & 0x0F] = true;
+        }
+        assert!(seen[0x0] && seen[0xF]);
+        assert_eq!(seen.iter().filter(|&&v| v).count(), 2);
+        assert_eq!(sound.channel_amplitudes()[1..], [0, 0, 0]);
+    }
+
+    #[derive(Default)]
+    struct Recording {
+        mixed: Vec<f32>,
+        channels: [Vec<f32>; 4],
+    }
+
+    struct RecordingPlayer(Arc<Mutex<Recording>>);
+
+    impl AudioPlayer for RecordingPlayer {
+        fn play(&mut self, left_channel: &[f32], _right_channel: &[f32]) {
+            self.0.lock().unwrap().mixed.extend_from_slice(left_channel);
+        }
+
+        fn samples_rate(&self) -> u32 {
+            44100
+        }
+
+        fn underflowed(&self) -> bool {
+            false
+        }
+
+        fn wants_channels(&self) -> bool {
+            true
+        }
+
+        fn play_channels(&mut self, channels: &[(&[f32], &[f32]); 4]) {
+            let mut recording = self.0.lock().unwrap();
+            for (n, (left, _right)) in channels.iter().enumerate() {
+                recording.channels[n].extend_from_slice(left);
+            }
+        }
+    }
+
+    #[test]
+    fn channel_streams() {
+        let recording = Arc::new(Mutex::new(Recording::default()));
+        let mut sound = Sound::new_cgb(Box::new(RecordingPlayer(recording.clone())));
+        sound.wb(0xFF26, 0x80);
+        sound.wb(0xFF24, 0x77);
+        sound.wb(0xFF25, 0xFF);
+        sound.wb(0xFF12, 0xF0);
+        sound.wb(0xFF14, 0x87);
+        sound.wb(0xFF17, 0xA0);
+        sound.wb(0xFF19, 0x86);
+        sound.set_muted(SoundChannel::Square2, true);
+        for _ in 0..CLOCKS_PER_SECOND / 1000 {
+            sound.do_cycle(100);
+        }
+
+        let recording = recording.lock().unwrap();
+        assert!(!recording.mixed.is_empty());
+        for channel in &recording.channels {
+            assert_eq!(channel.len(), recording.mixed.len());
+        }
+        // The muted channel is still present in its own stream, but not in the mix
+        assert!(recording.channels[1].iter().any(|&v| v != 0.0));
+        for i in 0..recording.mixed.len() {
+            let channels = &recording.channels;
+            let expected = channels[0][i] + channels[2][i] + channels[3][i];
+            assert!((recording.mixed[i] - expected).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn channel_recording() {
+        let dir = std::env::temp_dir().join("gb_emulator_channel_recording");
+        let mut sound = Sound::new_cgb(Box::new(NullPlayer));
+        sound.start_channel_recording(&dir).unwrap();
+        sound.wb(0xFF26, 0x80);
+        sound.wb(0xFF24, 0x77);
+        sound.wb(0xFF25, 0x10);
+        sound.wb(0xFF12, 0xF0);
+        sound.wb(0xFF14, 0x87);
+        for _ in 0..CLOCKS_PER_SECOND / 1000 {
+            sound.do_cycle(100);
+        }
+        sound.flush();
+        sound.stop_channel_recording().unwrap();
+
+        let files: Vec<Vec<u8>> = super::CHANNEL_FILE_NAMES
+            .iter()
+            .map(|name| std::fs::read(dir.join(name)).unwrap())
+            .collect();
+        let _ = std::fs::remove_dir_all(&dir);
+        // All tracks have the same length, only the square channel on the left makes a sound
+        let data: Vec<&[u8]> = files.iter().map(|file| &file[58..]).collect();
+        assert!(data[0].len() > 4000);
+        assert!(data.iter().all(|d| d.len() == data[0].len()));
+        let samples = |d: &[u8]| -> Vec<f32> {
+            let bytes = d.chunks_exact(4).map(|b| [b[0], b[1], b[2], b[3]]);
+            bytes.map(f32::from_le_bytes).collect()
+        };
+        let square = samples(data[0]);
+        assert!(square.iter().step_by(2).any(|&v| v != 0.0));
+        assert!(square.iter().skip(1).step_by(2).all(|&v| v == 0.0));
+        assert!(data[1..]
+            .iter()
+            .all(|d| samples(d).iter().all(|&v| v == 0.0)));
+    }
+
+    #[test]
+    fn recording_ignores_rate_ratio() {
+        let path = std::env::temp_dir().join("gb_emulator_recording_rate.wav");
+        let mut sound = Sound::new_cgb(Box::new(NullPlayer));
+        sound.set_rate_ratio(1.005);
+        sound.do_cycle(64);
+        sound.start_recording(&path).unwrap();
+        // Hosts keep adjusting the rate while the recording runs
+        for _ in 0..CLOCKS_PER_SECOND / 64 {
+            sound.set_rate_ratio(1.005);
+            sound.do_cycle(64);
+        }
+        sound.flush();
+        sound.stop_recording().unwrap();
+
+        let size = std::fs::metadata(&path).unwrap().len();
+        let _ = std::fs::remove_file(&path);
+        // One second of stereo samples at the nominal rate
+        let frames = (size - 58) / 8;
+        assert!(frames.abs_diff(44100) <= 1, "{} frames", frames);
+    }
+
+    fn record_silent_dac(filter: HighPassFilter, dac_on: bool) -> Vec<f32> {
+        let recording = Arc::new(Mutex::new(Recording::default()));
+        let mut sound = Sound::new_dmg(Box::new(RecordingPlayer(recording.clone())));
+        sound.set_high_pass_filter(filter);
+        sound.wb(0xFF26, 0x80);
+        sound.wb(0xFF24, 0x77);
+        sound.wb(0xFF25, 0x11);
+        // The DAC of channel 1 is on, but the channel is never triggered
+        sound.wb(0xFF12, if dac_on { 0x08 } else { 0x00 });
+        for _ in 0..CLOCKS_PER_SECOND / 100 {
+            sound.do_cycle(100);
+        }
+        let mixed = recording.lock().unwrap().mixed.clone();
+        mixed
+    }
+
+    #[test]
+    fn dac_offset_and_high_pass() {
+        // Without a filter, an enabled DAC adds a constant offset
+        let raw = record_silent_dac(HighPassFilter::Off, true);
+        assert!(raw.len() > 1000);
+        assert!(raw[raw.len() / 2..]
+            .iter()
+            .all(|&v| (v + 0.25).abs() < 1e-3));
+
+        // The filters remove it again
+        for filter in [HighPassFilter::Accurate, HighPassFilter::PreserveWaveform] {
+            let filtered = record_silent_dac(filter, true);
+            assert!(filtered.last().unwrap().abs() < 1e-3);
+        }
+
+        // A disabled DAC does not output anything
+        let off = record_silent_dac(HighPassFilter::Off, false);
+        assert!(off.iter().all(|&v| v == 0.0));
+    }
+
+    #[test]
+    fn rate_ratio() {
+        let recording = Arc::new(Mutex::new(Recording::default()));
+        let mut sound = Sound::new_cgb(Box::new(RecordingPlayer(recording.clone())));
+        sound.wb(0xFF26, 0x80);
+        // Limited to +0.5%
+        sound.set_rate_ratio(1.01);
+        for _ in 0..CLOCKS_PER_SECOND / 64 {
+            sound.do_cycle(64);
+        }
+        sound.flush();
+
+        let samples = recording.lock().unwrap().mixed.len() as i64;
+        assert!((samples - 44320).abs() <= 2, "{} samples", samples);
+    }
+
+    #[test]
+    fn mute_and_solo() {
+        let mut sound = Sound::new_dmg(Box::new(NullPlayer));
+        sound.set_gain(SoundChannel::Wave, 0.5);
+        sound.set_muted(SoundChannel::Square2, true);
+        assert_eq!(sound.mix_gains(), [1.0, 0.0, 0.5, 1.0]);
+
+        sound.set_solo(SoundChannel::Square2, true);
+        sound.set_solo(SoundChannel::Noise, true);
+        assert_eq!(sound.mix_gains(), [0.0, 0.0, 0.0, 1.0]);
+
+        // Mixer settings survive a reset of the APU
+        sound.reset();
+        assert!(sound.solo(SoundChannel::Noise));
+        assert_eq!(sound.gain(SoundChannel::Wave), 0.5);
+    }
+
+    #[test]
+    fn apu_log() {
+        let mut sound = Sound::new_dmg(Box::new(NullPlayer));
+        sound.wb(0xFF26, 0x80);
+        sound.wb(0xFF12, 0xF0);
+        sound.wb(0xFF14, 0x87);
+        sound.do_cycle(100);
+
+        sound.start_apu_log();
+        // Longer than one output period, so the sound unit restarts its time in between
+        sound.do_cycle(200_000);
+        sound.wb(0xFF26, 0x00);
+        sound.do_cycle(10);
+        let log = sound.stop_apu_log().unwrap();
+        assert!(sound.stop_apu_log().is_none());
+
+        let writes = log.writes();
+        assert_eq!(
+            (writes[0].cycle, writes[0].address, writes[0].value),
+            (0, 0xFF26, 0x80)
+        );
+        // The playing channel 1 is triggered, the silent channel 2 is not
+        assert!(writes
+            .iter()
+            .any(|w| w.address == 0xFF14 && w.value == 0x87));
+        assert!(writes
+            .iter()
+            .any(|w| w.address == 0xFF19 && w.value & 0x80 == 0));
+        // Turning the APU off clears the registers, but the log only has the write to NR52
+        let last = writes.last().unwrap();
+        assert_eq!(
+            (last.cycle, last.address, last.value),
+            (200_000, 0xFF26, 0x00)
+        );
+        assert_eq!(writes.iter().filter(|w| w.cycle > 0).count(), 1);
+        assert_eq!(log.cycles(), 200_010);
+    }
+}
@@ -0,0 +1,314 @@
+use crate::image::Image;
+use crate::{Error, Result};
+use std::fmt;
+use std::path::Path;
+
+// Dots per line and lines per frame, including VBlank
+const DOTS_PER_LINE: usize = 456;
+const LINES_PER_FRAME: usize = 154;
+// Dots of the OAM scan (mode 2) and the drawing of the pixels (mode 3)
+const OAM_SCAN_DOTS: usize = 80;
+const DRAWING_DOTS: usize = 172;
+// Events kept per frame, the LCD may be off for a long time without finishing a frame
+const MAX_EVENTS: usize = 0x10000;
+// Width of the columns next to the lines that show the interrupts raised, one per interrupt
+const INTERRUPT_COLUMN_W: usize = 3;
+const INTERRUPT_COUNT: usize = 5;
+
+const OAM_SCAN_COLOR: [u8; 3] = [0x30, 0x30, 0x48];
+const HBLANK_COLOR: [u8; 3] = [0x28, 0x28, 0x28];
+const VBLANK_COLOR: [u8; 3] = [0x18, 0x18, 0x30];
+
+#[rustfmt::skip]
+const INTERRUPT_NAMES: [&str; INTERRUPT_COUNT] = ["VBlank", "STAT", "Timer", "Serial", "Joypad"];
+#[rustfmt::skip]
+const INTERRUPT_COLORS: [[u8; 3]; INTERRUPT_COUNT] = [
+    [0xFF, 0xFF, 0xFF], // VBlank
+    [0xFF, 0x40, 0x40], // STAT
+    [0x40, 0xFF, 0x40], // Timer
+    [0x40, 0x80, 0xFF], // Serial
+    [0xFF, 0xFF, 0x40], // Joypad
+];
+
+/// A write to a PPU register, at the position of the LCD when it happened. `dot` counts
+/// from 0 to 455 within the line, the line is LY.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PpuWrite {
+    pub line: u8,
+    pub dot: u16,
+    pub address: u16,
+    pub value: u8,
+}
+
+impl PpuWrite {
+    /// The name of the register, like `SCX`
+    pub fn register_name(&self) -> &'static str {
+        match self.address {
+            0xFF40 => "LCDC",
+            0xFF41 => "STAT",
+            0xFF42 => "SCY",
+            0xFF43 => "SCX",
+            0xFF45 => "LYC",
+            0xFF47 => "BGP",
+            0xFF48 => "OBP0",
+            0xFF49 => "OBP1",
+            0xFF4A => "WY",
+            0xFF4B => "WX",
+            0xFF68 => "BCPS",
+            0xFF69 => "BCPD",
+            0xFF6A => "OCPS",
+            0xFF6B => "OCPD",
+            _ => "?",
+        }
+    }
+
+    // The colour of the marker in the overlay, registers with a similar purpose share one
+    fn color(&self) -> [u8; 3] {
+        match self.address {
+            0xFF40 => [0xFF, 0x40, 0x40],
+            0xFF41 | 0xFF45 => [0xFF, 0xA0, 0x00],
+            0xFF42 | 0xFF43 => [0x40, 0xFF, 0x40],
+            0xFF4A | 0xFF4B => [0x40, 0xFF, 0xFF],
+            0xFF47..=0xFF49 => [0xFF, 0xFF, 0x40],
+            _ => [0xFF, 0x40, 0xFF],
+        }
+    }
+}
+
+/// Interrupts raised at a position of the LCD, `flags` uses the bits of the IF register
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct InterruptEvent {
+    pub line: u8,
+    pub dot: u16,
+    pub flags: u8,
+}
+
+/// The PPU register writes and raised interrupts of one frame, in the order they happened.
+/// Events while the LCD is off are placed at line 0, dot 0 of the next frame.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FrameTimeline {
+    pub writes: Vec<PpuWrite>,
+    pub interrupts: Vec<InterruptEvent>,
+}
+
+impl FrameTimeline {
+    /// The registers written while the LCD was on a line
+    pub fn writes_on_line(&self, line: u8) -> impl Iterator<Item = &PpuWrite> {
+        self.writes.iter().filter(move |write| write.line == line)
+    }
+
+    /// The interrupts raised on a line, as bits of the IF register
+    pub fn interrupts_on_line(&self, line: u8) -> u8 {
+        let events = self.interrupts.iter().filter(|event| event.line == line);
+        events.fold(0, |flags, event| flags | event.flags)
+    }
+
+    /// Draws the frame with a pixel per dot and a row per line. The background shows the
+    /// modes of the PPU with the picture in the dots of mode 3, writes are marked in the colour
+    /// of their register and the columns on the right show the interrupts raised on each line.
+    pub fn render(&self, screen: &Image) -> Image {
+        let width = DOTS_PER_LINE + INTERRUPT_COUNT * INTERRUPT_COLUMN_W;
+        let mut image = Image::new(width, LINES_PER_FRAME);
+        image.fill_rect(0, 0, OAM_SCAN_DOTS, screen.height, OAM_SCAN_COLOR);
+        let hblank = OAM_SCAN_DOTS + DRAWING_DOTS;
+        image.fill_rect(
+            hblank,
+            0,
+            DOTS_PER_LINE - hblank,
+            screen.height,
+            HBLANK_COLOR,
+        );
+        let vblank_lines = LINES_PER_FRAME - screen.height;
+        image.fill_rect(0, screen.height, DOTS_PER_LINE, vblank_lines, VBLANK_COLOR);
+        for y in 0..screen.height {
+            for x in 0..screen.width {
+                image.set_pixel(OAM_SCAN_DOTS + x, y, screen.pixel(x, y));
+            }
+        }
+
+        // Three dots wide, a single pixel is hard to find
+        for write in &self.writes {
+            let x = (write.dot as usize).min(DOTS_PER_LINE - 3);
+            image.fill_rect(x, write.line as usize, 3, 1, write.color());
+        }
+        for event in &self.interrupts {
+            for (i, &color) in INTERRUPT_COLORS.iter().enumerate() {
+                if event.flags & (1 << i) != 0 {
+                    let x = DOTS_PER_LINE + i * INTERRUPT_COLUMN_W;
+                    image.fill_rect(x, event.line as usize, INTERRUPT_COLUMN_W, 1, color);
+                }
+            }
+        }
+        image
+    }
+
+    /// Writes the events as text, one per line
+    pub fn save_text(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, self.to_string()).map_err(|source| Error::Io {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+}
+
+/// One event per line, like `LY 012 dot 252: SCX = 04`
+impl fmt::Display for FrameTimeline {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut writes = self.writes.iter().peekable();
+        let mut interrupts = self.interrupts.iter().peekable();
+        loop {
+            // Merges both lists by their position, writes first
+            let next_write = writes.peek().map(|w| (w.line, w.dot));
+            let next_interrupt = interrupts.peek().map(|i| (i.line, i.dot));
+            match (next_write, next_interrupt) {
+                (Some(w), Some(i)) if w <= i => {}
+                (Some(_), None) => {}
+                (_, Some(_)) => {
+                    let event = interrupts.next().unwrap();
+                    let names: Vec<&str> = (0..INTERRUPT_COUNT)
+                        .filter(|i| event.flags & (1 << i) != 0)
+                        .map(|i| INTERRUPT_NAMES[i])
+                        .collect();
+                    let names = names.join(", ");
+                    writeln!(
+                        f,
+                        "LY {:03} dot {:03}: interrupt {}",
+                        event.line, event.dot, names
+                    )?;
+                    continue;
+                }
+                (None, None) => return Ok(()),
+            }
+            let write = writes.next().unwrap();
+            writeln!(
+                f,
+                "LY {:03} dot {:03}: {} = {:02X}",
+                write.line,
+                write.dot,
+                write.register_name(),
+                write.value
+            )?;
+        }
+    }
+}
+
+/// Collects the timeline of the current frame and keeps the one of the last finished frame
+#[derive(Clone, Default, PartialEq)]
+pub(crate) struct TimelineRecorder {
+    current: FrameTimeline,
+    last: FrameTimeline,
+}
+
+impl TimelineRecorder {
+    pub(crate) fn new() -> TimelineRecorder {
+        TimelineRecorder::default()
+    }
+
+    /// The last finished frame
+    pub(crate) fn last(&self) -> &FrameTimeline {
+        &self.last
+    }
+
+    pub(crate) fn write(&mut self, line: u8, dot: u32, address: u16, value: u8) {
+        if self.current.writes.len() < MAX_EVENTS {
+            self.current.writes.push(PpuWrite {
+                line,
+                dot: dot as u16,
+                address,
+                value,
+            });
+        }
+    }
+
+    pub(crate) fn interrupts(&mut self, line: u8, dot: u32, flags: u8) {
+        if flags != 0 && self.current.interrupts.len() < MAX_EVENTS {
+            self.current.interrupts.push(InterruptEvent {
+                line,
+                dot: dot as u16,
+                flags,
+            });
+        }
+    }
+
+    pub(crate) fn finish_frame(&mut self) {
+        self.last = std::mem::take(&mut self.current);
+    }
+}
+
+/// The registers that are recorded: LCDC to WX except LY and DMA, and the CGB palettes
+pub(crate) fn is_timeline_register(address: u16) -> bool {
+    matches!(address, 0xFF40..=0xFF43 | 0xFF45 | 0xFF47..=0xFF4B | 0xFF68..=0xFF6B)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::gpu::GPU;
+    use crate::image::Image;
+
+    // Runs the GPU up to a position of the current frame, passing the interrupts on like the
+    // MMU does
+    fn run_to(gpu: &mut GPU, line: u8, dot: u32) {
+        fn step(gpu: &mut GPU) {
+            gpu.do_cycle(4);
+            gpu.record_interrupts(gpu.interrupt);
+            gpu.interrupt = 0;
+        }
+        while gpu.rb(0xFF44) != line {
+            step(gpu);
+        }
+        for _ in 0..dot / 4 {
+            step(gpu);
+        }
+    }
+
+    #[test]
+    fn frame_timeline() {
+        let mut gpu = GPU::new();
+        gpu.set_timeline_enabled(true);
+        // Turns the LCD on with the LYC interrupt for line 100
+        gpu.wb(0xFF45, 100);
+        gpu.wb(0xFF41, 0x40);
+        gpu.wb(0xFF40, 0x91);
+        run_to(&mut gpu, 152, 0);
+        run_to(&mut gpu, 0, 0);
+
+        // A raster effect changing the scroll position in the middle of the screen
+        run_to(&mut gpu, 72, 256);
+        gpu.wb(0xFF43, 0x20);
+        run_to(&mut gpu, 100, 8);
+        gpu.wb(0xFF47, 0xE4);
+        run_to(&mut gpu, 152, 0);
+        assert!(gpu
+            .timeline()
+            .unwrap()
+            .writes
+            .iter()
+            .all(|write| write.line == 0));
+        run_to(&mut gpu, 0, 0);
+
+        let timeline = gpu.timeline().unwrap();
+        let writes: Vec<_> = timeline
+            .writes
+            .iter()
+            .map(|w| (w.line, w.address))
+            .collect();
+        assert_eq!(writes, [(72, 0xFF43), (100, 0xFF47)]);
+        let scx = timeline.writes_on_line(72).next().unwrap();
+        assert!((256..264).contains(&scx.dot));
+        assert_eq!(scx.register_name(), "SCX");
+        assert_eq!(timeline.interrupts_on_line(100), 0x02);
+        assert_eq!(timeline.interrupts_on_line(144), 0x01);
+        assert_eq!(timeline.interrupts_on_line(50), 0);
+
+        let text = timeline.to_string();
+        assert!(text.starts_with("LY 072 dot 2"));
+        assert!(text.contains(": SCX = 20\n"));
+        assert!(text.contains(": interrupt STAT\n"));
+
+        let overlay = timeline.render(&Image::new(160, 144));
+        assert_eq!((overlay.width, overlay.height), (471, 154));
+        assert_eq!(overlay.pixel(scx.dot as usize + 1, 72), [0x40, 0xFF, 0x40]);
+        assert_eq!(overlay.pixel(456 + 3, 100), [0xFF, 0x40, 0x40]);
+        assert_eq!(overlay.pixel(456, 144), [0xFF, 0xFF, 0xFF]);
+    }
+}
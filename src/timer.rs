@@ -38,7 +38,7 @@ impl Timer {
                         _ => 0,
                     })
             }
-            _ => panic!("Timer does not handler read {:4X}", a),
+            _ => 0xFF,
         }
     }
 
@@ -62,7 +62,7 @@ impl Timer {
                     _ => 1024,
                 };
             }
-            _ => panic!("Timer does not handler write {:4X}", a),
+            _ => {}
         };
     }
 
@@ -0,0 +1,162 @@
+use crate::{Error, Result};
+use std::path::Path;
+
+const CLOCKS_PER_SECOND: u64 = 1 << 22;
+// VGM files count time in samples at 44100 Hz
+const VGM_SAMPLE_RATE: u64 = 44100;
+const VGM_VERSION: u32 = 0x161;
+const HEADER_SIZE: usize = 0x100;
+
+const CMD_GB_DMG_WRITE: u8 = 0xB3;
+const CMD_WAIT: u8 = 0x61;
+const CMD_WAIT_SHORT: u8 = 0x70;
+const CMD_END: u8 = 0x66;
+
+/// A write to a sound register, `cycle` counts clock ticks from the start of the log
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ApuWrite {
+    pub cycle: u64,
+    pub address: u16,
+    pub value: u8,
+}
+
+/// Every write to the sound registers (FF10-FF3F) while logging was active. The log starts
+/// with writes that bring a powered off APU into the state it was in when logging started.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApuLog {
+    writes: Vec<ApuWrite>,
+    // Time of the sound unit at which the log started
+    start: u64,
+    // Clock ticks until the start of the current output frame of the sound unit
+    frame_start: u64,
+    cycles: u64,
+}
+
+impl ApuLog {
+    // `time` is the current time within the output frame of the sound unit
+    pub(crate) fn new(time: u32) -> ApuLog {
+        ApuLog {
+            writes: Vec::new(),
+            start: time as u64,
+            frame_start: 0,
+            cycles: 0,
+        }
+    }
+
+    pub(crate) fn record(&mut self, time: u32, address: u16, value: u8) {
+        self.writes.push(ApuWrite {
+            cycle: self.frame_start + time as u64 - self.start,
+            address,
+            value,
+        });
+    }
+
+    // Called when the sound unit restarts counting its time at 0
+    pub(crate) fn end_frame(&mut self, time: u32) {
+        self.frame_start += time as u64;
+    }
+
+    pub(crate) fn finish(&mut self, time: u32) {
+        self.cycles = self.frame_start + time as u64 - self.start;
+    }
+
+    pub fn writes(&self) -> &[ApuWrite] {
+        &self.writes
+    }
+
+    /// Length of the log in clock ticks
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Encodes the log as a VGM 1.61 file for the Game Boy DMG sound chip
+    pub fn to_vgm(&self) -> Vec<u8> {
+        let mut res = vec![0; HEADER_SIZE];
+        let mut samples = 0;
+        for write in &self.writes {
+            let target = to_samples(write.cycle);
+            wait(&mut res, target - samples);
+            samples = target;
+            res.extend_from_slice(&[
+                CMD_GB_DMG_WRITE,
+                (write.address - 0xFF10) as u8,
+                write.value,
+            ]);
+        }
+        let total = to_samples(self.cycles).max(samples);
+        wait(&mut res, total - samples);
+        res.push(CMD_END);
+
+        let len = res.len() as u32;
+        let mut set = |offset: usize, value: u32| {
+            res[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        };
+        set(0x04, len - 4);
+        set(0x08, VGM_VERSION);
+        set(0x18, total as u32);
+        // The data offset is relative to its own position
+        set(0x34, (HEADER_SIZE - 0x34) as u32);
+        set(0x80, CLOCKS_PER_SECOND as u32);
+        res[0..4].copy_from_slice(b"Vgm ");
+        res
+    }
+
+    pub fn save_vgm(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, self.to_vgm()).map_err(|source| Error::Io {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+}
+
+fn to_samples(cycle: u64) -> u64 {
+    cycle * VGM_SAMPLE_RATE / CLOCKS_PER_SECOND
+}
+
+fn wait(data: &mut Vec<u8>, mut samples: u64) {
+    while samples > 16 {
+        let n = samples.min(0xFFFF);
+        data.push(CMD_WAIT);
+        data.extend_from_slice(&(n as u16).to_le_bytes());
+        samples -= n;
+    }
+    if samples > 0 {
+        data.push(CMD_WAIT_SHORT + samples as u8 - 1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ApuLog;
+
+    #[test]
+    fn vgm_encoding() {
+        let mut log = ApuLog::new(0);
+        log.record(0, 0xFF26, 0x80);
+        // 1 second later, 44100 samples
+        log.end_frame(1 << 22);
+        log.record(0, 0xFF12, 0xF0);
+        log.record(952, 0xFF30, 0x12);
+        log.finish(952);
+
+        let vgm = log.to_vgm();
+        let u32_at = |i: usize| u32::from_le_bytes([vgm[i], vgm[i + 1], vgm[i + 2], vgm[i + 3]]);
+        assert_eq!(&vgm[0..4], b"Vgm ");
+        assert_eq!(u32_at(0x04) as usize, vgm.len() - 4);
+        assert_eq!(u32_at(0x08), 0x161);
+        assert_eq!(u32_at(0x18), 44110);
+        assert_eq!(u32_at(0x80), 4194304);
+        assert_eq!(0x34 + u32_at(0x34) as usize, 0x100);
+        assert_eq!(
+            &vgm[0x100..],
+            &[
+                0xB3, 0x16, 0x80, // NR52
+                0x61, 0x44, 0xAC, // 44100 samples
+                0xB3, 0x02, 0xF0, // NR12
+                0x79, // 10 samples
+                0xB3, 0x20, 0x12, // Wave RAM
+                0x66,
+            ][..]
+        );
+    }
+}
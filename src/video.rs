@@ -0,0 +1,177 @@
+use crate::{Error, Result};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+// Clock ticks per frame of the LCD
+const TICKS_PER_FRAME: u64 = 70224;
+// The frame rate of 4194304 / 70224 Hz, reduced
+const FRAME_RATE: &str = "262144:4389";
+
+/// Writes uncompressed YUV4MPEG2 (Y4M) video files with full resolution chroma (4:4:4)
+pub struct Y4mWriter {
+    file: BufWriter<File>,
+    path: PathBuf,
+    width: usize,
+    height: usize,
+    frames: u64,
+    error: Option<io::Error>,
+}
+
+impl Y4mWriter {
+    pub fn create<P: AsRef<Path>>(path: P, width: usize, height: usize) -> Result<Y4mWriter> {
+        let path = path.as_ref().to_path_buf();
+        let file = File::create(&path).map_err(|source| Error::Io {
+            path: path.clone(),
+            source,
+        })?;
+        let mut res = Y4mWriter {
+            file: BufWriter::new(file),
+            path,
+            width,
+            height,
+            frames: 0,
+            error: None,
+        };
+        let header = format!(
+            "YUV4MPEG2 W{} H{} F{} Ip A1:1 C444\n",
+            width, height, FRAME_RATE
+        );
+        res.write_bytes(header.as_bytes());
+        res.check()?;
+        Ok(res)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// Appends an RGB888 frame of the size given to `create`. Write errors are reported by
+    /// `finish`.
+    pub fn write_frame(&mut self, rgb: &[u8]) {
+        debug_assert_eq!(rgb.len(), self.width * self.height * 3);
+        // BT.601 with the limited range most players expect
+        let mut planes = vec![0; rgb.len()];
+        let plane_size = self.width * self.height;
+        for (i, pixel) in rgb.chunks_exact(3).enumerate() {
+            let [r, g, b] = [pixel[0], pixel[1], pixel[2]].map(|c| c as i32);
+            planes[i] = (((66 * r + 129 * g + 25 * b + 128) >> 8) + 16) as u8;
+            planes[plane_size + i] = (((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128) as u8;
+            planes[plane_size * 2 + i] = (((112 * r - 94 * g - 18 * b + 128) >> 8) + 128) as u8;
+        }
+        self.write_bytes(b"FRAME\n");
+        self.write_bytes(&planes);
+        self.frames += 1;
+    }
+
+    /// Flushes the file
+    pub fn finish(mut self) -> Result<()> {
+        if let Err(e) = self.file.flush() {
+            self.error.get_or_insert(e);
+        }
+        self.check()
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        if self.error.is_some() {
+            return;
+        }
+        if let Err(e) = self.file.write_all(bytes) {
+            self.error = Some(e);
+        }
+    }
+
+    fn check(&mut self) -> Result<()> {
+        match self.error.take() {
+            Some(source) => Err(Error::Io {
+                path: self.path.clone(),
+                source,
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Places the frames of the GPU in a video with a constant frame rate, following the emulated
+/// time. Frames the GPU does not produce, e.g. while the LCD is off, repeat the last frame, so
+/// the video stays in sync with the audio recording.
+pub(crate) struct VideoRecorder {
+    writer: Y4mWriter,
+    cycles: u64,
+    last_frame: Vec<u8>,
+}
+
+impl VideoRecorder {
+    /// `screen` is shown until the first frame arrives
+    pub(crate) fn new(writer: Y4mWriter, screen: Vec<u8>) -> VideoRecorder {
+        VideoRecorder {
+            writer,
+            cycles: 0,
+            last_frame: screen,
+        }
+    }
+
+    pub(crate) fn add_cycles(&mut self, ticks: u32) {
+        self.cycles += ticks as u64;
+    }
+
+    pub(crate) fn frame(&mut self, rgb: &[u8]) {
+        self.repeat_last_frame();
+        self.writer.write_frame(rgb);
+        self.last_frame.clear();
+        self.last_frame.extend_from_slice(rgb);
+    }
+
+    pub(crate) fn finish(mut self) -> Result<()> {
+        self.repeat_last_frame();
+        self.writer.finish()
+    }
+
+    // Fills the time up to now with the last frame
+    fn repeat_last_frame(&mut self) {
+        let target = (self.cycles + TICKS_PER_FRAME / 2) / TICKS_PER_FRAME;
+        while self.writer.frames() < target {
+            self.writer.write_frame(&self.last_frame);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{VideoRecorder, Y4mWriter, TICKS_PER_FRAME};
+
+    #[test]
+    fn frame_timing() {
+        let path = std::env::temp_dir().join("gb_emulator_frame_timing.y4m");
+        let writer = Y4mWriter::create(&path, 2, 1).unwrap();
+        let mut video = VideoRecorder::new(writer, vec![0; 6]);
+
+        // The first frame arrives late, the screen at the start is shown before it
+        video.add_cycles(TICKS_PER_FRAME as u32 * 3 / 4);
+        video.frame(&[0xFF; 6]);
+        // The LCD is off for three frames
+        video.add_cycles(TICKS_PER_FRAME as u32 * 4);
+        video.frame(&[0x00, 0x00, 0xFF, 0xFF, 0x00, 0x00]);
+        video.add_cycles(TICKS_PER_FRAME as u32);
+        video.finish().unwrap();
+
+        let data = std::fs::read(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let header = b"YUV4MPEG2 W2 H1 F262144:4389 Ip A1:1 C444\n";
+        assert_eq!(&data[..header.len()], header);
+        let frames: Vec<&[u8]> = data[header.len()..].chunks(12).collect();
+        assert_eq!(frames.len(), 6);
+        assert!(frames.iter().all(|frame| &frame[..6] == b"FRAME\n"));
+        let black = [16, 16, 128, 128, 128, 128];
+        let white = [235, 235, 128, 128, 128, 128];
+        let mixed = [41, 82, 240, 90, 110, 240];
+        let expected = [black, white, white, white, white, mixed];
+        for (frame, expected) in frames.iter().zip(expected) {
+            assert_eq!(frame[6..], expected);
+        }
+    }
+}
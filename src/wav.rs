@@ -0,0 +1,163 @@
+use crate::{Error, Result};
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+const HEADER_SIZE: u32 = 58;
+
+/// Writes 32-bit float WAV files. The sizes in the header are filled in by `finish`.
+pub struct WavWriter {
+    file: BufWriter<File>,
+    path: PathBuf,
+    channels: u16,
+    frames: u32,
+    error: Option<io::Error>,
+    finished: bool,
+}
+
+impl WavWriter {
+    pub fn create<P: AsRef<Path>>(path: P, sample_rate: u32, channels: u16) -> Result<WavWriter> {
+        let path = path.as_ref().to_path_buf();
+        let file = File::create(&path).map_err(|source| Error::Io {
+            path: path.clone(),
+            source,
+        })?;
+        let mut res = WavWriter {
+            file: BufWriter::new(file),
+            path,
+            channels,
+            frames: 0,
+            error: None,
+            finished: false,
+        };
+        let header = res.header(sample_rate);
+        res.write_bytes(&header);
+        res.check()?;
+        Ok(res)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Appends interleaved samples. Write errors are reported by `finish`.
+    pub fn write_samples(&mut self, samples: &[f32]) {
+        debug_assert!(samples.len().is_multiple_of(self.channels as usize));
+        for sample in samples {
+            self.write_bytes(&sample.to_le_bytes());
+        }
+        self.frames += (samples.len() / self.channels as usize) as u32;
+    }
+
+    /// Appends a block of stereo samples to a two channel file
+    pub fn write_stereo(&mut self, left: &[f32], right: &[f32]) {
+        debug_assert!(self.channels == 2 && left.len() == right.len());
+        for (l, r) in left.iter().zip(right) {
+            self.write_bytes(&l.to_le_bytes());
+            self.write_bytes(&r.to_le_bytes());
+        }
+        self.frames += left.len() as u32;
+    }
+
+    /// Completes the header and flushes the file
+    pub fn finish(mut self) -> Result<()> {
+        self.finished = true;
+        self.finalize();
+        self.check()
+    }
+
+    fn header(&self, sample_rate: u32) -> Vec<u8> {
+        let block_align = self.channels * 4;
+        let mut header = Vec::with_capacity(HEADER_SIZE as usize);
+        header.extend_from_slice(b"RIFF");
+        header.extend_from_slice(&(HEADER_SIZE - 8).to_le_bytes());
+        header.extend_from_slice(b"WAVE");
+        header.extend_from_slice(b"fmt ");
+        header.extend_from_slice(&18u32.to_le_bytes());
+        header.extend_from_slice(&WAVE_FORMAT_IEEE_FLOAT.to_le_bytes());
+        header.extend_from_slice(&self.channels.to_le_bytes());
+        header.extend_from_slice(&sample_rate.to_le_bytes());
+        header.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+        header.extend_from_slice(&block_align.to_le_bytes());
+        header.extend_from_slice(&32u16.to_le_bytes());
+        header.extend_from_slice(&0u16.to_le_bytes());
+        // Non-PCM formats require a fact chunk with the number of frames
+        header.extend_from_slice(b"fact");
+        header.extend_from_slice(&4u32.to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(b"data");
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header
+    }
+
+    fn finalize(&mut self) {
+        let data_size = self.frames * self.channels as u32 * 4;
+        let result = (|| {
+            self.file.seek(SeekFrom::Start(4))?;
+            self.file.write_all(&(HEADER_SIZE - 8 + data_size).to_le_bytes())?;
+            self.file.seek(SeekFrom::Start(46))?;
+            self.file.write_all(&self.frames.to_le_bytes())?;
+            self.file.seek(SeekFrom::Start(54))?;
+            self.file.write_all(&data_size.to_le_bytes())?;
+            self.file.flush()
+        })();
+        if let Err(e) = result {
+            self.error.get_or_insert(e);
+        }
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        if self.error.is_some() {
+            return;
+        }
+        if let Err(e) = self.file.write_all(bytes) {
+            self.error = Some(e);
+        }
+    }
+
+    fn check(&mut self) -> Result<()> {
+        match self.error.take() {
+            Some(source) => Err(Error::Io {
+                path: self.path.clone(),
+                source,
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for WavWriter {
+    fn drop(&mut self) {
+        // Keep the file readable when the writer is dropped without calling finish
+        if !self.finished {
+            self.finalize();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::WavWriter;
+
+    #[test]
+    fn header_sizes() {
+        let path = std::env::temp_dir().join(format!("gb_wav_test_{}.wav", std::process::id()));
+        let mut writer = WavWriter::create(&path, 44100, 2).unwrap();
+        writer.write_stereo(&[0.5, -0.5, 0.25], &[1.0, 0.0, -1.0]);
+        writer.finish().unwrap();
+
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let u32_at = |i: usize| u32::from_le_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+
+        assert_eq!(data.len(), 58 + 3 * 8);
+        assert_eq!(&data[0..4], b"RIFF");
+        assert_eq!(u32_at(4) as usize, data.len() - 8);
+        assert_eq!(u32_at(46), 3);
+        assert_eq!(&data[50..54], b"data");
+        assert_eq!(u32_at(54), 24);
+        assert_eq!(&data[58..62], &0.5f32.to_le_bytes());
+        assert_eq!(&data[62..66], &1.0f32.to_le_bytes());
+    }
+}
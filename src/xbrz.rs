@@ -0,0 +1,331 @@
+use crate::image::Image;
+
+// xBRZ by Zenju (version 1.8) with its default settings. The picture is scaled in two passes:
+// the first decides for the corner between every four pixels whether it lies on an edge, the
+// second blends the corners of every pixel.
+
+const EQUAL_COLOR_TOLERANCE: f64 = 30.0;
+const DOMINANT_DIRECTION_THRESHOLD: f64 = 3.6;
+const STEEP_DIRECTION_THRESHOLD: f64 = 2.2;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Blend {
+    None,
+    Normal,
+    // The edge is clearly diagonal
+    Dominant,
+}
+
+// The corners of a pixel, clockwise from the top left
+type Corners = [Blend; 4];
+const TOP_LEFT: usize = 0;
+const TOP_RIGHT: usize = 1;
+const BOTTOM_RIGHT: usize = 2;
+const BOTTOM_LEFT: usize = 3;
+
+// A pixel and its neighbours, row by row:
+// A B C
+// D E F
+// G H I
+type Kernel = [[u8; 3]; 9];
+
+// Where the pixels of a kernel come from when it is turned by 90 degrees clockwise
+const ROTATE_90: [usize; 9] = [6, 3, 0, 7, 4, 1, 8, 5, 2];
+
+// Blending of the bottom right corner: row, column and the opacity of the new colour
+type Steps = &'static [(usize, usize, u32, u32)];
+
+struct Scaler {
+    shallow: Steps,
+    steep: Steps,
+    steep_and_shallow: Steps,
+    diagonal: Steps,
+    corner: Steps,
+}
+
+const SCALER_2X: Scaler = Scaler {
+    shallow: &[(1, 0, 1, 4), (1, 1, 3, 4)],
+    steep: &[(0, 1, 1, 4), (1, 1, 3, 4)],
+    steep_and_shallow: &[(1, 0, 1, 4), (0, 1, 1, 4), (1, 1, 5, 6)],
+    diagonal: &[(1, 1, 1, 2)],
+    corner: &[(1, 1, 21, 100)],
+};
+
+const SCALER_3X: Scaler = Scaler {
+    shallow: &[(2, 0, 1, 4), (1, 2, 1, 4), (2, 1, 3, 4), (2, 2, 1, 1)],
+    steep: &[(0, 2, 1, 4), (2, 1, 1, 4), (1, 2, 3, 4), (2, 2, 1, 1)],
+    steep_and_shallow: &[
+        (2, 0, 3, 4),
+        (0, 2, 3, 4),
+        (2, 1, 1, 4),
+        (1, 2, 1, 4),
+        (2, 2, 1, 1),
+    ],
+    diagonal: &[(1, 2, 1, 8), (2, 1, 1, 8), (2, 2, 7, 8)],
+    corner: &[(2, 2, 45, 100)],
+};
+
+const SCALER_4X: Scaler = Scaler {
+    shallow: &[
+        (3, 0, 1, 4),
+        (2, 2, 1, 4),
+        (3, 1, 3, 4),
+        (2, 3, 3, 4),
+        (3, 2, 1, 1),
+        (3, 3, 1, 1),
+    ],
+    steep: &[
+        (0, 3, 1, 4),
+        (2, 2, 1, 4),
+        (1, 3, 3, 4),
+        (3, 2, 3, 4),
+        (2, 3, 1, 1),
+        (3, 3, 1, 1),
+    ],
+    steep_and_shallow: &[
+        (3, 1, 3, 4),
+        (1, 3, 3, 4),
+        (3, 0, 1, 4),
+        (0, 3, 1, 4),
+        (2, 2, 1, 3),
+        (3, 3, 1, 1),
+        (3, 2, 1, 1),
+        (2, 3, 1, 1),
+    ],
+    diagonal: &[(3, 2, 1, 2), (2, 3, 1, 2), (3, 3, 1, 1)],
+    corner: &[(3, 3, 68, 100), (3, 2, 9, 100), (2, 3, 9, 100)],
+};
+
+/// Enlarges the picture by 2, 3 or 4
+pub(crate) fn scale(image: &Image, n: usize) -> Image {
+    let scaler = match n {
+        2 => &SCALER_2X,
+        3 => &SCALER_3X,
+        _ => &SCALER_4X,
+    };
+    let (w, h) = (image.width, image.height);
+    // Pixels outside the picture repeat the border
+    let at = |x: usize, y: usize, dx: isize, dy: isize| {
+        let x = (x as isize + dx).clamp(0, w as isize - 1);
+        let y = (y as isize + dy).clamp(0, h as isize - 1);
+        image.pixel(x as usize, y as usize)
+    };
+
+    // The corner at the bottom right of every pixel belongs to four pixels. Like in xBRZ, the
+    // corners above the first row and left of the first column are not blended.
+    let mut corners = vec![[Blend::None; 4]; w * h];
+    for y in 0..h {
+        for x in 0..w {
+            let [f, g, j, k] = corner_blend(|dx, dy| at(x, y, dx, dy));
+            corners[y * w + x][BOTTOM_RIGHT] = f;
+            if x + 1 < w {
+                corners[y * w + x + 1][BOTTOM_LEFT] = g;
+            }
+            if y + 1 < h {
+                corners[(y + 1) * w + x][TOP_RIGHT] = j;
+                if x + 1 < w {
+                    corners[(y + 1) * w + x + 1][TOP_LEFT] = k;
+                }
+            }
+        }
+    }
+
+    let mut res = Image::new(w * n, h * n);
+    for y in 0..h {
+        for x in 0..w {
+            let mut out = [image.pixel(x, y); 16];
+            let blend = corners[y * w + x];
+            if blend != [Blend::None; 4] {
+                let mut kernel: Kernel =
+                    std::array::from_fn(|i| at(x, y, i as isize % 3 - 1, i as isize / 3 - 1));
+                // Every corner is blended like the bottom right one, in a turned kernel
+                for rotation in 0..4 {
+                    let blend = std::array::from_fn(|i| blend[(i + 4 - rotation) % 4]);
+                    blend_corner(&kernel, blend, scaler, rotation, n, &mut out);
+                    kernel = std::array::from_fn(|i| kernel[ROTATE_90[i]]);
+                }
+            }
+            for (i, &color) in out[..n * n].iter().enumerate() {
+                res.set_pixel(x * n + i % n, y * n + i / n, color);
+            }
+        }
+    }
+    res
+}
+
+// Decides about the corner between F, G, J and K, for each of these four pixels:
+// A B C D
+// E F G H
+// I J K L
+// M N O P
+fn corner_blend(at: impl Fn(isize, isize) -> [u8; 3]) -> [Blend; 4] {
+    let mut res = [Blend::None; 4];
+    let (b, c) = (at(0, -1), at(1, -1));
+    let (e, f, g, h) = (at(-1, 0), at(0, 0), at(1, 0), at(2, 0));
+    let (i, j, k, l) = (at(-1, 1), at(0, 1), at(1, 1), at(2, 1));
+    let (n, o) = (at(0, 2), at(1, 2));
+    if (f == g && j == k) || (f == j && g == k) {
+        return res;
+    }
+    // The differences across an edge from J to G, and across one from F to K
+    let jg = dist(i, f) + dist(f, c) + dist(n, k) + dist(k, h) + 4.0 * dist(j, g);
+    let fk = dist(e, j) + dist(j, o) + dist(b, g) + dist(g, l) + 4.0 * dist(f, k);
+    if jg < fk {
+        let blend = if DOMINANT_DIRECTION_THRESHOLD * jg < fk {
+            Blend::Dominant
+        } else {
+            Blend::Normal
+        };
+        if f != g && f != j {
+            res[0] = blend;
+        }
+        if k != j && k != g {
+            res[3] = blend;
+        }
+    } else if fk < jg {
+        let blend = if DOMINANT_DIRECTION_THRESHOLD * fk < jg {
+            Blend::Dominant
+        } else {
+            Blend::Normal
+        };
+        if j != f && j != k {
+            res[2] = blend;
+        }
+        if g != f && g != k {
+            res[1] = blend;
+        }
+    }
+    res
+}
+
+// Blends the bottom right corner of E into the colour of its neighbours
+fn blend_corner(
+    kernel: &Kernel,
+    blend: Corners,
+    scaler: &Scaler,
+    rotation: usize,
+    n: usize,
+    out: &mut [[u8; 3]; 16],
+) {
+    if blend[BOTTOM_RIGHT] == Blend::None {
+        return;
+    }
+    let [_, b, c, d, e, f, g, h, i] = *kernel;
+    let eq = |p, q| dist(p, q) < EQUAL_COLOR_TOLERANCE;
+    let line = blend[BOTTOM_RIGHT] == Blend::Dominant
+        || !(
+            // Another corner next to this one is blended, except in a 90 degree corner
+            (blend[TOP_RIGHT] != Blend::None && !eq(e, g))
+                || (blend[BOTTOM_LEFT] != Blend::None && !eq(e, c))
+                // An L shape, only the corner is rounded
+                || (!eq(e, i) && eq(g, h) && eq(h, i) && eq(i, f) && eq(f, c))
+        );
+    let color = if dist(e, f) <= dist(e, h) { f } else { h };
+    let steps = if line {
+        let (fg, hc) = (dist(f, g), dist(h, c));
+        let shallow = STEEP_DIRECTION_THRESHOLD * fg <= hc && e != g && d != g;
+        let steep = STEEP_DIRECTION_THRESHOLD * hc <= fg && e != c && b != c;
+        match (shallow, steep) {
+            (true, true) => scaler.steep_and_shallow,
+            (true, false) => scaler.shallow,
+            (false, true) => scaler.steep,
+            (false, false) => scaler.diagonal,
+        }
+    } else {
+        scaler.corner
+    };
+    for &(row, col, m, total) in steps {
+        // Turn the position back
+        let (mut row, mut col) = (row, col);
+        for _ in 0..rotation {
+            (row, col) = (n - 1 - col, row);
+        }
+        let pixel = &mut out[row * n + col];
+        *pixel = std::array::from_fn(|i| {
+            ((color[i] as u32 * m + pixel[i] as u32 * (total - m)) / total) as u8
+        });
+    }
+}
+
+// The distance of two colours in YCbCr (ITU-R BT.2020). xBRZ looks it up in a table with the
+// differences rounded to odd numbers, stored as f32, which is done here as well.
+fn dist(a: [u8; 3], b: [u8; 3]) -> f64 {
+    const K_B: f64 = 0.0593;
+    const K_R: f64 = 0.2627;
+    const K_G: f64 = 1.0 - K_B - K_R;
+    let diff = |i: usize| ((a[i] as i32 - b[i] as i32 + 0xFF) / 2 * 2 - 0xFF) as f64;
+    let (r, g, b) = (diff(0), diff(1), diff(2));
+    let y = K_R * r + K_G * g + K_B * b;
+    let c_b = 0.5 / (1.0 - K_B) * (b - y);
+    let c_r = 0.5 / (1.0 - K_R) * (r - y);
+    (y * y + c_b * c_b + c_r * c_r).sqrt() as f32 as f64
+}
+
+#[cfg(test)]
+mod test {
+    use super::scale;
+    use crate::image::Image;
+
+    const W: [u8; 3] = [0xFF; 3];
+    const K: [u8; 3] = [0x00; 3];
+
+    // The red channel, row by row
+    fn rows(image: &Image) -> Vec<Vec<u8>> {
+        (0..image.height)
+            .map(|y| (0..image.width).map(|x| image.pixel(x, y)[0]).collect())
+            .collect()
+    }
+
+    #[test]
+    fn isolated_pixel() {
+        let mut image = Image::new(3, 3);
+        image.fill_rect(0, 0, 3, 3, W);
+        image.set_pixel(1, 1, K);
+        // All four corners are rounded: 21%, 45% and 68% (9% next to it) of white
+        let blocks: [&[&[u8]]; 3] = [
+            &[&[53, 53], &[53, 53]],
+            &[&[114, 0, 114], &[0, 0, 0], &[114, 0, 114]],
+            &[
+                &[173, 22, 22, 173],
+                &[22, 0, 0, 22],
+                &[22, 0, 0, 22],
+                &[173, 22, 22, 173],
+            ],
+        ];
+        for (n, block) in (2..=4).zip(blocks) {
+            let res = rows(&scale(&image, n));
+            for (y, row) in res.iter().enumerate() {
+                for (x, &c) in row.iter().enumerate() {
+                    let inside = (n..2 * n).contains(&x) && (n..2 * n).contains(&y);
+                    let expected = if inside { block[y - n][x - n] } else { 0xFF };
+                    assert_eq!(c, expected, "{}x at {}, {}", n, x, y);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn diagonal_edge() {
+        let mut image = Image::new(6, 6);
+        for y in 0..6 {
+            for x in 0..6 {
+                image.set_pixel(x, y, if x + y >= 6 { K } else { W });
+            }
+        }
+        let res = rows(&scale(&image, 2));
+        // A dominant diagonal: the corners on the edge get half of the other colour
+        for (y, row) in res.iter().enumerate().take(10).skip(3) {
+            for (x, &c) in row.iter().enumerate() {
+                let expected = match x + y {
+                    0..=11 => 0xFF,
+                    12 => 0x7F,
+                    _ => 0x00,
+                };
+                assert_eq!(c, expected, "at {}, {}", x, y);
+            }
+        }
+        // Where the edge meets the border, the line is shallow
+        assert_eq!(res[2][9..], [0xFF, 0xBF, 0x3F]);
+        assert_eq!(res[11][1..4], [0xFF, 0x3F, 0x00]);
+    }
+}